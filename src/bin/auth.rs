@@ -0,0 +1,64 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    process::ExitCode,
+};
+
+use rust_fps_net::game::{
+    config::args::Args,
+    net::auth::{generate_private_key, load_private_key, private_key_to_hex, AuthService, DEFAULT_AUTH_PORT},
+};
+
+// Hands out connect tokens for servers that share its private key.
+// Usage: auth [--port 4998] [--private-key-file server.key] [--accounts accounts.txt]
+//        auth --generate-key > server.key
+fn main() -> ExitCode {
+    let args = Args::from_env();
+    if args.flag("--generate-key") {
+        println!("{}", private_key_to_hex(&generate_private_key()));
+        return ExitCode::SUCCESS;
+    }
+
+    let key_file = args.value("--private-key-file").unwrap_or("server.key");
+    let key = match load_private_key(Path::new(key_file)) {
+        Ok(key) => key,
+        Err(err) => {
+            eprintln!("{}: {} (create one with --generate-key)", key_file, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let accounts = args.value("--accounts").unwrap_or("accounts.txt");
+    let mut service = match AuthService::with_accounts_file(key, Path::new(accounts)) {
+        Ok(service) => service,
+        Err(err) => {
+            eprintln!("{}: {}", accounts, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], args.parse_or("--port", DEFAULT_AUTH_PORT)));
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("{}: {}", addr, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("Auth service listening on {}", addr);
+
+    // one request at a time, each is a single small frame with a short timeout
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("accept: {}", err);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().ok();
+        if let Err(err) = service.serve(&mut stream) {
+            eprintln!("{:?}: {}", peer, err);
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bevy::{app::PluginsState, prelude::*};
+use bevy_renet::renet::RenetClient;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rust_fps_net::game::{
+    config::args::Args,
+    net::{
+        client::{open_connection, ClientAuth, ConnectSettings},
+        conditioner::ClientConditioner,
+        protocol::{ClientMessage, InputCommand, ServerChannel, ServerMessage, PROTOCOL_VERSION},
+        rates::{clamp_tickrate, ClientRates, NetRates, DEFAULT_TICKRATE},
+        server::{headless_server_app, BandwidthStats, ServerSettings, TickTimes},
+        snapshot::{decode_snapshot, SnapshotHistory},
+        transport::ClientTransport,
+    },
+    player::{input::PlayerInput, player::WeaponType},
+    weapons::{
+        definition::{Recoil, WeaponDefinition},
+        recoil::Spray,
+    },
+};
+
+// Load test: many bots in one process playing against a server over loopback.
+// Usage: loadtest [--clients 32] [--duration 60] [--inputs random|circle] [--seed 1]
+//                 [--cmdrate 64] [--updaterate 64] [--connect 127.0.0.1:5000]
+// Without --connect the server runs in this process with the usual server options (--port,
+// --tickrate, ...) and every frame it runs is timed. An external server only reports a
+// mean frame time per second; pass its --tickrate so the bots send at the same rate.
+// The bots connect unsecured, a server with a private key turns them away.
+
+// how many input packets every command is repeated in, as in prediction.rs
+const INPUT_REDUNDANCY: usize = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() -> ExitCode {
+    let args = Args::from_env();
+    let clients: usize = args.parse_or("--clients", 32);
+    let duration = Duration::from_secs_f64(args.parse_or("--duration", 60.0));
+    let script = match args.value("--inputs").unwrap_or("random") {
+        "random" => Script::Random,
+        "circle" => Script::Circle,
+        other => {
+            eprintln!("unknown --inputs '{}', expected random or circle", other);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut rng = StdRng::seed_from_u64(args.parse_or("--seed", 1));
+    let rates = ClientRates::from_args(&args);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (server_addr, tickrate, server) = match args.value("--connect") {
+        Some(addr) => {
+            let Ok(addr) = addr.parse::<SocketAddr>() else {
+                eprintln!("--connect: '{}' is not an address", addr);
+                return ExitCode::FAILURE;
+            };
+            (addr, clamp_tickrate(args.parse_or("--tickrate", DEFAULT_TICKRATE)), None)
+        }
+        None => {
+            let mut settings = ServerSettings::from_args(&args);
+            settings.max_clients = settings.max_clients.max(clients);
+            let (addr, tickrate) = (settings.public_addr, settings.tickrate);
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let server = spawn_server(settings, stop.clone(), ready_tx);
+            if ready_rx.recv().is_err() {
+                eprintln!("the server failed to start");
+                return ExitCode::FAILURE;
+            }
+            (addr, tickrate, Some(server))
+        }
+    };
+
+    let mut bots = Vec::with_capacity(clients);
+    for index in 0..clients {
+        match Bot::connect(server_addr, index, script, rates, StdRng::seed_from_u64(rng.random())) {
+            Ok(bot) => bots.push(bot),
+            Err(err) => {
+                eprintln!("bot {}: {}", index, err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    println!(
+        "{} bots against {} at {} tick for {:.0} s",
+        clients,
+        server_addr,
+        tickrate,
+        duration.as_secs_f64()
+    );
+
+    let tick = Duration::from_secs_f64(1.0 / tickrate);
+    let started = Instant::now();
+    let mut last = started;
+    while started.elapsed() < duration {
+        let now = Instant::now();
+        let delta = now - last;
+        last = now;
+        for bot in bots.iter_mut() {
+            bot.update(delta);
+        }
+        if bots.iter().all(|bot| !bot.joined()) && started.elapsed() > CONNECT_TIMEOUT {
+            eprintln!("no bot got in within {} s", CONNECT_TIMEOUT.as_secs());
+            break;
+        }
+        if let Some(rest) = tick.checked_sub(now.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    // the server's numbers are read before the bots leave and their stats are dropped
+    stop.store(true, Ordering::Relaxed);
+    let report = server.map(|server| server.join().expect("server thread panicked"));
+    for bot in bots.iter_mut() {
+        bot.disconnect();
+    }
+
+    let joined = bots.iter().filter(|bot| bot.joined()).count();
+    println!("{}/{} bots joined", joined, clients);
+    match &report {
+        Some(report) => print_percentiles("server frame time", &report.frame_times),
+        None => {
+            // every bot hears the same per-second means, one of them is enough
+            let means = bots
+                .iter()
+                .map(|bot| &bot.reported_frame_times)
+                .max_by_key(|means| means.len())
+                .cloned()
+                .unwrap_or_default();
+            print_percentiles("server frame time (1 s means)", &means);
+        }
+    }
+
+    let per_client = |bytes: Vec<u64>| -> Vec<f64> { bytes.into_iter().map(|bytes| bytes as f64 / elapsed / 1024.).collect() };
+    let sent_to_clients = match report {
+        Some(report) => per_client(report.bytes_sent),
+        None => per_client(bots.iter().map(|bot| bot.bytes_received).collect()),
+    };
+    print_bandwidth("server -> client", &sent_to_clients);
+    print_bandwidth("client -> server", &per_client(bots.iter().map(|bot| bot.bytes_sent).collect()));
+    let rejected: usize = bots.iter().filter(|bot| bot.rejected.is_some()).count();
+    if rejected > 0 {
+        println!("{} bots were rejected: {:?}", rejected, bots.iter().find_map(|bot| bot.rejected.as_ref()));
+    }
+    ExitCode::SUCCESS
+}
+
+/* ---------------- Servern ---------------- */
+
+struct ServerReport {
+    frame_times: Vec<Duration>,
+    // per client, everything the server handed to renet
+    bytes_sent: Vec<u64>,
+}
+
+// Steps the server app by hand at its tickrate until `stop` is set.
+fn spawn_server(settings: ServerSettings, stop: Arc<AtomicBool>, ready: mpsc::Sender<()>) -> JoinHandle<ServerReport> {
+    thread::spawn(move || {
+        let frame = Duration::from_secs_f64(1.0 / settings.tickrate);
+        let mut app = headless_server_app(settings);
+        while app.plugins_state() == PluginsState::Adding {
+            thread::yield_now();
+        }
+        app.finish();
+        app.cleanup();
+        let _ = ready.send(());
+
+        let mut frame_times = Vec::new();
+        while !stop.load(Ordering::Relaxed) {
+            let started = Instant::now();
+            app.update();
+            frame_times.push(app.world().resource::<TickTimes>().last);
+            if let Some(rest) = frame.checked_sub(started.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+        let bandwidth = app.world().resource::<BandwidthStats>();
+        ServerReport {
+            frame_times,
+            bytes_sent: bandwidth.clients.values().map(|client| client.total_bytes).collect(),
+        }
+    })
+}
+
+/* ---------------- Bottar ---------------- */
+
+#[derive(Clone, Copy)]
+enum Script {
+    // new direction, turn rate and stance every second or two, fires at random
+    Random,
+    // runs in circles and fires twice a second
+    Circle,
+}
+
+struct Bot {
+    name: String,
+    client: RenetClient,
+    transport: ClientTransport,
+    // bots connect over a clean network, it only passes packets through
+    conditioner: ClientConditioner,
+    // time since connecting, the conditioner's clock
+    elapsed: Duration,
+    script: Script,
+    rng: StdRng,
+    requested_rates: ClientRates,
+    hello_sent: bool,
+    client_id: Option<u64>,
+    rates: Option<NetRates>,
+    rejected: Option<String>,
+
+    input: PlayerInput,
+    // degrees per second
+    turn_rate: f32,
+    next_change: Duration,
+    sequence: u32,
+    recent: VecDeque<InputCommand>,
+    since_fire: Duration,
+    // the builtin rifle, a server with edited weapon files may reject some shots
+    cycle_time: Duration,
+    rounds: u32,
+    recoil: Recoil,
+    spray: Spray,
+
+    snapshots: SnapshotHistory,
+    // eye position in the newest snapshot
+    eye: Option<Vec3>,
+
+    bytes_sent: u64,
+    bytes_received: u64,
+    reported_frame_times: Vec<Duration>,
+}
+
+impl Bot {
+    fn connect(
+        server_addr: SocketAddr,
+        index: usize,
+        script: Script,
+        requested_rates: ClientRates,
+        rng: StdRng,
+    ) -> Result<Bot, Box<dyn std::error::Error>> {
+        let settings = ConnectSettings {
+            server_addr,
+            name: format!("bot{:03}", index),
+            auth: ClientAuth::default(),
+        };
+        let (client, transport) = open_connection(&settings)?;
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        Ok(Bot {
+            name: settings.name,
+            client,
+            transport,
+            conditioner: ClientConditioner::default(),
+            elapsed: Duration::ZERO,
+            script,
+            rng,
+            requested_rates,
+            hello_sent: false,
+            client_id: None,
+            rates: None,
+            rejected: None,
+            input: PlayerInput::default(),
+            turn_rate: 0.,
+            next_change: Duration::ZERO,
+            sequence: 0,
+            recent: VecDeque::new(),
+            since_fire: Duration::ZERO,
+            cycle_time: rifle.cycle_time(),
+            rounds: rifle.magazine_size.unwrap_or(0),
+            recoil: rifle.recoil,
+            spray: Spray::default(),
+            snapshots: SnapshotHistory::default(),
+            eye: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            reported_frame_times: Vec::new(),
+        })
+    }
+
+    fn joined(&self) -> bool {
+        self.client_id.is_some()
+    }
+
+    fn send(&mut self, message: ClientMessage) {
+        let bytes = message.encode();
+        self.bytes_sent += bytes.len() as u64;
+        self.client.send_message(message.channel(), bytes);
+    }
+
+    fn update(&mut self, delta: Duration) {
+        self.elapsed += delta;
+        self.client.update(delta);
+        let transport = self.transport.update(self.elapsed, delta, &mut self.client, &mut self.conditioner);
+        if transport.is_err() || self.client.is_disconnected() {
+            return;
+        }
+        if self.client.is_connected() {
+            if !self.hello_sent {
+                self.hello_sent = true;
+                self.send(ClientMessage::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    name: self.name.clone(),
+                });
+            }
+            self.receive();
+            if self.joined() {
+                self.play(delta);
+            }
+        }
+        let _ = self.transport.send_packets(self.elapsed, &mut self.client, &mut self.conditioner);
+    }
+
+    fn receive(&mut self) {
+        for channel in [ServerChannel::Snapshot, ServerChannel::Reliable] {
+            let channel: u8 = channel.into();
+            while let Some(bytes) = self.client.receive_message(channel) {
+                self.bytes_received += bytes.len() as u64;
+                let Ok(message) = ServerMessage::decode(&bytes) else {
+                    continue;
+                };
+                match message {
+                    ServerMessage::Welcome { client_id, .. } => {
+                        self.client_id = Some(client_id);
+                        self.send(ClientMessage::Rates(self.requested_rates));
+                    }
+                    ServerMessage::Rejected(reason) => self.rejected = Some(reason.to_string()),
+                    ServerMessage::Rates(rates) => self.rates = Some(rates),
+                    ServerMessage::Snapshot(bytes) => {
+                        let Ok(snapshot) = decode_snapshot(&bytes, |tick| self.snapshots.get(tick)) else {
+                            continue;
+                        };
+                        let newest = self.snapshots.latest_tick().is_none_or(|latest| snapshot.tick > latest);
+                        let world = snapshot.to_world();
+                        if let Some(own) = world.players.iter().find(|state| Some(state.id) == self.client_id) {
+                            if newest {
+                                self.eye = Some(own.position + Vec3::Y * own.eye_height);
+                            }
+                        }
+                        self.snapshots.insert(snapshot);
+                    }
+                    ServerMessage::ServerStats { tick_time, .. } => {
+                        self.reported_frame_times.push(Duration::from_secs_f32(tick_time / 1000.));
+                    }
+                    // after spawning and after every reload
+                    ServerMessage::Ammo(ammo) => {
+                        if let Some((_, rifle)) = ammo.iter().find(|(weapon, _)| *weapon == WeaponType::Rifle) {
+                            self.rounds = rifle.magazine;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // one tick of input, batched like a real client at its cmdrate
+    fn play(&mut self, delta: Duration) {
+        self.steer(delta);
+        self.sequence += 1;
+        let command = InputCommand::new(self.sequence, &self.input);
+        let interval = self.rates.map_or(1, |rates| rates.command_interval());
+        self.recent.push_back(command);
+        while self.recent.len() > INPUT_REDUNDANCY * interval as usize {
+            self.recent.pop_front();
+        }
+        if self.sequence.is_multiple_of(interval) {
+            self.send(ClientMessage::Input {
+                commands: self.recent.iter().copied().collect(),
+                last_snapshot: self.snapshots.latest_tick(),
+            });
+        }
+
+        self.since_fire += delta;
+        let wants_to_fire = match self.script {
+            Script::Random => self.rng.random_bool(0.05),
+            Script::Circle => self.since_fire >= Duration::from_millis(500),
+        };
+        let (Some(tick), Some(origin), Some(rates)) = (self.snapshots.latest_tick(), self.eye, self.rates)
+        else {
+            return;
+        };
+        // stays under the server's fire rate and magazine checks
+        if wants_to_fire && self.rounds > 0 && self.since_fire >= self.cycle_time {
+            self.since_fire = Duration::ZERO;
+            self.rounds -= 1;
+            let spray = self.spray.next_at_tick(tick, rates.tickrate, &self.recoil) as u32;
+            self.send(ClientMessage::Fire {
+                tick,
+                origin,
+                rotation: self.input.rotation,
+                weapon: WeaponType::Rifle,
+                spray,
+            });
+            if self.rounds == 0 {
+                self.send(ClientMessage::Reload);
+            }
+        }
+    }
+
+    fn steer(&mut self, delta: Duration) {
+        match self.script {
+            Script::Random => {
+                self.next_change = self.next_change.saturating_sub(delta);
+                if self.next_change.is_zero() {
+                    self.next_change = Duration::from_secs_f32(self.rng.random_range(0.5..2.0));
+                    self.input.movement = Vec2::new(
+                        self.rng.random_range(-1..=1) as f32,
+                        self.rng.random_range(-1..=1) as f32,
+                    );
+                    self.input.crouch = self.rng.random_bool(0.1);
+                    self.input.rotation.x = self.rng.random_range(-10.0..10.0);
+                    self.turn_rate = self.rng.random_range(-120.0..120.0);
+                }
+                self.input.jump = self.rng.random_bool(0.02);
+            }
+            Script::Circle => {
+                self.input.movement = Vec2::X;
+                self.turn_rate = 45.;
+            }
+        }
+        self.input.rotation.y = (self.input.rotation.y + self.turn_rate * delta.as_secs_f32()).rem_euclid(360.);
+    }
+
+    fn disconnect(&mut self) {
+        self.transport.leave(&mut self.client);
+    }
+}
+
+/* ---------------- Rapport ---------------- */
+
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[((sorted.len() - 1) as f64 * percent / 100.).round() as usize]
+}
+
+fn print_percentiles(label: &str, samples: &[Duration]) {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.;
+    println!(
+        "{}: p50 {:.2} ms  p90 {:.2} ms  p99 {:.2} ms  max {:.2} ms  ({} samples)",
+        label,
+        ms(percentile(&sorted, 50.)),
+        ms(percentile(&sorted, 90.)),
+        ms(percentile(&sorted, 99.)),
+        ms(sorted.last().copied().unwrap_or_default()),
+        sorted.len()
+    );
+}
+
+// kB/s per client
+fn print_bandwidth(label: &str, rates: &[f64]) {
+    if rates.is_empty() {
+        println!("{}: no clients", label);
+        return;
+    }
+    let min = rates.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = rates.iter().copied().fold(0., f64::max);
+    println!(
+        "{}: {:.2} kB/s per client on average, {:.2} to {:.2}",
+        label,
+        rates.iter().sum::<f64>() / rates.len() as f64,
+        min,
+        max
+    );
+}
@@ -0,0 +1,103 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::{SocketAddr, TcpStream},
+    process::ExitCode,
+    time::Duration,
+};
+
+use rust_fps_net::game::{
+    config::args::Args,
+    net::{
+        protocol::{read_frame, write_frame},
+        rcon::{RconRequest, RconResponse},
+    },
+};
+
+// Remote console for a running dedicated server.
+// Usage: rcon [--address 127.0.0.1:5000] [--password secret] [--command "status"]
+// Without --password the RCON_PASSWORD environment variable is used, without --command
+// commands are read from stdin until EOF or "quit".
+fn main() -> ExitCode {
+    let args = Args::from_env();
+    let address = args.parse_or("--address", SocketAddr::from(([127, 0, 0, 1], 5000)));
+    let Some(password) = args
+        .value("--password")
+        .map(str::to_string)
+        .or_else(|| std::env::var("RCON_PASSWORD").ok())
+    else {
+        eprintln!("no password, pass --password or set RCON_PASSWORD");
+        return ExitCode::FAILURE;
+    };
+
+    let mut stream = match connect(address, password) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("{}: {}", address, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(command) = args.value("--command") {
+        return match run(&mut stream, command) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(err) => {
+                eprintln!("{}", err);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let stdin = io::stdin();
+    loop {
+        print!("rcon> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return ExitCode::SUCCESS;
+        }
+        match line.trim() {
+            "" => continue,
+            "quit" | "exit" => return ExitCode::SUCCESS,
+            command => {
+                if let Err(err) = run(&mut stream, command) {
+                    eprintln!("{}", err);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+}
+
+fn connect(address: SocketAddr, password: String) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write_frame(&mut stream, &RconRequest::Auth { password })?;
+    match read_frame(&mut stream)? {
+        RconResponse::Authenticated => Ok(stream),
+        RconResponse::Denied(reason) => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected reply {:?}", other),
+        )),
+    }
+}
+
+// false when the server reported an error
+fn run(stream: &mut TcpStream, command: &str) -> io::Result<bool> {
+    write_frame(stream, &RconRequest::Command(command.to_string()))?;
+    match read_frame(stream)? {
+        RconResponse::Output(output) => {
+            if !output.is_empty() {
+                println!("{}", output.trim_end());
+            }
+            Ok(true)
+        }
+        RconResponse::Error(err) => {
+            eprintln!("error: {}", err);
+            Ok(false)
+        }
+        RconResponse::Denied(reason) => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+        RconResponse::Authenticated => Ok(true),
+    }
+}
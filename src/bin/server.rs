@@ -0,0 +1,17 @@
+use rust_fps_net::game::{
+    config::args::Args,
+    net::server::{headless_server_app, ServerSettings},
+};
+
+// Headless dedicated server: no window, renderer or egui.
+// Usage: server [--port 5000] [--public-addr 127.0.0.1:5000] [--max-clients 10] [--tickrate 64]
+//               [--min-cmdrate 20] [--max-cmdrate 256] [--min-updaterate 20] [--max-updaterate 256]
+//               [--max-rewind 200] [--reconnect-grace 60]
+//               [--server-name name] [--map gridshot] [--mode Competitive]
+//               [--query-port 15000] [--beacon] [--rcon-password secret] [--rcon-port 5000]
+//               [--private-key hex | --private-key-file server.key] [--ban-file bans.txt]
+//               [--net-lag ms] [--net-jitter ms] [--net-loss %] [--net-dup %] [--net-reorder %] [--net-seed n]
+fn main() {
+    let args = Args::from_env();
+    headless_server_app(ServerSettings::from_args(&args)).run();
+}
@@ -4,6 +4,11 @@ use bevy::prelude::*;
 pub enum AppState {
     #[default]
     MainMenu,
+    // waiting for the server's Welcome
+    Connecting,
+    // welcomed, waiting for our player and the first snapshot
+    Loading,
     InGame,
     Paused,
-}
\ No newline at end of file
+    Disconnected,
+}
@@ -0,0 +1,46 @@
+use std::str::FromStr;
+
+// Minimal command line parsing shared by the binaries.
+// Accepts both "--port 5000" and "--port=5000".
+pub struct Args {
+    values: Vec<String>,
+}
+
+impl Args {
+    pub fn from_env() -> Args {
+        Args {
+            values: std::env::args().skip(1).collect(),
+        }
+    }
+
+    pub fn new(values: Vec<String>) -> Args {
+        Args { values }
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        let prefix = format!("{}=", name);
+        for (i, arg) in self.values.iter().enumerate() {
+            if arg == name {
+                return self.values.get(i + 1).map(|v| v.as_str());
+            }
+            if let Some(value) = arg.strip_prefix(&prefix) {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.iter().any(|arg| arg == name)
+    }
+
+    pub fn parse_or<T: FromStr>(&self, name: &str, default: T) -> T {
+        match self.value(name) {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                eprintln!("invalid value '{}' for {}, using default", value, name);
+                default
+            }),
+            None => default,
+        }
+    }
+}
@@ -10,6 +10,8 @@ impl Plugin for CursorPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(OnEnter(AppState::MainMenu), unlock_cursor)
            .add_systems(OnEnter(AppState::Paused), unlock_cursor)
+           .add_systems(OnEnter(AppState::Connecting), unlock_cursor)
+           .add_systems(OnEnter(AppState::Disconnected), unlock_cursor)
            .add_systems(OnEnter(AppState::InGame), lock_cursor);
     }
 }
@@ -22,7 +24,7 @@ fn lock_cursor(mut q: Query<&mut Window, With<PrimaryWindow>>) {
 }
 
 fn unlock_cursor(mut q: Query<&mut Window, With<PrimaryWindow>>) {
-    info!("Unlocking cursor (Menu/Pause)");
+    info!("Unlocking cursor (Menu/Pause/Connection screens)");
     let mut window = q.single_mut();
     window.cursor.visible = true;
     window.cursor.grab_mode = CursorGrabMode::None;
@@ -1,18 +1,21 @@
 use bevy::prelude::*;
 use bevy_rapier3d::plugin::{NoUserData, RapierPhysicsPlugin};
 
-use super::{level::level, player::player, ui::ui, window::window, cursor::cursor};
+use super::{level::level, net::client, player::player, ui::ui, window::window, cursor::cursor, weapons::weapons};
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
-            RapierPhysicsPlugin::<NoUserData>::default(),
+            // physics steps with the movement tick so client and server simulate the same way
+            RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule(),
             level::LevelPlugin,
             player::PlayerPlugin,
             window::WindowSettingsPlugin,
             ui::UiPlugin,
             cursor::CursorPlugin,
+            client::ClientPlugin,
+            weapons::WeaponsPlugin,
         ));
     }
 }
\ No newline at end of file
@@ -12,33 +12,35 @@ impl Plugin for LevelPlugin {
 }
 fn init_level(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
 ) {
-    let level_material = materials.add(StandardMaterial {
-        base_color: Color::WHITE,
-        ..default()
-    });
-    commands.spawn((
+    // colliders always exist, meshes are only attached when there is a renderer (not on the dedicated server)
+    let floor = commands.spawn((
         Collider::cuboid(1000., 0., 1000.),
-        PbrBundle {
-            material: level_material.clone(),
-            transform: Transform::IDENTITY,
-            mesh: meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(1000.))),
-            ..default()
-        },
+        SpatialBundle::from_transform(Transform::IDENTITY),
         Shootable,
-    ));
-    commands.spawn((
+    )).id();
+    let block = commands.spawn((
         Collider::cuboid(30., 30., 30.),
-        PbrBundle {
-            material: level_material.clone(),
-            transform: Transform::from_xyz(0., 0., -100.),
-            mesh: meshes.add(Cuboid::from_length(60.)),
-            ..default()
-        },
+        SpatialBundle::from_transform(Transform::from_xyz(0., 0., -100.)),
         Shootable,
-    ));
+    )).id();
+
+    if let (Some(mut meshes), Some(mut materials)) = (meshes, materials) {
+        let level_material = materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            ..default()
+        });
+        commands.entity(floor).insert((
+            meshes.add(Plane3d::new(Vec3::Y, Vec2::splat(1000.))),
+            level_material.clone(),
+        ));
+        commands.entity(block).insert((
+            meshes.add(Cuboid::from_length(60.)),
+            level_material.clone(),
+        ));
+    }
 
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
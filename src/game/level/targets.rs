@@ -29,19 +29,19 @@ pub struct GridShot {
 
 impl GridShot {
     pub fn generate_new_position(&self, rand: &mut ThreadRng) -> Vec2 {
-        return (Vec2::new(
+        (Vec2::new(
             rand.random_range(0..self.grid_size) as f32,
             rand.random_range(0..self.grid_size) as f32,
         ) - Vec2::new(self.grid_size as f32 / 2., 0.)
             + (Vec2::Y * 0.5))
-            * self.cell_size;
+            * self.cell_size
     }
 }
 
 fn init_grid_shot(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
 ) {
     let grid_shot = GridShot {
         grid_size: 5,
@@ -49,30 +49,34 @@ fn init_grid_shot(
         max_targets: 5,
     };
 
-    let target_material = materials.add(StandardMaterial {
-        base_color: Color::srgb(1., 0., 0.),
-        ..default()
-    });
-
     commands.insert_resource(grid_shot);
 
     let target_radius = grid_shot.cell_size / 8.;
 
     let collider_radius = target_radius * f32::sin(PI / 4.);
 
+    let visuals = match (meshes, materials) {
+        (Some(mut meshes), Some(mut materials)) => Some((
+            meshes.add(Sphere::new(target_radius)),
+            materials.add(StandardMaterial {
+                base_color: Color::srgb(1., 0., 0.),
+                ..default()
+            }),
+        )),
+        _ => None,
+    };
+
     for _i in 0..grid_shot.max_targets {
-        commands.spawn((
+        let target = commands.spawn((
             Collider::cuboid(collider_radius, collider_radius, collider_radius),
-            PbrBundle {
-                transform: Transform::from_xyz(0., 0., -40.),
-                mesh: meshes.add(Sphere::new(target_radius)),
-                material: target_material.clone(),
-                ..default()
-            },
+            SpatialBundle::from_transform(Transform::from_xyz(0., 0., -40.)),
             Target {},
             Shootable,
             DeadTarget,
-        ));
+        )).id();
+        if let Some((mesh, material)) = &visuals {
+            commands.entity(target).insert((mesh.clone(), material.clone()));
+        }
     }
 }
 
@@ -1,5 +1,5 @@
 use bevy::prelude::*;
 
 pub fn blender_to_world(blender : Vec3) -> Vec3{
-    return Vec3::new(blender.x,blender.z,-blender.y);
+    Vec3::new(blender.x,blender.z,-blender.y)
 }
\ No newline at end of file
@@ -6,4 +6,7 @@ pub mod shooting;
 pub mod ui;
 pub mod window;
 pub mod math;
-pub mod app_state;
\ No newline at end of file
+pub mod app_state;
+pub mod config;
+pub mod net;
+pub mod weapons;
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs, io,
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use bevy_renet::renet::transport::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+use serde::{Deserialize, Serialize};
+
+use super::protocol::{check_hello, read_frame, write_frame, PROTOCOL_ID, PROTOCOL_VERSION};
+
+// Stand-in for a real account service: a player is whoever holds the secret in their
+// identity file, the service hands out netcode connect tokens signed with the key the
+// game servers share. Runs as its own process (src/bin/auth.rs) or in-process.
+
+pub const DEFAULT_AUTH_PORT: u16 = 4998;
+// a token has to be used this soon after it was issued
+const TOKEN_EXPIRE_SECONDS: u64 = 120;
+// netcode connection timeout written into the token
+const TOKEN_TIMEOUT_SECONDS: i32 = 15;
+const AUTH_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+/* ---------------- Nycklar ---------------- */
+
+pub fn generate_private_key() -> PrivateKey {
+    rand::random()
+}
+
+pub fn private_key_to_hex(key: &PrivateKey) -> String {
+    key.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+pub fn parse_private_key(hex: &str) -> Result<PrivateKey, String> {
+    let hex = hex.trim();
+    if hex.len() != NETCODE_KEY_BYTES * 2 || !hex.is_ascii() {
+        return Err(format!("private key must be {} hex digits", NETCODE_KEY_BYTES * 2));
+    }
+    let mut key = [0; NETCODE_KEY_BYTES];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| "private key is not hex".to_string())?;
+    }
+    Ok(key)
+}
+
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    parse_private_key(&fs::read_to_string(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/* ---------------- Tokens ---------------- */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenRequest {
+    pub protocol_version: u32,
+    // from the player's identity file, proves who they are
+    pub secret: u64,
+    pub name: String,
+    pub server_addr: SocketAddr,
+}
+
+impl TokenRequest {
+    pub fn new(secret: u64, name: &str, server_addr: SocketAddr) -> TokenRequest {
+        TokenRequest {
+            protocol_version: PROTOCOL_VERSION,
+            secret,
+            name: name.to_string(),
+            server_addr,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TokenResponse {
+    // token is ConnectToken::write output
+    Token { player_id: u64, token: Vec<u8> },
+    Denied(String),
+}
+
+// The display name travels in the token's user data: a length byte, then UTF-8.
+pub fn name_to_user_data(name: &str) -> [u8; NETCODE_USER_DATA_BYTES] {
+    let mut data = [0; NETCODE_USER_DATA_BYTES];
+    let bytes = name.as_bytes();
+    let len = bytes.len().min(NETCODE_USER_DATA_BYTES - 1).min(u8::MAX as usize);
+    data[0] = len as u8;
+    data[1..=len].copy_from_slice(&bytes[..len]);
+    data
+}
+
+pub fn name_from_user_data(data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<String> {
+    let len = data[0] as usize;
+    String::from_utf8(data.get(1..=len)?.to_vec()).ok()
+}
+
+// Asks the auth service at `auth_addr` for a token. Blocks for up to a few seconds.
+pub fn request_token(auth_addr: SocketAddr, request: &TokenRequest) -> io::Result<(u64, ConnectToken)> {
+    let mut stream = TcpStream::connect_timeout(&auth_addr, AUTH_TIMEOUT)?;
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    write_frame(&mut stream, request)?;
+    match read_frame(&mut stream)? {
+        TokenResponse::Token { player_id, token } => {
+            let token = ConnectToken::read(&mut token.as_slice())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            Ok((player_id, token))
+        }
+        TokenResponse::Denied(reason) => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+    }
+}
+
+/* ---------------- Identitet ---------------- */
+
+// The secret in `path`, created on first use. Whoever has the file plays as that player.
+pub fn load_or_create_identity(path: &Path) -> io::Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(contents) => u64::from_str_radix(contents.trim(), 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an identity file", path.display()))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let secret = rand::random::<u64>();
+            fs::write(path, format!("{:016x}\n", secret))?;
+            Ok(secret)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/* ---------------- Tjänsten ---------------- */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub player_id: u64,
+    pub name: String,
+}
+
+pub struct AuthService {
+    key: PrivateKey,
+    // by identity secret
+    accounts: HashMap<u64, Account>,
+    // "<secret> <player id> <name>" per line, None keeps accounts in memory only
+    path: Option<PathBuf>,
+}
+
+impl AuthService {
+    pub fn new(key: PrivateKey) -> AuthService {
+        AuthService {
+            key,
+            accounts: HashMap::new(),
+            path: None,
+        }
+    }
+
+    pub fn with_accounts_file(key: PrivateKey, path: &Path) -> io::Result<AuthService> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut accounts = HashMap::new();
+        for line in contents.lines() {
+            let mut fields = line.trim().splitn(3, ' ');
+            let (Some(secret), Some(player_id), Some(name)) = (fields.next(), fields.next(), fields.next()) else {
+                continue;
+            };
+            if let (Ok(secret), Ok(player_id)) = (u64::from_str_radix(secret, 16), player_id.parse()) {
+                accounts.insert(
+                    secret,
+                    Account {
+                        player_id,
+                        name: name.to_string(),
+                    },
+                );
+            }
+        }
+        Ok(AuthService {
+            key,
+            accounts,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = self
+            .accounts
+            .iter()
+            .map(|(secret, account)| format!("{:016x} {} {}\n", secret, account.player_id, account.name))
+            .collect();
+        fs::write(path, contents)
+    }
+
+    // Finds or registers the account behind the secret, renames it if the name changed,
+    // and signs a token for the requested server.
+    pub fn issue_token(&mut self, request: &TokenRequest) -> Result<(Account, ConnectToken), String> {
+        check_hello(request.protocol_version, &request.name).map_err(|reason| reason.to_string())?;
+        let name = request.name.trim().to_string();
+
+        if !self.accounts.contains_key(&request.secret) {
+            // player ids double as netcode client ids, 0 is never handed out
+            let mut player_id = rand::random::<u64>();
+            while player_id == 0 || self.accounts.values().any(|account| account.player_id == player_id) {
+                player_id = rand::random();
+            }
+            self.accounts.insert(request.secret, Account { player_id, name: name.clone() });
+        }
+        let account = self.accounts.get_mut(&request.secret).unwrap();
+        account.name = name;
+        let account = account.clone();
+        if let Err(err) = self.save() {
+            return Err(format!("could not save accounts: {}", err));
+        }
+
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|err| err.to_string())?;
+        let token = ConnectToken::generate(
+            now,
+            PROTOCOL_ID,
+            TOKEN_EXPIRE_SECONDS,
+            account.player_id,
+            TOKEN_TIMEOUT_SECONDS,
+            vec![request.server_addr],
+            Some(&name_to_user_data(&account.name)),
+            &self.key,
+        )
+        .map_err(|err| err.to_string())?;
+        Ok((account, token))
+    }
+
+    // One request per connection, the reply is a TokenResponse.
+    pub fn serve(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+        let request: TokenRequest = read_frame(stream)?;
+        let response = match self.issue_token(&request) {
+            Ok((account, token)) => {
+                let mut bytes = Vec::new();
+                token.write(&mut bytes)?;
+                TokenResponse::Token {
+                    player_id: account.player_id,
+                    token: bytes,
+                }
+            }
+            Err(reason) => TokenResponse::Denied(reason),
+        };
+        write_frame(stream, &response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_keys_round_trip_through_hex() {
+        let key = generate_private_key();
+        assert_eq!(parse_private_key(&private_key_to_hex(&key)), Ok(key));
+        assert!(parse_private_key("abcd").is_err());
+        assert!(parse_private_key(&"zz".repeat(NETCODE_KEY_BYTES)).is_err());
+    }
+
+    #[test]
+    fn names_fit_in_user_data() {
+        assert_eq!(name_from_user_data(&name_to_user_data("Spelare åäö")), Some("Spelare åäö".to_string()));
+        assert_eq!(name_from_user_data(&[0; NETCODE_USER_DATA_BYTES]), Some(String::new()));
+    }
+
+    #[test]
+    fn player_id_follows_the_secret() {
+        let mut service = AuthService::new(generate_private_key());
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 5000));
+        let (first, _) = service.issue_token(&TokenRequest::new(1, "Player", server_addr)).unwrap();
+        let (renamed, _) = service.issue_token(&TokenRequest::new(1, "Renamed", server_addr)).unwrap();
+        let (other, _) = service.issue_token(&TokenRequest::new(2, "Player", server_addr)).unwrap();
+
+        assert_eq!(first.player_id, renamed.player_id);
+        assert_eq!(renamed.name, "Renamed");
+        assert_ne!(first.player_id, other.player_id);
+        assert!(service.issue_token(&TokenRequest::new(3, "  ", server_addr)).is_err());
+    }
+}
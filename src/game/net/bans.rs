@@ -0,0 +1,209 @@
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::ClientId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanTarget {
+    Player(ClientId),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Player(id) => write!(f, "id {}", id),
+            BanTarget::Ip(ip) => write!(f, "ip {}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub target: BanTarget,
+    // None for permanent bans
+    pub expires: Option<SystemTime>,
+    pub reason: String,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+
+    fn parse(line: &str) -> Result<Ban, String> {
+        let mut fields = line.splitn(4, char::is_whitespace);
+        let (Some(kind), Some(target), Some(expires)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err("expected '<id|ip> <target> <expiry> [reason]'".to_string());
+        };
+        let target = match kind {
+            "id" => BanTarget::Player(target.parse().map(ClientId::from_raw).map_err(|_| format!("bad player id '{}'", target))?),
+            "ip" => BanTarget::Ip(target.parse().map_err(|_| format!("bad address '{}'", target))?),
+            other => return Err(format!("unknown ban kind '{}'", other)),
+        };
+        let expires = match expires {
+            "never" => None,
+            seconds => Some(
+                SystemTime::UNIX_EPOCH
+                    + Duration::from_secs(seconds.parse().map_err(|_| format!("bad expiry '{}'", seconds))?),
+            ),
+        };
+        Ok(Ban {
+            target,
+            expires,
+            reason: fields.next().unwrap_or("").trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.target)?;
+        match self.expires {
+            Some(expires) => write!(
+                f,
+                "{}",
+                expires.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+            )?,
+            None => write!(f, "never")?,
+        }
+        if !self.reason.is_empty() {
+            write!(f, " {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+// Checked when a client connects, saved back to its file whenever it changes.
+// One ban per line: "id <player id> <expiry>" or "ip <address> <expiry>", the expiry in
+// unix seconds or "never", then an optional reason. Lines starting with # are ignored.
+#[derive(Resource, Default)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    pub bans: Vec<Ban>,
+}
+
+impl BanList {
+    // A missing file is an empty list, broken lines are skipped with a warning.
+    pub fn load(path: &Path) -> io::Result<BanList> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let mut bans = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Ban::parse(line) {
+                Ok(ban) => bans.push(ban),
+                Err(err) => warn!("{}:{}: {}", path.display(), number + 1, err),
+            }
+        }
+        Ok(BanList {
+            path: Some(path.to_path_buf()),
+            bans,
+        })
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let contents: String = self.bans.iter().map(|ban| format!("{}\n", ban)).collect();
+        if let Err(err) = fs::write(path, contents) {
+            warn!("Could not save ban list to {}: {}", path.display(), err);
+        }
+    }
+
+    // the ban that keeps this player or address out, if any
+    pub fn find(&self, client_id: ClientId, ip: Option<IpAddr>, now: SystemTime) -> Option<&Ban> {
+        self.bans.iter().find(|ban| {
+            ban.is_active(now)
+                && match ban.target {
+                    BanTarget::Player(id) => id == client_id,
+                    BanTarget::Ip(banned) => Some(banned) == ip,
+                }
+        })
+    }
+
+    // replaces an earlier ban on the same target
+    pub fn add(&mut self, ban: Ban) {
+        self.bans.retain(|existing| existing.target != ban.target);
+        self.bans.push(ban);
+        self.save();
+    }
+
+    // true if there was a ban to lift
+    pub fn remove(&mut self, target: BanTarget) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.target != target);
+        let removed = self.bans.len() != before;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn remove_expired(&mut self, now: SystemTime) {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.is_active(now));
+        if self.bans.len() != before {
+            self.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_round_trip_through_text() {
+        let lines = [
+            "id 1234567 never griefing",
+            "ip 192.168.1.20 1900000000",
+            "ip ::1 1900000000 aimbot, see demo 12",
+        ];
+        for line in lines {
+            assert_eq!(Ban::parse(line).unwrap().to_string(), line);
+        }
+        assert!(Ban::parse("id notanumber never").is_err());
+        assert!(Ban::parse("name Player never").is_err());
+        assert!(Ban::parse("ip 10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn bans_match_id_or_address_until_they_expire() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut list = BanList::default();
+        list.add(Ban {
+            target: BanTarget::Player(ClientId::from_raw(42)),
+            expires: Some(now + Duration::from_secs(60)),
+            reason: String::new(),
+        });
+        list.add(Ban {
+            target: BanTarget::Ip("10.0.0.7".parse().unwrap()),
+            expires: None,
+            reason: String::new(),
+        });
+
+        assert!(list.find(ClientId::from_raw(42), None, now).is_some());
+        assert!(list.find(ClientId::from_raw(7), Some("10.0.0.7".parse().unwrap()), now).is_some());
+        assert!(list.find(ClientId::from_raw(7), Some("10.0.0.8".parse().unwrap()), now).is_none());
+
+        let later = now + Duration::from_secs(61);
+        assert!(list.find(ClientId::from_raw(42), None, later).is_none());
+        list.remove_expired(later);
+        assert_eq!(list.bans.len(), 1);
+        assert!(list.remove(BanTarget::Ip("10.0.0.7".parse().unwrap())));
+        assert!(list.bans.is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    error::Error,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use bevy::prelude::*;
+use bevy_renet::{
+    client_just_connected,
+    renet::{
+        transport::ClientAuthentication,
+        RenetClient,
+    },
+    RenetClientPlugin, RenetReceive, RenetSend,
+};
+
+use super::{
+    auth::{load_or_create_identity, request_token, TokenRequest},
+    client_players::ClientPlayersPlugin,
+    connection::{ConnectionPlugin, DisconnectCause},
+    conditioner::ClientConditioner,
+    discovery::ClientDiscoveryPlugin,
+    interpolation::{InterpolationClock, InterpolationPlugin},
+    prediction::PredictionPlugin,
+    protocol::{
+        connection_config, ClientMessage, ServerChannel, ServerMessage,
+        WorldSnapshot, PROTOCOL_ID, PROTOCOL_VERSION,
+    },
+    rates::ClientRates,
+    snapshot::{decode_snapshot, SnapshotHistory},
+    transport::{ClientTransport, ClientTransportPlugin},
+};
+use crate::game::config::args::Args;
+
+#[derive(Resource, Clone, Debug)]
+pub struct ConnectSettings {
+    pub server_addr: SocketAddr,
+    pub name: String,
+    pub auth: ClientAuth,
+}
+
+impl ConnectSettings {
+    // "--connect 127.0.0.1:5000 --name Player"
+    pub fn from_args(args: &Args, auth: &ClientAuth) -> Option<ConnectSettings> {
+        let server_addr = args.value("--connect")?.parse().ok()?;
+        Some(ConnectSettings {
+            server_addr,
+            name: args.value("--name").unwrap_or("Player").to_string(),
+            auth: auth.clone(),
+        })
+    }
+}
+
+// Where connect tokens come from. Without an auth server the client connects unsecured,
+// which only servers started without a private key accept.
+#[derive(Resource, Clone, Debug)]
+pub struct ClientAuth {
+    pub server: Option<SocketAddr>,
+    // holds the secret the auth server knows this player by
+    pub identity_file: PathBuf,
+    // client id when connecting unsecured, kept for the whole session so a reconnect
+    // gets back the slot the server holds for it
+    pub session_id: u64,
+}
+
+impl Default for ClientAuth {
+    fn default() -> Self {
+        ClientAuth {
+            server: None,
+            identity_file: PathBuf::from("identity.txt"),
+            session_id: rand::random(),
+        }
+    }
+}
+
+impl ClientAuth {
+    // "--auth 127.0.0.1:4998 --identity identity.txt"
+    pub fn from_args(args: &Args) -> ClientAuth {
+        let default = ClientAuth::default();
+        ClientAuth {
+            server: args.value("--auth").and_then(|addr| addr.parse().ok()),
+            identity_file: args.value("--identity").map_or(default.identity_file, PathBuf::from),
+            session_id: default.session_id,
+        }
+    }
+}
+
+// id the server assigned us in its Welcome
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct LocalClientId(pub u64);
+
+#[derive(Event)]
+pub struct FromServer(pub ServerMessage);
+
+#[derive(Event)]
+pub struct ToServer(pub ClientMessage);
+
+// a snapshot decoded against its baseline, in arrival order (not tick order)
+#[derive(Event)]
+pub struct SnapshotReceived(pub WorldSnapshot);
+
+// Decoded snapshots kept as baselines for the server's deltas.
+#[derive(Resource, Default)]
+pub struct ReceivedSnapshots {
+    pub history: SnapshotHistory,
+}
+
+pub struct ClientPlugin;
+
+impl Plugin for ClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            RenetClientPlugin,
+            ClientTransportPlugin,
+            PredictionPlugin,
+            InterpolationPlugin,
+            ClientPlayersPlugin,
+            ConnectionPlugin,
+            ClientDiscoveryPlugin,
+        ))
+            .add_event::<FromServer>()
+            .add_event::<ToServer>()
+            .add_event::<SnapshotReceived>()
+            .init_resource::<ReceivedSnapshots>()
+            .init_resource::<ClientConditioner>()
+            .init_resource::<ClientAuth>()
+            .init_resource::<ClientRates>()
+            .add_systems(
+                PreUpdate,
+                (receive_server_messages, decode_snapshots)
+                    .chain()
+                    .after(RenetReceive)
+                    .run_if(resource_exists::<RenetClient>),
+            )
+            .add_systems(
+                Update,
+                (
+                    send_hello.run_if(client_just_connected),
+                    handle_handshake_reply,
+                ),
+            )
+            .add_systems(
+                PostUpdate,
+                send_client_messages
+                    .before(RenetSend)
+                    .run_if(resource_exists::<RenetClient>),
+            );
+    }
+}
+
+pub fn open_connection(
+    settings: &ConnectSettings,
+) -> Result<(RenetClient, ClientTransport), Box<dyn Error>> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let authentication = match settings.auth.server {
+        // blocks for the round trip to the auth server, it's only a stand-in on the LAN
+        Some(auth_server) => {
+            let secret = load_or_create_identity(&settings.auth.identity_file)?;
+            let request = TokenRequest::new(secret, &settings.name, settings.server_addr);
+            let (player_id, connect_token) = request_token(auth_server, &request)
+                .map_err(|err| format!("auth server {}: {}", auth_server, err))?;
+            info!("Signed in as player {}", player_id);
+            ClientAuthentication::Secure { connect_token }
+        }
+        None => ClientAuthentication::Unsecure {
+            protocol_id: PROTOCOL_ID,
+            client_id: settings.auth.session_id,
+            server_addr: settings.server_addr,
+            user_data: None,
+        },
+    };
+    let transport = ClientTransport::new(current_time, authentication, socket)?;
+    let client = RenetClient::new(connection_config());
+
+    Ok((client, transport))
+}
+
+fn send_hello(settings: Res<ConnectSettings>, mut to_server: EventWriter<ToServer>) {
+    info!("Connected to {}, sending hello", settings.server_addr);
+    to_server.send(ToServer(ClientMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        name: settings.name.clone(),
+    }));
+}
+
+pub fn handle_handshake_reply(
+    mut commands: Commands,
+    mut from_server: EventReader<FromServer>,
+    mut to_server: EventWriter<ToServer>,
+    mut client: Option<ResMut<RenetClient>>,
+    mut clock: ResMut<InterpolationClock>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    rates: Res<ClientRates>,
+) {
+    for FromServer(message) in from_server.read() {
+        match message {
+            ServerMessage::Welcome { client_id, tick, tickrate } => {
+                info!(
+                    "Joined server as client {} at tick {} ({} tick)",
+                    client_id, tick, tickrate
+                );
+                // prediction has to step exactly like the server does
+                fixed_time.set_timestep_hz(*tickrate);
+                clock.tickrate = *tickrate;
+                commands.insert_resource(LocalClientId(*client_id));
+                to_server.send(ToServer(ClientMessage::Rates(*rates)));
+            }
+            ServerMessage::Rates(rates) => {
+                info!("Server set cmdrate {}, updaterate {}", rates.cmdrate, rates.updaterate);
+                commands.insert_resource(*rates);
+            }
+            ServerMessage::Rejected(reason) => {
+                error!("Server rejected the connection: {}", reason);
+                commands.insert_resource(DisconnectCause::Server(reason.clone()));
+                if let Some(client) = client.as_mut() {
+                    client.disconnect();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn receive_server_messages(mut client: ResMut<RenetClient>, mut from_server: EventWriter<FromServer>) {
+    for channel in [ServerChannel::Snapshot, ServerChannel::Reliable] {
+        let channel: u8 = channel.into();
+        while let Some(bytes) = client.receive_message(channel) {
+            match ServerMessage::decode(&bytes) {
+                Ok(message) => {
+                    from_server.send(FromServer(message));
+                }
+                Err(err) => warn!("Ignoring message from server: {}", err),
+            }
+        }
+    }
+}
+
+fn decode_snapshots(
+    mut from_server: EventReader<FromServer>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut snapshots: EventWriter<SnapshotReceived>,
+) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::Snapshot(bytes) = message else {
+            continue;
+        };
+        match decode_snapshot(bytes, |tick| received.history.get(tick)) {
+            Ok(snapshot) => {
+                snapshots.send(SnapshotReceived(snapshot.to_world()));
+                received.history.insert(snapshot);
+            }
+            // the next delta against an acked baseline fixes it
+            Err(err) => warn!("Dropping snapshot: {}", err),
+        }
+    }
+}
+
+fn send_client_messages(mut client: ResMut<RenetClient>, mut to_server: EventReader<ToServer>) {
+    for ToServer(message) in to_server.read() {
+        let channel: u8 = message.channel().into();
+        client.send_message(channel, message.encode());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use super::{
+    client::{handle_handshake_reply, FromServer, LocalClientId},
+    interpolation::{RemotePlayer, RemoteViewPivot, SnapshotBuffer},
+    prediction::{PredictionHistory, PredictionSmoothing},
+    protocol::ServerMessage,
+};
+use crate::game::{
+    player::player::{remote_player_body, spawn_hitboxes, spawn_local_player, NetworkId},
+    weapons::weapons::WeaponStats,
+};
+
+// Creates and removes player entities as the server announces them: the local rig for
+// ourselves, a third-person proxy for everyone else.
+pub struct ClientPlayersPlugin;
+
+impl Plugin for ClientPlayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClientPlayers>().add_systems(
+            Update,
+            // Welcome and our own PlayerSpawned can arrive in the same frame
+            (spawn_announced_players, despawn_removed_players, switch_remote_weapons).after(handle_handshake_reply),
+        );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ClientPlayers {
+    pub entities: HashMap<u64, Entity>,
+}
+
+// name drawn above a remote player's head
+#[derive(Component)]
+pub struct Nameplate(pub String);
+
+// the gun model in a remote player's hands, `owner` is their network id
+#[derive(Component)]
+pub struct RemoteGun {
+    pub owner: u64,
+}
+
+fn spawn_announced_players(
+    mut commands: Commands,
+    mut from_server: EventReader<FromServer>,
+    local_id: Option<Res<LocalClientId>>,
+    mut players: ResMut<ClientPlayers>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    weapons: Res<WeaponStats>,
+) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::PlayerSpawned { id, name, position, weapon } = message else {
+            continue;
+        };
+        if players.entities.contains_key(id) {
+            continue;
+        }
+
+        let entity = if local_id.as_ref().is_some_and(|local_id| local_id.0 == *id) {
+            let entity = spawn_local_player(&mut commands, &asset_server, &mut meshes, &mut materials, &weapons, *position);
+            commands.entity(entity).insert((
+                NetworkId(*id),
+                PredictionHistory::default(),
+                PredictionSmoothing::default(),
+            ));
+            entity
+        } else {
+            let model = asset_server.load(weapons.get(*weapon).model.clone());
+            spawn_remote_player(&mut commands, &mut meshes, &mut materials, model, *id, name, *position)
+        };
+        info!("Player '{}' ({}) spawned", name, id);
+        players.entities.insert(*id, entity);
+    }
+}
+
+fn despawn_removed_players(
+    mut commands: Commands,
+    mut from_server: EventReader<FromServer>,
+    mut players: ResMut<ClientPlayers>,
+) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::PlayerDespawned { id } = message else {
+            continue;
+        };
+        if let Some(entity) = players.entities.remove(id) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+fn switch_remote_weapons(
+    mut from_server: EventReader<FromServer>,
+    asset_server: Res<AssetServer>,
+    weapons: Res<WeaponStats>,
+    mut gun_query: Query<(&RemoteGun, &mut Handle<Scene>)>,
+) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::WeaponSwitched { id, weapon } = message else {
+            continue;
+        };
+        for (gun, mut scene) in gun_query.iter_mut() {
+            if gun.owner == *id {
+                *scene = asset_server.load(weapons.get(*weapon).model.clone());
+            }
+        }
+    }
+}
+
+// Third-person body, hitboxes and nameplate. No camera or input, moved by interpolation.
+pub fn spawn_remote_player(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    gun_model: Handle<Scene>,
+    id: u64,
+    name: &str,
+    position: Vec3,
+) -> Entity {
+    let body_mesh = PbrBundle {
+        mesh: meshes.add(Capsule3d::new(0.3, 1.6)),
+        material: materials.add(Color::srgb(0.45, 0.45, 0.5)),
+        ..default()
+    };
+    let gun = (
+        RemoteGun { owner: id },
+        SceneBundle {
+            scene: gun_model,
+            transform: Transform::IDENTITY,
+            ..default()
+        },
+    );
+
+    commands
+        .spawn((
+            RemotePlayer,
+            NetworkId(id),
+            Nameplate(name.to_string()),
+            SnapshotBuffer::default(),
+            remote_player_body(position),
+        ))
+        .with_children(|parent| {
+            parent.spawn(body_mesh);
+            // pitched with the remote player's view so the gun points where they aim
+            parent
+                .spawn((RemoteViewPivot, SpatialBundle::from_transform(Transform::from_xyz(0.0, 1.7, 0.0))))
+                .with_children(|pivot| {
+                    pivot.spawn(gun);
+                });
+            spawn_hitboxes(parent, None);
+        })
+        .id()
+}
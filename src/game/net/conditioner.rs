@@ -0,0 +1,273 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::game::config::args::Args;
+
+// held back this much longer than the rest so later messages overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(30);
+
+// Fake bad network for loopback testing. Applied per direction, so with 50 ms lag on the
+// client alone the round trip grows by 100 ms. It works on UDP packets between netcode and
+// the socket (see transport.rs), so renet sees the loss and resends reliable messages itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConditionerSettings {
+    pub latency: Duration,
+    // latency varies by up to this much either way
+    pub jitter: Duration,
+    // probabilities, 0..1
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    // same seed and traffic gives the same drops
+    pub seed: u64,
+}
+
+impl Default for ConditionerSettings {
+    fn default() -> Self {
+        ConditionerSettings {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.,
+            duplicate: 0.,
+            reorder: 0.,
+            seed: 0,
+        }
+    }
+}
+
+impl ConditionerSettings {
+    // "--net-lag 80 --net-jitter 10" in milliseconds, "--net-loss 5 --net-dup 1 --net-reorder 2"
+    // in percent, "--net-seed 1234"
+    pub fn from_args(args: &Args) -> ConditionerSettings {
+        let percent = |name: &str| (args.parse_or(name, 0.0_f64) / 100.).clamp(0., 1.);
+        ConditionerSettings {
+            latency: Duration::from_millis(args.parse_or("--net-lag", 0)),
+            jitter: Duration::from_millis(args.parse_or("--net-jitter", 0)),
+            loss: percent("--net-loss"),
+            duplicate: percent("--net-dup"),
+            reorder: percent("--net-reorder"),
+            seed: args.parse_or("--net-seed", 0),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self != ConditionerSettings { seed: self.seed, ..default() }
+    }
+}
+
+// A packet on its way through the conditioner. `peer` is the client's address on the server,
+// () on the client where it can only be the server.
+pub struct ConditionedPacket<P> {
+    pub peer: P,
+    pub bytes: Vec<u8>,
+}
+
+struct Delayed<P> {
+    deliver_at: Duration,
+    // keeps messages with the same delivery time in send order
+    order: u64,
+    packet: ConditionedPacket<P>,
+}
+
+struct DelayQueue<P> {
+    packets: Vec<Delayed<P>>,
+    next_order: u64,
+}
+
+impl<P> DelayQueue<P> {
+    fn new() -> DelayQueue<P> {
+        DelayQueue {
+            packets: Vec::new(),
+            next_order: 0,
+        }
+    }
+
+    fn push(&mut self, deliver_at: Duration, packet: ConditionedPacket<P>) {
+        self.packets.push(Delayed {
+            deliver_at,
+            order: self.next_order,
+            packet,
+        });
+        self.next_order += 1;
+    }
+
+    fn take_ready(&mut self, now: Duration) -> Vec<ConditionedPacket<P>> {
+        let (mut ready, waiting): (Vec<_>, Vec<_>) =
+            self.packets.drain(..).partition(|delayed| delayed.deliver_at <= now);
+        self.packets = waiting;
+        ready.sort_by_key(|delayed| (delayed.deliver_at, delayed.order));
+        ready.into_iter().map(|delayed| delayed.packet).collect()
+    }
+}
+
+// Sits between netcode and the UDP socket, on the client and the server.
+#[derive(Resource)]
+pub struct NetworkConditioner<P> {
+    settings: ConditionerSettings,
+    rng: StdRng,
+    outgoing: DelayQueue<P>,
+    incoming: DelayQueue<P>,
+}
+
+pub type ClientConditioner = NetworkConditioner<()>;
+pub type ServerConditioner = NetworkConditioner<SocketAddr>;
+
+impl<P: Clone> Default for NetworkConditioner<P> {
+    fn default() -> Self {
+        NetworkConditioner::new(ConditionerSettings::default())
+    }
+}
+
+impl<P: Clone> NetworkConditioner<P> {
+    pub fn new(settings: ConditionerSettings) -> NetworkConditioner<P> {
+        if settings.is_active() {
+            info!("Simulating network conditions: {:?}", settings);
+        }
+        NetworkConditioner {
+            rng: StdRng::seed_from_u64(settings.seed),
+            settings,
+            outgoing: DelayQueue::new(),
+            incoming: DelayQueue::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &ConditionerSettings {
+        &self.settings
+    }
+
+    // the random sequence only restarts when the seed changes
+    pub fn set_settings(&mut self, settings: ConditionerSettings) {
+        if settings.seed != self.settings.seed {
+            self.rng = StdRng::seed_from_u64(settings.seed);
+        }
+        self.settings = settings;
+    }
+
+    pub fn send(&mut self, now: Duration, packet: ConditionedPacket<P>) {
+        condition(&self.settings, &mut self.rng, &mut self.outgoing, now, packet);
+    }
+
+    pub fn receive(&mut self, now: Duration, packet: ConditionedPacket<P>) {
+        condition(&self.settings, &mut self.rng, &mut self.incoming, now, packet);
+    }
+
+    pub fn ready_to_send(&mut self, now: Duration) -> Vec<ConditionedPacket<P>> {
+        self.outgoing.take_ready(now)
+    }
+
+    pub fn ready_to_receive(&mut self, now: Duration) -> Vec<ConditionedPacket<P>> {
+        self.incoming.take_ready(now)
+    }
+}
+
+fn delay(settings: &ConditionerSettings, rng: &mut StdRng) -> Duration {
+    if settings.jitter.is_zero() {
+        return settings.latency;
+    }
+    let jitter = settings.jitter.as_secs_f64();
+    let offset = rng.random_range(-jitter..=jitter);
+    Duration::from_secs_f64((settings.latency.as_secs_f64() + offset).max(0.))
+}
+
+fn condition<P: Clone>(
+    settings: &ConditionerSettings,
+    rng: &mut StdRng,
+    queue: &mut DelayQueue<P>,
+    now: Duration,
+    packet: ConditionedPacket<P>,
+) {
+    if rng.random_bool(settings.loss) {
+        return;
+    }
+    if rng.random_bool(settings.duplicate) {
+        let copy = ConditionedPacket {
+            peer: packet.peer.clone(),
+            bytes: packet.bytes.clone(),
+        };
+        queue.push(now + delay(settings, rng), copy);
+    }
+    let mut deliver_at = now + delay(settings, rng);
+    if rng.random_bool(settings.reorder) {
+        deliver_at += REORDER_DELAY + settings.jitter;
+    }
+    queue.push(deliver_at, packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(i: u32) -> ConditionedPacket<()> {
+        ConditionedPacket {
+            peer: (),
+            bytes: i.to_le_bytes().to_vec(),
+        }
+    }
+
+    // sends `count` packets a millisecond apart and returns what comes out, in delivery order
+    fn run(settings: ConditionerSettings, count: u32) -> Vec<u32> {
+        let mut conditioner = NetworkConditioner::<()>::new(settings);
+        for i in 0..count {
+            conditioner.send(Duration::from_millis(i as u64), packet(i));
+        }
+        conditioner
+            .ready_to_send(Duration::from_secs(60))
+            .into_iter()
+            .map(|packet| u32::from_le_bytes(packet.bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_drops_and_duplicates() {
+        let settings = ConditionerSettings {
+            loss: 0.2,
+            duplicate: 0.2,
+            seed: 1234,
+            ..default()
+        };
+        let delivered = run(settings.clone(), 500);
+        assert_eq!(delivered, run(settings.clone(), 500));
+
+        let dropped = (0..500).filter(|i| !delivered.contains(i)).count();
+        let duplicated = (0..500).filter(|i| delivered.iter().filter(|d| *d == i).count() > 1).count();
+        assert!(dropped > 0 && duplicated > 0, "{} dropped, {} duplicated", dropped, duplicated);
+
+        assert_ne!(delivered, run(ConditionerSettings { seed: 4321, ..settings }, 500));
+    }
+
+    #[test]
+    fn zero_settings_pass_everything_straight_through() {
+        let settings = ConditionerSettings::default();
+        assert!(!settings.is_active());
+        let mut conditioner = NetworkConditioner::<()>::new(settings);
+        let now = Duration::from_millis(10);
+        for i in 0..4 {
+            conditioner.receive(now, packet(i));
+        }
+        let received = conditioner.ready_to_receive(now);
+        assert_eq!(
+            received.iter().map(|packet| packet.bytes.clone()).collect::<Vec<_>>(),
+            (0..4).map(|i| packet(i).bytes).collect::<Vec<_>>()
+        );
+        assert!(conditioner.ready_to_send(now).is_empty());
+    }
+
+    #[test]
+    fn jitter_and_reordering_shuffle_packets() {
+        let settings = ConditionerSettings {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(40),
+            reorder: 0.5,
+            seed: 7,
+            ..default()
+        };
+        let delivered = run(settings, 200);
+        assert!(delivered.windows(2).any(|pair| pair[0] > pair[1]));
+        // nothing is lost without loss
+        let mut sorted = delivered.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..200).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,167 @@
+use std::{error::Error, fmt};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{transport::NetcodeDisconnectReason, RenetClient};
+
+use super::{
+    client::{open_connection, ConnectSettings, LocalClientId, ReceivedSnapshots},
+    client_players::ClientPlayers,
+    conditioner::ClientConditioner,
+    interpolation::InterpolationClock,
+    protocol::DisconnectReason,
+    rates::{NetRates, DEFAULT_TICKRATE},
+    transport::ClientTransport,
+};
+use crate::game::{
+    app_state::AppState,
+    player::player::{LocalPlayer, NetworkId},
+};
+
+// Moves the app through Connecting -> Loading -> InGame and out to Disconnected when the
+// connection drops, tearing the client down whenever it leaves the game.
+pub struct ConnectionPlugin;
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(AppState::Connecting), despawn_offline_player)
+            .add_systems(
+                Update,
+                (
+                    finish_connecting.run_if(in_state(AppState::Connecting)),
+                    finish_loading.run_if(in_state(AppState::Loading)),
+                    watch_connection.run_if(resource_exists::<RenetClient>),
+                ),
+            )
+            .add_systems(OnEnter(AppState::MainMenu), teardown_client)
+            .add_systems(OnEnter(AppState::Disconnected), teardown_client);
+    }
+}
+
+// why the last connection ended, shown on the disconnect screen
+#[derive(Resource, Clone, Debug)]
+pub enum DisconnectCause {
+    Server(DisconnectReason),
+    Transport(String),
+    Failed(String),
+}
+
+impl fmt::Display for DisconnectCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectCause::Server(reason) => write!(f, "{}", reason),
+            DisconnectCause::Transport(reason) => write!(f, "{}", reason),
+            DisconnectCause::Failed(err) => write!(f, "could not connect: {}", err),
+        }
+    }
+}
+
+impl From<NetcodeDisconnectReason> for DisconnectCause {
+    fn from(reason: NetcodeDisconnectReason) -> Self {
+        match reason {
+            NetcodeDisconnectReason::ConnectionTimedOut
+            | NetcodeDisconnectReason::ConnectionRequestTimedOut
+            | NetcodeDisconnectReason::ConnectionResponseTimedOut => {
+                DisconnectCause::Server(DisconnectReason::Timeout)
+            }
+            // netcode only denies when every slot is taken
+            NetcodeDisconnectReason::ConnectionDenied => DisconnectCause::Server(DisconnectReason::ServerFull),
+            other => DisconnectCause::Transport(other.to_string()),
+        }
+    }
+}
+
+// Opens the socket and hands the client to renet, the caller moves to AppState::Connecting.
+pub fn connect(commands: &mut Commands, settings: ConnectSettings) -> Result<(), Box<dyn Error>> {
+    let (client, transport) = open_connection(&settings)?;
+    info!("Connecting to {} as '{}'", settings.server_addr, settings.name);
+    commands.insert_resource(client);
+    commands.insert_resource(transport);
+    commands.insert_resource(settings);
+    commands.remove_resource::<DisconnectCause>();
+    Ok(())
+}
+
+// the offline rig from "Play offline" makes way for the one the server spawns
+fn despawn_offline_player(mut commands: Commands, offline: Query<Entity, (With<LocalPlayer>, Without<NetworkId>)>) {
+    for entity in offline.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn finish_connecting(local_id: Option<Res<LocalClientId>>, mut next_state: ResMut<NextState<AppState>>) {
+    if local_id.is_some() {
+        next_state.set(AppState::Loading);
+    }
+}
+
+fn finish_loading(
+    received: Res<ReceivedSnapshots>,
+    local_player: Query<(), (With<LocalPlayer>, With<NetworkId>)>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if !local_player.is_empty() && received.history.latest_tick().is_some() {
+        next_state.set(AppState::InGame);
+    }
+}
+
+fn watch_connection(
+    mut commands: Commands,
+    client: Res<RenetClient>,
+    transport: Option<Res<ClientTransport>>,
+    cause: Option<Res<DisconnectCause>>,
+    state: Res<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if !client.is_disconnected() || *state.get() == AppState::Disconnected {
+        return;
+    }
+    // a rejection from the server says more than the transport's reason
+    if cause.is_none() {
+        let cause = match transport.and_then(|transport| transport.disconnect_reason()) {
+            Some(reason) => DisconnectCause::from(reason),
+            None => DisconnectCause::Transport(
+                client
+                    .disconnect_reason()
+                    .map_or("connection lost".to_string(), |reason| reason.to_string()),
+            ),
+        };
+        commands.insert_resource(cause);
+    }
+    next_state.set(AppState::Disconnected);
+}
+
+// Everything a game leaves behind. Runs when entering the main menu or the disconnect
+// screen, after an offline game only the rig is left to remove.
+fn teardown_client(world: &mut World) {
+    let offline: Vec<Entity> = world
+        .query_filtered::<Entity, (With<LocalPlayer>, Without<NetworkId>)>()
+        .iter(world)
+        .collect();
+    for entity in offline {
+        world.entity_mut(entity).despawn_recursive();
+    }
+
+    let Some(mut client) = world.remove_resource::<RenetClient>() else {
+        return;
+    };
+    match world.remove_resource::<ClientTransport>() {
+        Some(mut transport) => transport.leave(&mut client),
+        None => client.disconnect(),
+    }
+    world.remove_resource::<LocalClientId>();
+    world.remove_resource::<NetRates>();
+    world.resource_mut::<Time<Fixed>>().set_timestep_hz(DEFAULT_TICKRATE);
+    world.insert_resource(ReceivedSnapshots::default());
+    world.insert_resource(InterpolationClock::default());
+
+    let settings = world.resource::<ClientConditioner>().settings().clone();
+    world.insert_resource(ClientConditioner::new(settings));
+
+    let players = std::mem::take(&mut world.resource_mut::<ClientPlayers>().entities);
+    for entity in players.into_values() {
+        if let Some(entity) = world.get_entity_mut(entity) {
+            entity.despawn_recursive();
+        }
+    }
+    info!("Disconnected from server");
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    protocol::{decode, encode, PROTOCOL_ID, PROTOCOL_VERSION},
+    server::{ConnectedClients, ServerSettings},
+};
+
+// Servers answer info queries on their game port + QUERY_PORT_OFFSET unless told otherwise,
+// far enough away that servers started side by side on game ports 5000, 5001, ... never
+// take each other's ports. LAN clients scan this range the way Source scans 27015-27020.
+pub const QUERY_PORT_OFFSET: u16 = 10000;
+pub const DISCOVERY_PORTS: std::ops::RangeInclusive<u16> = 15000..=15019;
+// beacons go here, only one client per machine can listen for them
+pub const BEACON_PORT: u16 = 4999;
+const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+// servers that stop answering are dropped from the list after this
+const SERVER_EXPIRY: Duration = Duration::from_secs(10);
+const MAX_PACKET_SIZE: usize = 1200;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    // random per server run, the same server answering on loopback and the LAN is listed once
+    pub server_id: u64,
+    pub name: String,
+    pub map: String,
+    pub mode: String,
+    pub players: u32,
+    pub max_players: u32,
+    pub protocol_version: u32,
+    pub game_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum DiscoveryBody {
+    Query { nonce: u64 },
+    // nonce of the query it answers, None for beacons
+    Info { nonce: Option<u64>, info: ServerInfo },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct DiscoveryPacket {
+    // anything else on the port is ignored
+    protocol_id: u64,
+    body: DiscoveryBody,
+}
+
+impl DiscoveryPacket {
+    fn new(body: DiscoveryBody) -> DiscoveryPacket {
+        DiscoveryPacket {
+            protocol_id: PROTOCOL_ID,
+            body,
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<DiscoveryBody> {
+        decode::<DiscoveryPacket>(bytes)
+            .ok()
+            .filter(|packet| packet.protocol_id == PROTOCOL_ID)
+            .map(|packet| packet.body)
+    }
+}
+
+pub fn default_query_port(game_port: u16) -> u16 {
+    game_port.wrapping_add(QUERY_PORT_OFFSET)
+}
+
+fn bind_nonblocking(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
+
+/* ---------------- Server ---------------- */
+
+pub struct ServerDiscoveryPlugin;
+
+impl Plugin for ServerDiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, open_query_socket)
+            .add_systems(Update, (answer_queries, send_beacons).run_if(resource_exists::<QuerySocket>));
+    }
+}
+
+#[derive(Resource)]
+struct QuerySocket {
+    socket: UdpSocket,
+    server_id: u64,
+}
+
+fn open_query_socket(mut commands: Commands, settings: Res<ServerSettings>) {
+    match bind_nonblocking(SocketAddr::from(([0, 0, 0, 0], settings.query_port))) {
+        Ok(socket) => {
+            info!("Answering server queries on port {}", settings.query_port);
+            commands.insert_resource(QuerySocket {
+                socket,
+                server_id: rand::random(),
+            });
+        }
+        // the game itself works without it, the server just won't show up in browsers
+        Err(err) => warn!("Could not open query port {}: {}", settings.query_port, err),
+    }
+}
+
+fn server_info(socket: &QuerySocket, settings: &ServerSettings, clients: &ConnectedClients) -> ServerInfo {
+    ServerInfo {
+        server_id: socket.server_id,
+        name: settings.name.clone(),
+        map: settings.map.clone(),
+        mode: settings.mode.clone(),
+        players: clients.clients.len() as u32,
+        max_players: settings.max_clients as u32,
+        protocol_version: PROTOCOL_VERSION,
+        game_port: settings.port,
+    }
+}
+
+fn answer_queries(
+    socket: Res<QuerySocket>,
+    settings: Res<ServerSettings>,
+    clients: Res<ConnectedClients>,
+) {
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    while let Ok((len, from)) = socket.socket.recv_from(&mut buffer) {
+        let Some(DiscoveryBody::Query { nonce }) = DiscoveryPacket::decode(&buffer[..len]) else {
+            continue;
+        };
+        let reply = DiscoveryPacket::new(DiscoveryBody::Info {
+            nonce: Some(nonce),
+            info: server_info(&socket, &settings, &clients),
+        });
+        let _ = socket.socket.send_to(&encode(&reply), from);
+    }
+}
+
+fn send_beacons(
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    socket: Res<QuerySocket>,
+    settings: Res<ServerSettings>,
+    clients: Res<ConnectedClients>,
+) {
+    if !settings.beacon {
+        return;
+    }
+    let timer = timer.get_or_insert_with(|| Timer::new(BEACON_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let beacon = encode(&DiscoveryPacket::new(DiscoveryBody::Info {
+        nonce: None,
+        info: server_info(&socket, &settings, &clients),
+    }));
+    // broadcasts don't loop back on lo, so local listeners get their own copy
+    for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        let _ = socket.socket.send_to(&beacon, SocketAddr::from((ip, BEACON_PORT)));
+    }
+}
+
+/* ---------------- Klient ---------------- */
+
+pub struct ClientDiscoveryPlugin;
+
+impl Plugin for ClientDiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerBrowser>()
+            .add_systems(Update, receive_server_info);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BrowserEntry {
+    // where the server answers queries
+    pub query_addr: SocketAddr,
+    pub info: Option<ServerInfo>,
+    pub ping: Option<Duration>,
+    pub manual: bool,
+    last_seen: Instant,
+}
+
+impl BrowserEntry {
+    pub fn game_addr(&self) -> Option<SocketAddr> {
+        let info = self.info.as_ref()?;
+        Some(SocketAddr::new(self.query_addr.ip(), info.game_port))
+    }
+}
+
+#[derive(Resource)]
+pub struct ServerBrowser {
+    socket: Option<UdpSocket>,
+    beacon_socket: Option<UdpSocket>,
+    pub entries: Vec<BrowserEntry>,
+    // send time of every query since the last refresh, a broadcast query gets many answers
+    pending: HashMap<u64, Instant>,
+}
+
+impl Default for ServerBrowser {
+    fn default() -> Self {
+        let socket = bind_nonblocking(SocketAddr::from(([0, 0, 0, 0], 0)))
+            .map_err(|err| warn!("Server browser disabled: {}", err))
+            .ok();
+        // optional, a second client on the same machine just relies on scanning
+        let beacon_socket = bind_nonblocking(SocketAddr::from(([0, 0, 0, 0], BEACON_PORT))).ok();
+        ServerBrowser {
+            socket,
+            beacon_socket,
+            entries: Vec::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl ServerBrowser {
+    fn query(&mut self, addr: SocketAddr) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let nonce = rand::random::<u64>();
+        let packet = encode(&DiscoveryPacket::new(DiscoveryBody::Query { nonce }));
+        if socket.send_to(&packet, addr).is_ok() {
+            self.pending.insert(nonce, Instant::now());
+        }
+    }
+
+    // Scans the LAN and loopback and re-queries every known server for a fresh ping.
+    pub fn refresh(&mut self) {
+        self.pending.clear();
+        self.entries.retain(|entry| entry.manual || entry.last_seen.elapsed() < SERVER_EXPIRY);
+        for port in DISCOVERY_PORTS {
+            self.query(SocketAddr::from((Ipv4Addr::BROADCAST, port)));
+            self.query(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        }
+        let known: Vec<SocketAddr> = self.entries.iter().map(|entry| entry.query_addr).collect();
+        for addr in known {
+            self.query(addr);
+        }
+    }
+
+    // `addr` is the game address, its query port is assumed to be the default one
+    pub fn add_manual(&mut self, addr: SocketAddr) {
+        let query_addr = SocketAddr::new(addr.ip(), default_query_port(addr.port()));
+        if !self.entries.iter().any(|entry| entry.query_addr == query_addr) {
+            self.entries.push(BrowserEntry {
+                query_addr,
+                info: None,
+                ping: None,
+                manual: true,
+                last_seen: Instant::now(),
+            });
+        }
+        self.query(query_addr);
+    }
+
+    fn find(&self, from: SocketAddr, info: &ServerInfo) -> Option<usize> {
+        self.entries.iter().position(|entry| {
+            entry.query_addr == from || entry.info.as_ref().is_some_and(|known| known.server_id == info.server_id)
+        })
+    }
+
+    fn update_entry(&mut self, from: SocketAddr, info: ServerInfo, ping: Option<Duration>) {
+        let entry = match self.find(from, &info) {
+            // a local server answers both the broadcast and the loopback query, loopback is kept
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                if entry.query_addr != from {
+                    if !from.ip().is_loopback() {
+                        return;
+                    }
+                    entry.query_addr = from;
+                }
+                entry
+            }
+            None => {
+                self.entries.push(BrowserEntry {
+                    query_addr: from,
+                    info: None,
+                    ping: None,
+                    manual: false,
+                    last_seen: Instant::now(),
+                });
+                self.entries.last_mut().unwrap()
+            }
+        };
+        entry.info = Some(info);
+        entry.last_seen = Instant::now();
+        if ping.is_some() {
+            entry.ping = ping;
+        }
+    }
+}
+
+fn receive_server_info(mut browser: ResMut<ServerBrowser>) {
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let mut received = Vec::new();
+    for socket in [&browser.socket, &browser.beacon_socket].into_iter().flatten() {
+        while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+            if let Some(DiscoveryBody::Info { nonce, info }) = DiscoveryPacket::decode(&buffer[..len]) {
+                received.push((from, nonce, info));
+            }
+        }
+    }
+
+    for (from, nonce, info) in received {
+        let ping = nonce
+            .and_then(|nonce| browser.pending.get(&nonce))
+            .map(|sent| sent.elapsed());
+        // a beacon from a server we have no ping for yet
+        let unmeasured = nonce.is_none()
+            && browser
+                .find(from, &info)
+                .is_none_or(|index| browser.entries[index].ping.is_none());
+        browser.update_entry(from, info, ping);
+        if unmeasured {
+            browser.query(from);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+
+use super::{
+    client::{LocalClientId, SnapshotReceived},
+    protocol::PlayerState,
+};
+use crate::game::{config::args::Args, player::player::NetworkId};
+
+const MAX_BUFFERED_SNAPSHOTS: usize = 32;
+// render clock further off than this is snapped instead of slowly corrected
+const MAX_CLOCK_DRIFT: f64 = 0.25;
+const CLOCK_CORRECTION: f64 = 0.1;
+
+// Remote players are drawn from buffered server snapshots, a fixed delay behind the newest one.
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterpolationSettings>()
+            .init_resource::<InterpolationClock>()
+            .add_systems(
+                Update,
+                (buffer_snapshots, advance_clock, interpolate_remote_players)
+                    .chain()
+                    .run_if(resource_exists::<LocalClientId>),
+            );
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct InterpolationSettings {
+    // two snapshots at 20 Hz updates, enough to ride out a single lost packet
+    pub delay: Duration,
+    // how long a remote player keeps moving along its last velocity when snapshots stop
+    pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        InterpolationSettings {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(250),
+        }
+    }
+}
+
+impl InterpolationSettings {
+    // "--interp-delay 100 --max-extrapolation 250", both in milliseconds
+    pub fn from_args(args: &Args) -> InterpolationSettings {
+        let default = InterpolationSettings::default();
+        InterpolationSettings {
+            delay: Duration::from_millis(
+                args.parse_or("--interp-delay", default.delay.as_millis() as u64),
+            ),
+            max_extrapolation: Duration::from_millis(args.parse_or(
+                "--max-extrapolation",
+                default.max_extrapolation.as_millis() as u64,
+            )),
+        }
+    }
+}
+
+// Server time (in seconds) the client is currently rendering remote players at.
+#[derive(Resource)]
+pub struct InterpolationClock {
+    pub tickrate: f64,
+    pub latest_tick: Option<u32>,
+    pub render_time: f64,
+}
+
+impl Default for InterpolationClock {
+    fn default() -> Self {
+        InterpolationClock {
+            tickrate: 64.,
+            latest_tick: None,
+            render_time: 0.,
+        }
+    }
+}
+
+impl InterpolationClock {
+    pub fn tick_time(&self, tick: u32) -> f64 {
+        tick as f64 / self.tickrate
+    }
+
+    // server tick the player is looking at, used to rewind hitboxes for lag compensation
+    pub fn render_tick(&self) -> u32 {
+        self.render_tick_before(0.)
+    }
+
+    // for shots that were due earlier in a long frame
+    pub fn render_tick_before(&self, seconds: f64) -> u32 {
+        ((self.render_time - seconds) * self.tickrate).floor().max(0.) as u32
+    }
+}
+
+#[derive(Component)]
+pub struct RemotePlayer;
+
+// child of a remote player that carries the weapon and is pitched with the view
+#[derive(Component)]
+pub struct RemoteViewPivot;
+
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    states: VecDeque<(f64, PlayerState)>,
+    // result of the last sample, read by the view pivot
+    pub sampled: Option<PlayerState>,
+}
+
+impl SnapshotBuffer {
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn push(&mut self, time: f64, state: PlayerState) {
+        if self.states.back().is_some_and(|(latest, _)| time <= *latest) {
+            return;
+        }
+        self.states.push_back((time, state));
+        while self.states.len() > MAX_BUFFERED_SNAPSHOTS {
+            self.states.pop_front();
+        }
+    }
+
+    // drops snapshots that can no longer be part of an interpolation window
+    fn discard_before(&mut self, time: f64) {
+        while self.states.len() > 2 && self.states[1].0 <= time {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<PlayerState> {
+        let (first_time, first) = self.states.front()?;
+        if time <= *first_time {
+            return Some(*first);
+        }
+
+        for ((from_time, from), (to_time, to)) in self.states.iter().zip(self.states.iter().skip(1)) {
+            if time >= *from_time && time <= *to_time {
+                let t = ((time - from_time) / (to_time - from_time)) as f32;
+                return Some(PlayerState {
+                    id: to.id,
+                    position: from.position.lerp(to.position, t),
+                    velocity: from.velocity.lerp(to.velocity, t),
+                    rotation: Vec2::new(
+                        lerp_angle(from.rotation.x, to.rotation.x, t),
+                        lerp_angle(from.rotation.y, to.rotation.y, t),
+                    ),
+                    eye_height: from.eye_height + (to.eye_height - from.eye_height) * t,
+                });
+            }
+        }
+
+        // packets stopped arriving, keep moving for a little while then freeze
+        let (last_time, last) = self.states.back()?;
+        let ahead = (time - last_time).min(max_extrapolation) as f32;
+        Some(PlayerState {
+            position: last.position + last.velocity * ahead,
+            ..*last
+        })
+    }
+}
+
+// shortest way around, angles in degrees
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let difference = (to - from + 180.).rem_euclid(360.) - 180.;
+    from + difference * t
+}
+
+fn buffer_snapshots(
+    local_id: Res<LocalClientId>,
+    mut clock: ResMut<InterpolationClock>,
+    mut snapshots: EventReader<SnapshotReceived>,
+    mut buffer_query: Query<(&NetworkId, &mut SnapshotBuffer)>,
+) {
+    for SnapshotReceived(snapshot) in snapshots.read() {
+        if clock.latest_tick.is_none_or(|latest| snapshot.tick > latest) {
+            clock.latest_tick = Some(snapshot.tick);
+        }
+        let time = clock.tick_time(snapshot.tick);
+        for state in snapshot.players.iter().filter(|state| state.id != local_id.0) {
+            if let Some((_, mut buffer)) = buffer_query.iter_mut().find(|(id, _)| id.0 == state.id) {
+                buffer.push(time, *state);
+            }
+        }
+    }
+}
+
+fn advance_clock(time: Res<Time>, settings: Res<InterpolationSettings>, mut clock: ResMut<InterpolationClock>) {
+    let Some(latest_tick) = clock.latest_tick else {
+        return;
+    };
+    let target = clock.tick_time(latest_tick) - settings.delay.as_secs_f64();
+
+    clock.render_time += time.delta_seconds_f64();
+    let drift = target - clock.render_time;
+    if drift.abs() > MAX_CLOCK_DRIFT {
+        clock.render_time = target;
+    } else {
+        clock.render_time += drift * CLOCK_CORRECTION;
+    }
+}
+
+fn interpolate_remote_players(
+    settings: Res<InterpolationSettings>,
+    clock: Res<InterpolationClock>,
+    mut player_query: Query<(&mut SnapshotBuffer, &mut Transform), With<RemotePlayer>>,
+    mut pivot_query: Query<(&Parent, &mut Transform), (With<RemoteViewPivot>, Without<RemotePlayer>)>,
+) {
+    let max_extrapolation = settings.max_extrapolation.as_secs_f64();
+
+    for (mut buffer, mut transform) in player_query.iter_mut() {
+        buffer.discard_before(clock.render_time);
+        buffer.sampled = buffer.sample(clock.render_time, max_extrapolation);
+        if let Some(state) = buffer.sampled {
+            // body only turns with yaw, hitboxes follow as children
+            transform.translation = state.position;
+            transform.rotation = Quat::from_axis_angle(Vec3::Y, state.rotation.y.to_radians());
+        }
+    }
+
+    for (parent, mut transform) in pivot_query.iter_mut() {
+        let Ok((buffer, _)) = player_query.get(parent.get()) else {
+            continue;
+        };
+        if let Some(state) = buffer.sampled {
+            transform.translation.y = state.eye_height;
+            transform.rotation = Quat::from_axis_angle(Vec3::X, state.rotation.x.to_radians());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    fn state(x: f32, yaw: f32) -> PlayerState {
+        PlayerState {
+            id: 2,
+            position: Vec3::new(x, 0., 0.),
+            velocity: Vec3::new(10., 0., 0.),
+            rotation: Vec2::new(0., yaw),
+            eye_height: 1.7,
+        }
+    }
+
+    #[test]
+    fn samples_between_snapshots_and_extrapolates_briefly() {
+        let mut buffer = SnapshotBuffer::default();
+        assert!(buffer.sample(1., 0.25).is_none());
+        buffer.push(1.0, state(0., 0.));
+        buffer.push(1.1, state(1., 20.));
+        // out of order, dropped
+        buffer.push(1.05, state(5., 0.));
+        assert_eq!(buffer.len(), 2);
+
+        assert_eq!(buffer.sample(0.5, 0.25).unwrap().position.x, 0.);
+        let halfway = buffer.sample(1.05, 0.25).unwrap();
+        assert!((halfway.position.x - 0.5).abs() < 0.001);
+        assert!((halfway.rotation.y - 10.).abs() < 0.001);
+
+        // at 10 m/s along x past the newest snapshot, but never more than the cap
+        let ahead = buffer.sample(1.2, 0.25).unwrap();
+        assert!((ahead.position.x - 2.).abs() < 0.001);
+        let capped = buffer.sample(5., 0.25).unwrap();
+        assert!((capped.position.x - 3.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn old_snapshots_are_dropped_but_two_are_kept() {
+        let mut buffer = SnapshotBuffer::default();
+        for i in 0..5 {
+            buffer.push(i as f64 * 0.1, state(i as f32, 0.));
+        }
+        buffer.discard_before(0.25);
+        assert_eq!(buffer.len(), 3);
+        buffer.discard_before(10.);
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn angles_take_the_short_way_around() {
+        assert!((lerp_angle(350., 10., 0.5).rem_euclid(360.)).abs() < 0.001);
+        assert!((lerp_angle(10., 350., 0.25) - 5.).abs() < 0.001);
+        assert!((lerp_angle(-170., 170., 0.5).rem_euclid(360.) - 180.).abs() < 0.001);
+        assert!((lerp_angle(0., 90., 0.5) - 45.).abs() < 0.001);
+    }
+
+    // renders at `render_time` with the newest snapshot at tick 64 (1 s), then runs a 16 ms frame
+    fn advance(render_time: f64) -> f64 {
+        let mut world = World::new();
+        let mut time = Time::<()>::default();
+        time.advance_by(Duration::from_millis(16));
+        world.insert_resource(time);
+        world.insert_resource(InterpolationSettings::default());
+        world.insert_resource(InterpolationClock {
+            latest_tick: Some(64),
+            render_time,
+            ..default()
+        });
+        world.run_system_once(advance_clock);
+        world.resource::<InterpolationClock>().render_time
+    }
+
+    #[test]
+    fn clock_drift_is_corrected_slowly_and_large_drift_is_snapped() {
+        // 100 ms behind the newest snapshot is the target
+        let target = 0.9;
+        let behind = advance(0.85);
+        assert!(behind > 0.85 + 0.016 && behind < target, "{}", behind);
+        let ahead = advance(0.95);
+        assert!(ahead > target && ahead < 0.95 + 0.016, "{}", ahead);
+
+        assert!((advance(0.) - target).abs() < 1e-9);
+        assert!((advance(3.) - target).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use super::server::{AdvanceTick, ServerTick};
+use crate::game::player::player::{Hitbox, HitboxPart, Player};
+
+// ~1 s at 128 tick, always more than the rewind cap
+const HISTORY_LENGTH: usize = 128;
+pub const MAX_SHOT_DISTANCE: f32 = 1000.;
+
+// Keeps a short history of every player's hitboxes so shots can be checked against
+// what the shooter saw on their screen, not where the targets are now.
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, record_hitboxes.after(AdvanceTick).after(PhysicsSet::Writeback));
+    }
+}
+
+#[derive(Component, Default)]
+pub struct HitboxHistory {
+    frames: VecDeque<HitboxFrame>,
+}
+
+pub struct HitboxFrame {
+    pub tick: u32,
+    pub hitboxes: Vec<RecordedHitbox>,
+}
+
+#[derive(Clone, Copy)]
+pub struct RecordedHitbox {
+    pub entity: Entity,
+    pub part: HitboxPart,
+    pub translation: Vec3,
+    pub rotation: Quat,
+}
+
+impl HitboxHistory {
+    // newest frame not after the requested tick, the oldest one if history doesn't go that far
+    pub fn at(&self, tick: u32) -> Option<&HitboxFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .find(|frame| frame.tick <= tick)
+            .or(self.frames.front())
+    }
+}
+
+pub struct LagCompensatedHit {
+    pub victim: Entity,
+    pub hitbox: Entity,
+    pub part: HitboxPart,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub distance: f32,
+}
+
+// world transforms are composed by hand, GlobalTransform isn't propagated until PostUpdate
+fn record_hitboxes(
+    tick: Res<ServerTick>,
+    mut player_query: Query<(&Transform, &Children, &mut HitboxHistory)>,
+    hitbox_query: Query<(&Hitbox, &Transform)>,
+) {
+    for (transform, children, mut history) in player_query.iter_mut() {
+        let hitboxes = children
+            .iter()
+            .filter_map(|child| {
+                let (hitbox, local) = hitbox_query.get(*child).ok()?;
+                let world = transform.mul_transform(*local);
+                Some(RecordedHitbox {
+                    entity: *child,
+                    part: hitbox.part,
+                    translation: world.translation,
+                    rotation: world.rotation,
+                })
+            })
+            .collect();
+
+        history.frames.push_back(HitboxFrame {
+            tick: tick.0,
+            hitboxes,
+        });
+        while history.frames.len() > HISTORY_LENGTH {
+            history.frames.pop_front();
+        }
+    }
+}
+
+// Ray cast with every other player rewound to `tick`. Rewound hitboxes are tested on their
+// own, the live physics world is only used for level geometry that can stop the bullet.
+pub fn lag_compensated_ray_cast(
+    rapier_context: &RapierContext,
+    shooter: Entity,
+    origin: Vec3,
+    direction: Vec3,
+    tick: u32,
+    history_query: &Query<(Entity, &HitboxHistory)>,
+    hitbox_colliders: &Query<&Collider, With<Hitbox>>,
+    player_parts: &Query<(), Or<(With<Player>, With<Hitbox>)>>,
+) -> Option<LagCompensatedHit> {
+    let predicate = |entity| !player_parts.contains(entity);
+    let world_distance = rapier_context
+        .cast_ray(
+            origin,
+            direction,
+            MAX_SHOT_DISTANCE,
+            true,
+            QueryFilter::default().exclude_sensors().predicate(&predicate),
+        )
+        .map_or(MAX_SHOT_DISTANCE, |(_, distance)| distance);
+
+    let mut closest: Option<LagCompensatedHit> = None;
+    for (victim, history) in history_query.iter() {
+        if victim == shooter {
+            continue;
+        }
+        let Some(frame) = history.at(tick) else {
+            continue;
+        };
+        for recorded in frame.hitboxes.iter() {
+            let Ok(collider) = hitbox_colliders.get(recorded.entity) else {
+                continue;
+            };
+            let max_distance = closest.as_ref().map_or(world_distance, |hit| hit.distance);
+            if let Some(intersection) = collider.cast_ray_and_get_normal(
+                recorded.translation,
+                recorded.rotation,
+                origin,
+                direction,
+                max_distance,
+                true,
+            ) {
+                closest = Some(LagCompensatedHit {
+                    victim,
+                    hitbox: recorded.entity,
+                    part: recorded.part,
+                    point: intersection.point,
+                    normal: intersection.normal,
+                    distance: intersection.time_of_impact,
+                });
+            }
+        }
+    }
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    // casts from the origin towards `target` with everyone rewound to `tick`
+    fn shoot(world: &mut World, target: Vec3, tick: u32) -> Option<(Entity, HitboxPart)> {
+        world.run_system_once(
+            move |rapier_context: Res<RapierContext>,
+                  history_query: Query<(Entity, &HitboxHistory)>,
+                  hitbox_colliders: Query<&Collider, With<Hitbox>>,
+                  player_parts: Query<(), Or<(With<Player>, With<Hitbox>)>>| {
+                lag_compensated_ray_cast(
+                    &rapier_context,
+                    Entity::PLACEHOLDER,
+                    Vec3::ZERO,
+                    target.normalize(),
+                    tick,
+                    &history_query,
+                    &hitbox_colliders,
+                    &player_parts,
+                )
+                .map(|hit| (hit.victim, hit.part))
+            },
+        )
+    }
+
+    #[test]
+    fn shots_hit_where_the_target_was_on_that_tick() {
+        let mut world = World::new();
+        world.init_resource::<RapierContext>();
+        world.insert_resource(ServerTick(10));
+        let victim = world
+            .spawn((Transform::from_xyz(0., 0., -10.), HitboxHistory::default()))
+            .with_children(|parent| {
+                parent.spawn((
+                    Transform::from_xyz(0., 1.5, 0.),
+                    Hitbox { part: HitboxPart::Head },
+                    Collider::ball(0.2),
+                ));
+            })
+            .id();
+        world.run_system_once(record_hitboxes);
+
+        // the target steps aside on the next tick
+        world.resource_mut::<ServerTick>().0 = 11;
+        world.get_mut::<Transform>(victim).unwrap().translation.x = 5.;
+        world.run_system_once(record_hitboxes);
+
+        let old_spot = Vec3::new(0., 1.5, -10.);
+        let new_spot = Vec3::new(5., 1.5, -10.);
+        assert_eq!(shoot(&mut world, old_spot, 10), Some((victim, HitboxPart::Head)));
+        assert_eq!(shoot(&mut world, new_spot, 10), None);
+        assert_eq!(shoot(&mut world, new_spot, 11), Some((victim, HitboxPart::Head)));
+        assert_eq!(shoot(&mut world, old_spot, 11), None);
+        // ticks past the newest frame use it, ticks before the oldest use the oldest
+        assert_eq!(shoot(&mut world, new_spot, 12), Some((victim, HitboxPart::Head)));
+        assert_eq!(shoot(&mut world, old_spot, 3), Some((victim, HitboxPart::Head)));
+    }
+}
@@ -0,0 +1,19 @@
+pub mod auth;
+pub mod bans;
+pub mod client;
+pub mod client_players;
+pub mod conditioner;
+pub mod connection;
+pub mod discovery;
+pub mod interpolation;
+pub mod lag_compensation;
+pub mod prediction;
+pub mod protocol;
+pub mod rates;
+pub mod rcon;
+pub mod server;
+pub mod server_chat;
+pub mod server_combat;
+pub mod server_players;
+pub mod snapshot;
+pub mod transport;
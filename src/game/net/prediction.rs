@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use super::{
+    client::{LocalClientId, ReceivedSnapshots, SnapshotReceived, ToServer},
+    protocol::{ClientMessage, InputCommand, PlayerState},
+    rates::NetRates,
+};
+use crate::game::{
+    player::{
+        input::PlayerInput,
+        player::{LocalPlayer, Player},
+        player_movement::{controller_offset, simulate_movement, update_movement},
+    },
+    weapons::weapons::WeaponStats,
+};
+
+// 2 s of ticks at 64 Hz
+const HISTORY_LENGTH: usize = 128;
+// how many input packets every command is repeated in
+const INPUT_REDUNDANCY: usize = 3;
+// errors below this are float noise, not worth a replay
+const POSITION_TOLERANCE: f32 = 0.01;
+// errors above this (respawn, teleport) are snapped without smoothing
+const MAX_SMOOTHED_ERROR: f32 = 2.0;
+const SMOOTHING_RATE: f32 = 12.0;
+
+// Client-side prediction for the local player: movement runs locally every tick and is
+// corrected against the server's authoritative state when a snapshot arrives.
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (receive_authoritative_state, decay_smoothing).run_if(resource_exists::<LocalClientId>),
+        )
+        .add_systems(
+            FixedUpdate,
+            (
+                (reconcile, send_predicted_input).chain().before(update_movement),
+                record_predicted_state.after(PhysicsSet::Writeback),
+            )
+                .run_if(resource_exists::<LocalClientId>),
+        );
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PredictedTick {
+    pub sequence: u32,
+    pub input: PlayerInput,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub eye_height: f32,
+    pub grounded: bool,
+}
+
+#[derive(Component)]
+pub struct PredictionHistory {
+    next_sequence: u32,
+    ticks: VecDeque<PredictedTick>,
+    latest_server_tick: Option<u32>,
+    // newest authoritative state not yet reconciled, with the input sequence it includes
+    pending: Option<(u32, PlayerState)>,
+}
+
+impl Default for PredictionHistory {
+    fn default() -> Self {
+        PredictionHistory {
+            // 0 is what the server acks before it has simulated anything
+            next_sequence: 1,
+            ticks: VecDeque::with_capacity(HISTORY_LENGTH),
+            latest_server_tick: None,
+            pending: None,
+        }
+    }
+}
+
+impl PredictionHistory {
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    fn push(&mut self, input: PlayerInput) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        if self.ticks.len() == HISTORY_LENGTH {
+            self.ticks.pop_front();
+        }
+        self.ticks.push_back(PredictedTick {
+            sequence,
+            input,
+            position: Vec3::ZERO,
+            velocity: Vec3::ZERO,
+            eye_height: 0.,
+            grounded: false,
+        });
+        sequence
+    }
+}
+
+// Visual offset hiding corrections, added to the camera and decayed towards zero.
+#[derive(Component, Default)]
+pub struct PredictionSmoothing {
+    pub offset: Vec3,
+    // distance between prediction and server state at the last reconciliation
+    pub last_error: f32,
+}
+
+fn receive_authoritative_state(
+    local_id: Res<LocalClientId>,
+    mut snapshots: EventReader<SnapshotReceived>,
+    mut history_query: Query<&mut PredictionHistory, With<LocalPlayer>>,
+) {
+    let Ok(mut history) = history_query.get_single_mut() else {
+        return;
+    };
+    for SnapshotReceived(snapshot) in snapshots.read() {
+        // snapshots are unreliable and may arrive out of order
+        if history.latest_server_tick.is_some_and(|tick| snapshot.tick <= tick) {
+            continue;
+        }
+        if let Some(state) = snapshot.players.iter().find(|state| state.id == local_id.0) {
+            history.latest_server_tick = Some(snapshot.tick);
+            history.pending = Some((snapshot.ack, *state));
+        }
+    }
+}
+
+fn reconcile(
+    time: Res<Time<Fixed>>,
+    weapons: Res<WeaponStats>,
+    mut rapier_context: ResMut<RapierContext>,
+    mut player_query: Query<
+        (
+            Entity,
+            &mut Player,
+            &mut Transform,
+            &Collider,
+            &KinematicCharacterController,
+            &mut PredictionHistory,
+            &mut PredictionSmoothing,
+        ),
+        With<LocalPlayer>,
+    >,
+) {
+    let Ok((entity, mut player, mut transform, collider, controller, mut history, mut smoothing)) =
+        player_query.get_single_mut()
+    else {
+        return;
+    };
+    let Some((ack, server_state)) = history.pending.take() else {
+        return;
+    };
+
+    // everything up to the ack is simulated by the server and can be forgotten
+    let acked = history.ticks.iter().find(|tick| tick.sequence == ack).copied();
+    while history.ticks.front().is_some_and(|tick| tick.sequence <= ack) {
+        history.ticks.pop_front();
+    }
+
+    let error = acked.map_or(f32::INFINITY, |tick| tick.position.distance(server_state.position));
+    if error <= POSITION_TOLERANCE {
+        smoothing.last_error = error;
+        return;
+    }
+
+    // rewind to the server state and replay the inputs it hasn't seen yet
+    player.velocity = server_state.velocity;
+    player.eye_height = server_state.eye_height;
+    let mut position = server_state.position;
+    let mut grounded = acked.is_some_and(|tick| tick.grounded);
+    let delta = time.timestep().as_secs_f32();
+    let filter = QueryFilter::default().exclude_rigid_body(entity).exclude_sensors();
+
+    for tick in history.ticks.iter_mut() {
+        let translation = simulate_movement(&mut player, &tick.input, grounded, delta, &weapons);
+        let options = MoveShapeOptions {
+            up: controller.up,
+            offset: controller_offset(tick.input.crouch),
+            slide: controller.slide,
+            autostep: controller.autostep,
+            max_slope_climb_angle: controller.max_slope_climb_angle,
+            min_slope_slide_angle: controller.min_slope_slide_angle,
+            snap_to_ground: controller.snap_to_ground,
+            ..default()
+        };
+        let output = rapier_context.move_shape(
+            translation,
+            collider,
+            position,
+            transform.rotation,
+            controller.custom_mass.unwrap_or(1.0),
+            &options,
+            filter,
+            |_| {},
+        );
+        position += output.effective_translation;
+        grounded = output.grounded;
+
+        tick.position = position;
+        tick.velocity = player.velocity;
+        tick.eye_height = player.eye_height;
+        tick.grounded = grounded;
+    }
+
+    let correction = transform.translation - position;
+    transform.translation = position;
+    if acked.is_some() {
+        smoothing.last_error = error;
+    }
+    if correction.length() < MAX_SMOOTHED_ERROR {
+        smoothing.offset += correction;
+    } else {
+        smoothing.offset = Vec3::ZERO;
+    }
+}
+
+// Every tick is predicted, but commands only go out at the negotiated cmdrate, batched.
+fn send_predicted_input(
+    received: Res<ReceivedSnapshots>,
+    rates: Option<Res<NetRates>>,
+    mut player_query: Query<(&PlayerInput, &mut PredictionHistory), With<LocalPlayer>>,
+    mut to_server: EventWriter<ToServer>,
+) {
+    let Ok((input, mut history)) = player_query.get_single_mut() else {
+        return;
+    };
+    let sequence = history.push(*input);
+    let interval = rates.map_or(1, |rates| rates.command_interval());
+    if sequence % interval != 0 {
+        return;
+    }
+
+    let skip = history.ticks.len().saturating_sub(INPUT_REDUNDANCY * interval as usize);
+    let commands = history
+        .ticks
+        .iter()
+        .skip(skip)
+        .map(|tick| InputCommand::new(tick.sequence, &tick.input))
+        .collect();
+    to_server.send(ToServer(ClientMessage::Input {
+        commands,
+        last_snapshot: received.history.latest_tick(),
+    }));
+}
+
+// fills in the result of this tick's movement once physics has run
+fn record_predicted_state(
+    mut player_query: Query<
+        (
+            &Transform,
+            &Player,
+            Option<&KinematicCharacterControllerOutput>,
+            &mut PredictionHistory,
+        ),
+        With<LocalPlayer>,
+    >,
+) {
+    let Ok((transform, player, output, mut history)) = player_query.get_single_mut() else {
+        return;
+    };
+    if let Some(tick) = history.ticks.back_mut() {
+        tick.position = transform.translation;
+        tick.velocity = player.velocity;
+        tick.eye_height = player.eye_height;
+        tick.grounded = output.is_some_and(|output| output.grounded);
+    }
+}
+
+fn decay_smoothing(time: Res<Time>, mut smoothing_query: Query<&mut PredictionSmoothing>) {
+    for mut smoothing in smoothing_query.iter_mut() {
+        smoothing.offset *= (-SMOOTHING_RATE * time.delta_seconds()).exp();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    // a local player that predicted one tick at each of `positions`, standing on the last
+    fn predicted_player(world: &mut World, positions: &[Vec3]) -> Entity {
+        let mut history = PredictionHistory::default();
+        for position in positions {
+            history.push(PlayerInput::default());
+            history.ticks.back_mut().unwrap().position = *position;
+        }
+        world
+            .spawn((
+                LocalPlayer,
+                Player::default(),
+                Transform::from_translation(*positions.last().unwrap()),
+                Collider::capsule_y(0.8, 0.3),
+                KinematicCharacterController::default(),
+                history,
+                PredictionSmoothing::default(),
+            ))
+            .id()
+    }
+
+    fn reconcile_world() -> World {
+        let mut world = World::new();
+        world.init_resource::<RapierContext>();
+        world.insert_resource(Time::<Fixed>::from_hz(64.));
+        world.insert_resource(WeaponStats::default());
+        world
+    }
+
+    fn server_state(position: Vec3) -> PlayerState {
+        PlayerState {
+            id: 1,
+            position,
+            velocity: Vec3::ZERO,
+            rotation: Vec2::ZERO,
+            eye_height: Player::default().eye_height,
+        }
+    }
+
+    fn sequences(world: &World, entity: Entity) -> Vec<u32> {
+        let history = world.get::<PredictionHistory>(entity).unwrap();
+        history.ticks.iter().map(|tick| tick.sequence).collect()
+    }
+
+    #[test]
+    fn history_keeps_the_newest_ticks() {
+        let mut history = PredictionHistory::default();
+        assert_eq!(history.push(PlayerInput::default()), 1);
+        for _ in 0..HISTORY_LENGTH + 9 {
+            history.push(PlayerInput::default());
+        }
+        assert_eq!(history.len(), HISTORY_LENGTH);
+        assert_eq!(history.ticks.front().unwrap().sequence, 11);
+        assert_eq!(history.ticks.back().unwrap().sequence, HISTORY_LENGTH as u32 + 10);
+    }
+
+    #[test]
+    fn acked_inputs_are_dropped_when_the_prediction_was_right() {
+        let mut world = reconcile_world();
+        let positions: Vec<Vec3> = (0..5).map(|x| Vec3::new(x as f32, 0., 0.)).collect();
+        let player = predicted_player(&mut world, &positions);
+        world.get_mut::<PredictionHistory>(player).unwrap().pending = Some((3, server_state(positions[2])));
+
+        world.run_system_once(reconcile);
+
+        assert_eq!(sequences(&world, player), vec![4, 5]);
+        assert_eq!(world.get::<Transform>(player).unwrap().translation, positions[4]);
+        assert_eq!(world.get::<PredictionSmoothing>(player).unwrap().offset, Vec3::ZERO);
+    }
+
+    #[test]
+    fn mispredictions_are_replayed_from_the_server_state() {
+        let mut world = reconcile_world();
+        let player = predicted_player(&mut world, &[Vec3::ZERO; 5]);
+        // the server had us half a metre further along x when it simulated tick 3
+        let server_position = Vec3::new(0.5, 0., 0.);
+        world.get_mut::<PredictionHistory>(player).unwrap().pending = Some((3, server_state(server_position)));
+
+        world.run_system_once(reconcile);
+
+        assert_eq!(sequences(&world, player), vec![4, 5]);
+        // ticks 4 and 5 were replayed on top of it, nothing moved us sideways
+        let translation = world.get::<Transform>(player).unwrap().translation;
+        assert!((translation.x - 0.5).abs() < 0.001, "{}", translation);
+        let history = world.get::<PredictionHistory>(player).unwrap();
+        assert_eq!(history.ticks.back().unwrap().position, translation);
+        // the camera is held back where it was and slides over
+        let smoothing = world.get::<PredictionSmoothing>(player).unwrap();
+        assert!((smoothing.last_error - 0.5).abs() < 0.001);
+        assert!((smoothing.offset.x + 0.5).abs() < 0.001, "{}", smoothing.offset);
+    }
+
+    #[test]
+    fn an_unknown_ack_snaps_without_smoothing() {
+        let mut world = reconcile_world();
+        let player = predicted_player(&mut world, &[Vec3::ZERO; 3]);
+        // far off and acking a tick the history no longer has, as after a respawn
+        world.get_mut::<PredictionHistory>(player).unwrap().pending = Some((0, server_state(Vec3::new(0., 0., 20.))));
+
+        world.run_system_once(reconcile);
+
+        assert_eq!(sequences(&world, player), vec![1, 2, 3]);
+        assert!(world.get::<Transform>(player).unwrap().translation.z > 19.);
+        assert_eq!(world.get::<PredictionSmoothing>(player).unwrap().offset, Vec3::ZERO);
+    }
+}
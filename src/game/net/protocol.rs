@@ -0,0 +1,626 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{ChannelConfig, ConnectionConfig, SendType};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::rates::{ClientRates, NetRates};
+use crate::game::{
+    player::{
+        input::PlayerInput,
+        player::{Team, WeaponType},
+    },
+    weapons::ammo::Ammo,
+};
+
+// Bump whenever a message layout changes so old builds are turned away in the handshake.
+pub const PROTOCOL_VERSION: u32 = 16;
+// netcode protocol id, identical for all builds so a mismatch can be reported instead of silently dropped
+pub const PROTOCOL_ID: u64 = 0x5246_504e;
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub const MAX_NAME_LENGTH: usize = 32;
+// in characters, longer chat messages are cut
+pub const MAX_CHAT_LENGTH: usize = 127;
+
+/* ---------------- Kanaler ---------------- */
+
+pub enum ClientChannel {
+    Input,
+    Command,
+}
+
+pub enum ServerChannel {
+    Snapshot,
+    Reliable,
+}
+
+impl From<ClientChannel> for u8 {
+    fn from(channel: ClientChannel) -> u8 {
+        match channel {
+            ClientChannel::Input => 0,
+            ClientChannel::Command => 1,
+        }
+    }
+}
+
+impl From<ServerChannel> for u8 {
+    fn from(channel: ServerChannel) -> u8 {
+        match channel {
+            ServerChannel::Snapshot => 0,
+            ServerChannel::Reliable => 1,
+        }
+    }
+}
+
+impl ClientChannel {
+    pub fn configs() -> Vec<ChannelConfig> {
+        vec![
+            ChannelConfig {
+                channel_id: ClientChannel::Input.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: ClientChannel::Command.into(),
+                max_memory_usage_bytes: 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
+        ]
+    }
+}
+
+impl ServerChannel {
+    pub fn configs() -> Vec<ChannelConfig> {
+        vec![
+            ChannelConfig {
+                channel_id: ServerChannel::Snapshot.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::Unreliable,
+            },
+            ChannelConfig {
+                channel_id: ServerChannel::Reliable.into(),
+                max_memory_usage_bytes: 5 * 1024 * 1024,
+                send_type: SendType::ReliableOrdered {
+                    resend_time: Duration::from_millis(200),
+                },
+            },
+        ]
+    }
+}
+
+pub fn connection_config() -> ConnectionConfig {
+    ConnectionConfig {
+        available_bytes_per_tick: 60_000,
+        client_channels_config: ClientChannel::configs(),
+        server_channels_config: ServerChannel::configs(),
+    }
+}
+
+/* ---------------- Meddelanden ---------------- */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputCommand {
+    // increases by one every client tick, acknowledged in snapshots
+    pub sequence: u32,
+    pub movement: Vec2,
+    pub jump: bool,
+    pub crouch: bool,
+    // CameraController::rotation, x = pitch and y = yaw in degrees
+    pub rotation: Vec2,
+}
+
+impl InputCommand {
+    pub fn new(sequence: u32, input: &PlayerInput) -> InputCommand {
+        InputCommand {
+            sequence,
+            movement: input.movement,
+            jump: input.jump,
+            crouch: input.crouch,
+            rotation: input.rotation,
+        }
+    }
+
+    // Inputs come from the network, clamp them to what a real client can produce.
+    pub fn to_input(&self) -> PlayerInput {
+        if !self.movement.is_finite() || !self.rotation.is_finite() {
+            return PlayerInput::default();
+        }
+        PlayerInput {
+            movement: self.movement.clamp(Vec2::splat(-1.), Vec2::splat(1.)),
+            jump: self.jump,
+            crouch: self.crouch,
+            rotation: Vec2::new(self.rotation.x.clamp(-90., 90.), self.rotation.y % 360.),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    Hello { protocol_version: u32, name: String },
+    // commands oldest first, the last few are repeated in case a packet is lost;
+    // last_snapshot is the newest snapshot tick received, the server's next delta baseline
+    Input { commands: Vec<InputCommand>, last_snapshot: Option<u32> },
+    // tick is the one being rendered, origin the client's eye position and rotation the aim
+    // without recoil; the server re-runs the shot with its own spray offset and only uses
+    // origin to detect disagreement. spray is the shot's index in the recoil pattern, the
+    // server always uses its own count and only logs when they differ
+    Fire { tick: u32, origin: Vec3, rotation: Vec2, weapon: WeaponType, spray: u32 },
+    SwitchWeapon(WeaponType),
+    // the held weapon, the server ignores it if there is nothing to reload
+    Reload,
+    Chat { channel: ChatChannel, text: String },
+    // sent once the Welcome arrived, kept out of the hello so its layout never changes
+    Rates(ClientRates),
+    // the burst toggle, it only applies to weapons that have a burst
+    FireMode { burst: bool },
+    // sent right before disconnecting on purpose, the server frees the slot instead of holding
+    // it for a reconnect
+    Leave,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    Welcome { client_id: u64, tick: u32, tickrate: f64 },
+    Rejected(DisconnectReason),
+    // weapon is the one in their hands, for the model others see
+    PlayerSpawned { id: u64, name: String, position: Vec3, weapon: WeaponType },
+    PlayerDespawned { id: u64 },
+    // delta-encoded WorldSnapshot, see snapshot.rs
+    Snapshot(Vec<u8>),
+    // from is None for messages from the server itself: joins, leaves, round results
+    Chat { from: Option<ChatSender>, channel: ChatChannel, text: String },
+    // the client's rates after the server's limits, the fastest allowed until the first one
+    Rates(NetRates),
+    // everyone in the match, including players whose slot is held for a reconnect
+    Scoreboard(Vec<ScoreboardEntry>),
+    // once a second for the net graph: mean server frame time in ms, and the share of
+    // the server's packets to this client that were lost
+    ServerStats { tick_time: f32, packet_loss: f32 },
+    // the receiving player's rounds for every gun they carry
+    Ammo(Vec<(WeaponType, Ammo)>),
+    // another player took out a different weapon
+    WeaponSwitched { id: u64, weapon: WeaponType },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScoreboardEntry {
+    pub id: u64,
+    pub name: String,
+    pub team: Team,
+    pub kills: u32,
+    pub deaths: u32,
+    // in ms, 0 while disconnected
+    pub ping: u32,
+    pub connected: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatChannel {
+    All,
+    Team,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatSender {
+    pub id: u64,
+    pub name: String,
+    // dead players' messages only reach other dead players
+    pub dead: bool,
+}
+
+// What a snapshot decodes to, it only travels quantized (see snapshot.rs).
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldSnapshot {
+    pub tick: u32,
+    // last input sequence the server simulated for the receiving client
+    pub ack: u32,
+    pub players: Vec<PlayerState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerState {
+    pub id: u64,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub rotation: Vec2,
+    pub eye_height: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    ProtocolMismatch { server: u32, client: u32 },
+    ServerFull,
+    Timeout,
+    Kicked(String),
+    Banned,
+    InvalidName,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ProtocolMismatch { server, client } => write!(
+                f,
+                "protocol mismatch (server v{}, client v{}), update your game",
+                server, client
+            ),
+            DisconnectReason::ServerFull => write!(f, "server is full"),
+            DisconnectReason::Timeout => write!(f, "connection timed out"),
+            DisconnectReason::Kicked(reason) => write!(f, "kicked: {}", reason),
+            DisconnectReason::Banned => write!(f, "banned from this server"),
+            DisconnectReason::InvalidName => write!(f, "invalid player name"),
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn channel(&self) -> ClientChannel {
+        match self {
+            ClientMessage::Input { .. } => ClientChannel::Input,
+            _ => ClientChannel::Command,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ClientMessage, ProtocolError> {
+        decode(bytes)
+    }
+}
+
+impl ServerMessage {
+    pub fn channel(&self) -> ServerChannel {
+        match self {
+            ServerMessage::Snapshot(_) => ServerChannel::Snapshot,
+            _ => ServerChannel::Reliable,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ServerMessage, ProtocolError> {
+        decode(bytes)
+    }
+}
+
+/* ---------------- Kodning ---------------- */
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Decode(bincode::error::DecodeError),
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Decode(err) => write!(f, "malformed message: {}", err),
+            ProtocolError::TrailingBytes(count) => write!(f, "{} trailing bytes after message", count),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+pub(super) fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serde::encode_to_vec(message, bincode::config::standard())
+        .expect("protocol messages are always encodable")
+}
+
+pub(super) fn decode<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T, ProtocolError> {
+    let config = bincode::config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+    let (message, read) =
+        bincode::serde::decode_from_slice(bytes, config).map_err(ProtocolError::Decode)?;
+    if read != bytes.len() {
+        return Err(ProtocolError::TrailingBytes(bytes.len() - read));
+    }
+    Ok(message)
+}
+
+/* ---------------- TCP-ramar ---------------- */
+
+// RCON and the auth service talk over TCP: a little endian u32 length, then the bincode body.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+pub fn write_frame<T: Serialize>(stream: &mut impl Write, message: &T) -> io::Result<()> {
+    let body = encode(message);
+    stream.write_all(&(body.len() as u32).to_le_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+// Blocking, for the command line tools. Servers read frames without blocking, see take_frame.
+pub fn read_frame<T: DeserializeOwned>(stream: &mut impl Read) -> io::Result<T> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    decode(&body).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+// Next complete frame in `buffer`, None until enough bytes have arrived.
+pub(super) fn take_frame<T: DeserializeOwned>(buffer: &mut Vec<u8>) -> Option<io::Result<T>> {
+    let len = u32::from_le_bytes(buffer.get(..4)?.try_into().unwrap()) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Some(Err(io::Error::new(ErrorKind::InvalidData, "frame too large")));
+    }
+    if buffer.len() < 4 + len {
+        return None;
+    }
+    let frame: Vec<u8> = buffer.drain(..4 + len).skip(4).collect();
+    Some(decode(&frame).map_err(|err| io::Error::new(ErrorKind::InvalidData, err)))
+}
+
+// Result of checking a client's Hello, shared by the server handshake and tests.
+pub fn check_hello(protocol_version: u32, name: &str) -> Result<(), DisconnectReason> {
+    if protocol_version != PROTOCOL_VERSION {
+        return Err(DisconnectReason::ProtocolMismatch {
+            server: PROTOCOL_VERSION,
+            client: protocol_version,
+        });
+    }
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(DisconnectReason::InvalidName);
+    }
+    Ok(())
+}
+
+// Control characters stripped and cut to MAX_CHAT_LENGTH, None when nothing is left to say.
+pub fn sanitize_chat(text: &str) -> Option<String> {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_CHAT_LENGTH)
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_messages() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                name: "Player".to_string(),
+            },
+            ClientMessage::Input {
+                commands: vec![
+                    InputCommand {
+                        sequence: 41,
+                        movement: Vec2::new(1., -1.),
+                        jump: true,
+                        crouch: false,
+                        rotation: Vec2::new(-12.5, 270.),
+                    },
+                    InputCommand {
+                        sequence: 42,
+                        movement: Vec2::ZERO,
+                        jump: false,
+                        crouch: true,
+                        rotation: Vec2::new(-12.5, 271.),
+                    },
+                ],
+                last_snapshot: Some(98),
+            },
+            ClientMessage::Fire {
+                tick: 1234,
+                origin: Vec3::new(0., 1.7, 0.),
+                rotation: Vec2::new(3., 45.),
+                weapon: WeaponType::Rifle,
+                spray: 4,
+            },
+            ClientMessage::SwitchWeapon(WeaponType::Pistol),
+            ClientMessage::Reload,
+            ClientMessage::Chat {
+                channel: ChatChannel::Team,
+                text: "gg wp åäö".to_string(),
+            },
+            ClientMessage::Rates(ClientRates {
+                cmdrate: 64,
+                updaterate: 128,
+            }),
+            ClientMessage::FireMode { burst: true },
+            ClientMessage::Leave,
+        ]
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::Welcome {
+                client_id: 42,
+                tick: 7,
+                tickrate: 64.,
+            },
+            ServerMessage::Rejected(DisconnectReason::ProtocolMismatch { server: 2, client: 1 }),
+            ServerMessage::Rejected(DisconnectReason::Kicked("afk".to_string())),
+            ServerMessage::PlayerSpawned {
+                id: 42,
+                name: "Player".to_string(),
+                position: Vec3::new(0., 30., 0.),
+                weapon: WeaponType::Pistol,
+            },
+            ServerMessage::PlayerDespawned { id: 42 },
+            ServerMessage::Snapshot(vec![99, 0, 0, 0, 42, 0, 0, 0, 0b1010]),
+            ServerMessage::Chat {
+                from: None,
+                channel: ChatChannel::All,
+                text: "Player joined".to_string(),
+            },
+            ServerMessage::Chat {
+                from: Some(ChatSender {
+                    id: 42,
+                    name: "Player".to_string(),
+                    dead: true,
+                }),
+                channel: ChatChannel::Team,
+                text: "rotate b".to_string(),
+            },
+            ServerMessage::Rates(NetRates {
+                tickrate: 128.,
+                cmdrate: 64,
+                updaterate: 128,
+            }),
+            ServerMessage::Scoreboard(vec![
+                ScoreboardEntry {
+                    id: 42,
+                    name: "Player".to_string(),
+                    team: Team::CounterTerrorists,
+                    kills: 12,
+                    deaths: 3,
+                    ping: 35,
+                    connected: true,
+                },
+                ScoreboardEntry {
+                    id: 7,
+                    name: "Dropped".to_string(),
+                    team: Team::Terrorists,
+                    kills: 0,
+                    deaths: 1,
+                    ping: 0,
+                    connected: false,
+                },
+            ]),
+            ServerMessage::ServerStats {
+                tick_time: 1.25,
+                packet_loss: 0.02,
+            },
+            ServerMessage::Ammo(vec![
+                (WeaponType::Rifle, Ammo { magazine: 17, reserve: 90 }),
+                (WeaponType::Pistol, Ammo { magazine: 0, reserve: 0 }),
+            ]),
+            ServerMessage::WeaponSwitched {
+                id: 42,
+                weapon: WeaponType::Sniper,
+            },
+        ]
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for message in client_messages() {
+            let decoded = ClientMessage::decode(&message.encode()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for message in server_messages() {
+            let decoded = ServerMessage::decode(&message.encode()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn input_command_from_player_input() {
+        let input = PlayerInput {
+            movement: Vec2::new(1., 0.),
+            jump: false,
+            crouch: true,
+            rotation: Vec2::new(5., 90.),
+        };
+        let command = InputCommand::new(7, &input);
+        assert_eq!(command.sequence, 7);
+        assert_eq!(command.to_input(), input);
+    }
+
+    #[test]
+    fn input_command_is_clamped() {
+        let command = InputCommand {
+            sequence: 1,
+            movement: Vec2::new(50., -3.),
+            jump: false,
+            crouch: false,
+            rotation: Vec2::new(400., 10.),
+        };
+        let input = command.to_input();
+        assert_eq!(input.movement, Vec2::new(1., -1.));
+        assert_eq!(input.rotation.x, 90.);
+    }
+
+    #[test]
+    fn empty_and_garbage_input_is_rejected() {
+        assert!(ClientMessage::decode(&[]).is_err());
+        assert!(ServerMessage::decode(&[]).is_err());
+        // variant index far outside the enum
+        assert!(ClientMessage::decode(&[200, 1, 2, 3]).is_err());
+        assert!(ServerMessage::decode(&[0xff; 16]).is_err());
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        for message in server_messages() {
+            let bytes = message.encode();
+            assert!(ServerMessage::decode(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut bytes = ClientMessage::SwitchWeapon(WeaponType::Knife).encode();
+        bytes.push(0);
+        assert!(matches!(
+            ClientMessage::decode(&bytes),
+            Err(ProtocolError::TrailingBytes(1))
+        ));
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        // an empty Chat on the all channel with its string length swapped for one far beyond
+        // MAX_MESSAGE_SIZE, 252 marks a u32 varint
+        let mut bytes = ClientMessage::Chat {
+            channel: ChatChannel::All,
+            text: String::new(),
+        }
+        .encode();
+        assert_eq!(bytes.pop(), Some(0));
+        bytes.push(252);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            ClientMessage::decode(&bytes),
+            Err(ProtocolError::Decode(bincode::error::DecodeError::LimitExceeded))
+        ));
+    }
+
+    #[test]
+    fn handshake_rejects_other_versions() {
+        assert!(check_hello(PROTOCOL_VERSION, "Player").is_ok());
+        assert_eq!(
+            check_hello(PROTOCOL_VERSION + 1, "Player"),
+            Err(DisconnectReason::ProtocolMismatch {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1,
+            })
+        );
+        assert_eq!(check_hello(PROTOCOL_VERSION, "   "), Err(DisconnectReason::InvalidName));
+    }
+
+    #[test]
+    fn chat_is_sanitized() {
+        assert_eq!(sanitize_chat("  gg\n wp \u{7}"), Some("gg wp".to_string()));
+        assert_eq!(sanitize_chat(" \t\r\n"), None);
+        let long = "a".repeat(MAX_CHAT_LENGTH * 2);
+        assert_eq!(sanitize_chat(&long).unwrap().chars().count(), MAX_CHAT_LENGTH);
+    }
+}
@@ -0,0 +1,166 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::game::config::args::Args;
+
+// Bevy's default fixed timestep, also what the game simulates at offline
+pub const DEFAULT_TICKRATE: f64 = 64.;
+pub const MIN_TICKRATE: f64 = 16.;
+pub const MAX_TICKRATE: f64 = 256.;
+
+// Out of range tickrates are pulled into MIN_TICKRATE..=MAX_TICKRATE.
+pub fn clamp_tickrate(tickrate: f64) -> f64 {
+    if !tickrate.is_finite() {
+        return DEFAULT_TICKRATE;
+    }
+    tickrate.clamp(MIN_TICKRATE, MAX_TICKRATE)
+}
+
+// What a client asks for in packets per second (cl_cmdrate, cl_updaterate), sent in
+// ClientMessage::Rates once the Welcome has arrived.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientRates {
+    pub cmdrate: u32,
+    pub updaterate: u32,
+}
+
+impl Default for ClientRates {
+    // as often as the server allows
+    fn default() -> Self {
+        ClientRates {
+            cmdrate: MAX_TICKRATE as u32,
+            updaterate: MAX_TICKRATE as u32,
+        }
+    }
+}
+
+impl ClientRates {
+    // "--cmdrate 64 --updaterate 64"
+    pub fn from_args(args: &Args) -> ClientRates {
+        let default = ClientRates::default();
+        ClientRates {
+            cmdrate: args.parse_or("--cmdrate", default.cmdrate),
+            updaterate: args.parse_or("--updaterate", default.updaterate),
+        }
+    }
+}
+
+// Bounds the server puts on the clients' rates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimits {
+    pub min_cmdrate: u32,
+    pub max_cmdrate: u32,
+    pub min_updaterate: u32,
+    pub max_updaterate: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            min_cmdrate: 20,
+            max_cmdrate: MAX_TICKRATE as u32,
+            min_updaterate: 20,
+            max_updaterate: MAX_TICKRATE as u32,
+        }
+    }
+}
+
+impl RateLimits {
+    // "--min-cmdrate 20 --max-cmdrate 128 --min-updaterate 20 --max-updaterate 128"
+    pub fn from_args(args: &Args) -> RateLimits {
+        let default = RateLimits::default();
+        RateLimits {
+            min_cmdrate: args.parse_or("--min-cmdrate", default.min_cmdrate),
+            max_cmdrate: args.parse_or("--max-cmdrate", default.max_cmdrate),
+            min_updaterate: args.parse_or("--min-updaterate", default.min_updaterate),
+            max_updaterate: args.parse_or("--max-updaterate", default.max_updaterate),
+        }
+    }
+
+    // Clamped to the limits, and never faster than one packet per tick.
+    pub fn negotiate(&self, tickrate: f64, requested: ClientRates) -> NetRates {
+        let per_tick = tickrate.round() as u32;
+        // max wins over min if they were configured the wrong way round
+        let limit = |rate: u32, min: u32, max: u32| rate.max(min).min(max).min(per_tick).max(1);
+        NetRates {
+            tickrate,
+            cmdrate: limit(requested.cmdrate, self.min_cmdrate, self.max_cmdrate),
+            updaterate: limit(requested.updaterate, self.min_updaterate, self.max_updaterate),
+        }
+    }
+}
+
+// The rates in effect for one client, reported to it in ServerMessage::Rates.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NetRates {
+    pub tickrate: f64,
+    pub cmdrate: u32,
+    pub updaterate: u32,
+}
+
+impl NetRates {
+    // ticks between two input packets, each carries the commands of all of them
+    pub fn command_interval(&self) -> u32 {
+        ticks_between(self.tickrate, self.cmdrate)
+    }
+
+    // ticks between two snapshots
+    pub fn snapshot_interval(&self) -> u32 {
+        ticks_between(self.tickrate, self.updaterate)
+    }
+}
+
+fn ticks_between(tickrate: f64, rate: u32) -> u32 {
+    (tickrate / rate.max(1) as f64).round().max(1.) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tickrate_stays_in_range() {
+        assert_eq!(clamp_tickrate(128.), 128.);
+        assert_eq!(clamp_tickrate(1.), MIN_TICKRATE);
+        assert_eq!(clamp_tickrate(1000.), MAX_TICKRATE);
+        assert_eq!(clamp_tickrate(f64::NAN), DEFAULT_TICKRATE);
+    }
+
+    #[test]
+    fn rates_are_clamped_by_the_server_and_the_tickrate() {
+        let limits = RateLimits {
+            min_cmdrate: 30,
+            max_cmdrate: 100,
+            min_updaterate: 20,
+            max_updaterate: 128,
+        };
+        let asked = |cmdrate, updaterate| ClientRates { cmdrate, updaterate };
+
+        let rates = limits.negotiate(64., asked(128, 128));
+        assert_eq!((rates.cmdrate, rates.updaterate), (64, 64));
+        let rates = limits.negotiate(128., asked(128, 128));
+        assert_eq!((rates.cmdrate, rates.updaterate), (100, 128));
+        let rates = limits.negotiate(128., asked(1, 0));
+        assert_eq!((rates.cmdrate, rates.updaterate), (30, 20));
+
+        let backwards = RateLimits {
+            min_cmdrate: 100,
+            max_cmdrate: 10,
+            ..limits
+        };
+        assert_eq!(backwards.negotiate(64., asked(64, 64)).cmdrate, 10);
+    }
+
+    #[test]
+    fn intervals_follow_the_tickrate() {
+        let limits = RateLimits::default();
+        let rates = |tickrate, rate| limits.negotiate(tickrate, ClientRates { cmdrate: rate, updaterate: rate });
+
+        assert_eq!(rates(64., 64).snapshot_interval(), 1);
+        assert_eq!(rates(128., 128).snapshot_interval(), 1);
+        assert_eq!(rates(128., 64).snapshot_interval(), 2);
+        assert_eq!(rates(128., 64).command_interval(), 2);
+        assert_eq!(rates(64., 20).command_interval(), 3);
+        assert_eq!(rates(128., 20).command_interval(), 6);
+    }
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{ErrorKind, Read},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::{ClientId, RenetServer};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    conditioner::ServerConditioner,
+    protocol::{take_frame, write_frame, DisconnectReason, ServerMessage, MAX_NAME_LENGTH},
+    bans::{Ban, BanList, BanTarget},
+    server::{BandwidthStats, ConnectedClients, PendingDisconnects, ServerSettings, ServerTick, ToClient},
+    server_chat::SystemChat,
+    server_players::{DisconnectedPlayers, RestartRound, ServerPlayers},
+    transport::ServerTransport,
+};
+use crate::game::player::player::Team;
+
+// this many wrong passwords from one address within the window locks it out
+const MAX_AUTH_FAILURES: u32 = 3;
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+const AUTH_LOCKOUT: Duration = Duration::from_secs(300);
+// connections that haven't authenticated by then are closed
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const RCON_HELP: &str = "\
+status                      players, map and tick
+kick <id|name> [reason]     disconnect a player
+ban <id|name> [ip] [minutes] [reason]
+                            kick and refuse the player id, with 'ip' their address too,
+                            for good without minutes
+unban <id|ip>               lift a ban
+changelevel <map>           switch map and restart the round
+say <text>                  chat message from the console
+set <cvar> [value]          show or change a setting, 'set' alone lists them
+restart_round               everyone back to spawn with full health
+help                        this text";
+
+/* ---------------- Protokoll ---------------- */
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RconRequest {
+    Auth { password: String },
+    Command(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RconResponse {
+    Authenticated,
+    // the server closes the connection after sending this
+    Denied(String),
+    Output(String),
+    Error(String),
+}
+
+/* ---------------- Kommandon ---------------- */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RconCommand {
+    Status,
+    Kick { target: String, reason: String },
+    // ip also bans the address, off by default since on a LAN that is often everyone's
+    Ban { target: String, ip: bool, minutes: Option<u64>, reason: String },
+    Unban(String),
+    ChangeLevel(String),
+    Say(String),
+    Set { cvar: Option<String>, value: Option<String> },
+    RestartRound,
+    Help,
+}
+
+impl RconCommand {
+    pub fn parse(line: &str) -> Result<RconCommand, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let (first, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let required = |value: &str, usage: &str| {
+            if value.is_empty() {
+                Err(format!("usage: {}", usage))
+            } else {
+                Ok(value.to_string())
+            }
+        };
+
+        match name.to_lowercase().as_str() {
+            "status" => Ok(RconCommand::Status),
+            "kick" => Ok(RconCommand::Kick {
+                target: required(first, "kick <id|name> [reason]")?,
+                reason: match remainder.trim() {
+                    "" => "kicked by an admin".to_string(),
+                    reason => reason.to_string(),
+                },
+            }),
+            "ban" => {
+                let target = required(first, "ban <id|name> [ip] [minutes] [reason]")?;
+                let remainder = remainder.trim();
+                let (word, after) = remainder.split_once(char::is_whitespace).unwrap_or((remainder, ""));
+                let ip = word.eq_ignore_ascii_case("ip");
+                let remainder = if ip { after.trim() } else { remainder };
+                let (minutes, reason) = match remainder.split_once(char::is_whitespace).unwrap_or((remainder, "")) {
+                    (minutes, reason) if minutes.parse::<u64>().is_ok() => (minutes.parse().ok(), reason.trim()),
+                    _ => (None, remainder),
+                };
+                Ok(RconCommand::Ban {
+                    target,
+                    ip,
+                    minutes,
+                    reason: reason.to_string(),
+                })
+            }
+            "unban" => Ok(RconCommand::Unban(required(first, "unban <id|ip>")?)),
+            "changelevel" => Ok(RconCommand::ChangeLevel(required(first, "changelevel <map>")?)),
+            "say" => Ok(RconCommand::Say(required(rest, "say <text>")?)),
+            "set" => Ok(RconCommand::Set {
+                cvar: (!first.is_empty()).then(|| first.to_lowercase()),
+                value: (!remainder.trim().is_empty()).then(|| remainder.trim().to_string()),
+            }),
+            "restart_round" => Ok(RconCommand::RestartRound),
+            "help" | "" => Ok(RconCommand::Help),
+            other => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+}
+
+// client id, or a name when it isn't one
+fn find_client(world: &World, target: &str) -> Result<(ClientId, String), String> {
+    let clients = world.resource::<ConnectedClients>();
+    let found = match target.parse().map(ClientId::from_raw) {
+        Ok(id) => clients.clients.get(&id).map(|client| (id, client.name.clone())),
+        Err(_) => clients
+            .clients
+            .iter()
+            .find(|(_, client)| client.name.eq_ignore_ascii_case(target))
+            .map(|(id, client)| (*id, client.name.clone())),
+    };
+    found.ok_or_else(|| format!("no player '{}'", target))
+}
+
+fn disconnect_client(world: &mut World, client_id: ClientId, reason: DisconnectReason) {
+    world.send_event(ToClient::new(client_id, ServerMessage::Rejected(reason)));
+    world.resource_mut::<PendingDisconnects>().schedule(client_id);
+}
+
+fn status(world: &World) -> String {
+    let settings = world.resource::<ServerSettings>();
+    let clients = world.resource::<ConnectedClients>();
+    let mut out = String::new();
+    let _ = writeln!(out, "hostname: {}", settings.name);
+    let _ = writeln!(out, "map:      {} ({})", settings.map, settings.mode);
+    let _ = writeln!(
+        out,
+        "players:  {}/{} ({} held for reconnect), tick {} at {} Hz",
+        clients.clients.len(),
+        settings.max_clients,
+        world.resource::<DisconnectedPlayers>().len(),
+        world.resource::<ServerTick>().0,
+        settings.tickrate
+    );
+    let _ = writeln!(
+        out,
+        "{:>20}  {:<name$}  {:<4}  {:>6}  {:>8}  {:>7}",
+        "id",
+        "name",
+        "team",
+        "ping",
+        "kB/s",
+        "cmd/upd",
+        name = MAX_NAME_LENGTH
+    );
+
+    let mut rows: Vec<(ClientId, String, String)> = clients
+        .clients
+        .iter()
+        .map(|(id, client)| (*id, client.name.clone(), format!("{}/{}", client.rates.cmdrate, client.rates.updaterate)))
+        .collect();
+    rows.sort();
+    for (client_id, name, rates) in rows {
+        let ping = world
+            .resource::<RenetServer>()
+            .network_info(client_id)
+            .map_or("-".to_string(), |info| format!("{:.0}", info.rtt * 1000.));
+        let team = world
+            .resource::<ServerPlayers>()
+            .entities
+            .get(&client_id)
+            .and_then(|entity| world.get::<Team>(*entity))
+            .map_or("-", |team| match team {
+                Team::Terrorists => "T",
+                Team::CounterTerrorists => "CT",
+            });
+        let rate = world
+            .resource::<BandwidthStats>()
+            .clients
+            .get(&client_id)
+            .map_or(0., |bandwidth| bandwidth.bytes_per_second / 1024.);
+        let _ = writeln!(
+            out,
+            "{:>20}  {:<name$}  {:<4}  {:>6}  {:>8.1}  {:>7}",
+            client_id,
+            name,
+            team,
+            ping,
+            rate,
+            rates,
+            name = MAX_NAME_LENGTH
+        );
+    }
+    out
+}
+
+// name, current value
+fn cvars(settings: &ServerSettings) -> Vec<(&'static str, String)> {
+    vec![
+        ("hostname", settings.name.clone()),
+        ("mode", settings.mode.clone()),
+        ("beacon", settings.beacon.to_string()),
+        ("max_rewind", settings.max_rewind.as_millis().to_string()),
+        ("reconnect_grace", settings.reconnect_grace.as_secs().to_string()),
+        ("net_lag", settings.conditioner.latency.as_millis().to_string()),
+        ("net_jitter", settings.conditioner.jitter.as_millis().to_string()),
+        ("net_loss", (settings.conditioner.loss * 100.).to_string()),
+        ("net_dup", (settings.conditioner.duplicate * 100.).to_string()),
+        ("net_reorder", (settings.conditioner.reorder * 100.).to_string()),
+    ]
+}
+
+fn set_cvar(world: &mut World, cvar: &str, value: &str) -> Result<(), String> {
+    fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("invalid value '{}'", value))
+    }
+    let percent = |value: &str| parse::<f64>(value).map(|percent| (percent / 100.).clamp(0., 1.));
+
+    let mut settings = world.resource::<ServerSettings>().clone();
+    match cvar {
+        "hostname" => settings.name = value.to_string(),
+        "mode" => settings.mode = value.to_string(),
+        "beacon" => settings.beacon = parse(value)?,
+        "max_rewind" => settings.max_rewind = Duration::from_millis(parse(value)?),
+        "reconnect_grace" => settings.reconnect_grace = Duration::from_secs(parse(value)?),
+        "net_lag" => settings.conditioner.latency = Duration::from_millis(parse(value)?),
+        "net_jitter" => settings.conditioner.jitter = Duration::from_millis(parse(value)?),
+        "net_loss" => settings.conditioner.loss = percent(value)?,
+        "net_dup" => settings.conditioner.duplicate = percent(value)?,
+        "net_reorder" => settings.conditioner.reorder = percent(value)?,
+        _ => return Err(format!("unknown cvar '{}'", cvar)),
+    }
+    world
+        .resource_mut::<ServerConditioner>()
+        .set_settings(settings.conditioner.clone());
+    world.insert_resource(settings);
+    Ok(())
+}
+
+fn execute(world: &mut World, command: RconCommand) -> Result<String, String> {
+    match command {
+        RconCommand::Status => Ok(status(world)),
+        RconCommand::Kick { target, reason } => {
+            let (client_id, name) = find_client(world, &target)?;
+            disconnect_client(world, client_id, DisconnectReason::Kicked(reason.clone()));
+            world.send_event(SystemChat(format!("{} was kicked ({})", name, reason)));
+            Ok(format!("kicked {} ({})", name, client_id))
+        }
+        RconCommand::Ban { target, ip, minutes, reason } => {
+            let (client_id, name) = find_client(world, &target)?;
+            let expires = minutes.map(|minutes| SystemTime::now() + Duration::from_secs(minutes * 60));
+            let reason = format!("{} {}", name, reason).trim().to_string();
+            let addr = world
+                .resource::<ServerTransport>()
+                .client_addr(client_id)
+                .map(|addr| addr.ip())
+                .filter(|_| ip);
+            let mut bans = world.resource_mut::<BanList>();
+            bans.add(Ban {
+                target: BanTarget::Player(client_id),
+                expires,
+                reason: reason.clone(),
+            });
+            let mut out = match minutes {
+                Some(minutes) => format!("banned {} ({}) for {} minutes", name, client_id, minutes),
+                None => format!("banned {} ({})", name, client_id),
+            };
+            match addr {
+                // would lock out every other client on this machine, the host's own game included
+                Some(addr) if addr.is_loopback() => {
+                    warn!("Not banning {} ({}), it is a loopback address", addr, name);
+                    out.push_str(&format!(", not their address {} since it is loopback", addr));
+                }
+                Some(addr) => {
+                    bans.add(Ban {
+                        target: BanTarget::Ip(addr),
+                        expires,
+                        reason,
+                    });
+                    out.push_str(&format!(" and their address {}", addr));
+                }
+                None => {}
+            }
+            disconnect_client(world, client_id, DisconnectReason::Banned);
+            world.send_event(SystemChat(format!("{} was banned", name)));
+            Ok(out)
+        }
+        RconCommand::Unban(target) => {
+            let target = match (target.parse().map(ClientId::from_raw), target.parse::<IpAddr>()) {
+                (Ok(id), _) => BanTarget::Player(id),
+                (_, Ok(ip)) => BanTarget::Ip(ip),
+                _ => return Err(format!("'{}' is neither a player id nor an address", target)),
+            };
+            if world.resource_mut::<BanList>().remove(target) {
+                Ok(format!("lifted the ban on {}", target))
+            } else {
+                Err(format!("{} isn't banned", target))
+            }
+        }
+        RconCommand::ChangeLevel(map) => {
+            // there is only one level scene so far, the map name is what browsers see
+            world.resource_mut::<ServerSettings>().map = map.clone();
+            world.send_event(SystemChat(format!("Changing level to {}", map)));
+            world.send_event(RestartRound);
+            Ok(format!("map is now {}", map))
+        }
+        RconCommand::Say(text) => {
+            world.send_event(SystemChat(format!("Console: {}", text)));
+            Ok(String::new())
+        }
+        RconCommand::Set { cvar: None, .. } => Ok(cvars(world.resource::<ServerSettings>())
+            .into_iter()
+            .map(|(name, value)| format!("{} = {}\n", name, value))
+            .collect()),
+        RconCommand::Set { cvar: Some(cvar), value } => {
+            if let Some(value) = value {
+                set_cvar(world, &cvar, &value)?;
+                info!("RCON set {} to {}", cvar, value);
+            }
+            cvars(world.resource::<ServerSettings>())
+                .into_iter()
+                .find(|(name, _)| *name == cvar)
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .ok_or_else(|| format!("unknown cvar '{}'", cvar))
+        }
+        RconCommand::RestartRound => {
+            world.send_event(RestartRound);
+            Ok("round restarted".to_string())
+        }
+        RconCommand::Help => Ok(RCON_HELP.to_string()),
+    }
+}
+
+/* ---------------- Server ---------------- */
+
+// Password protected admin console on localhost TCP, see src/bin/rcon.rs for the client.
+pub struct RconPlugin;
+
+impl Plugin for RconPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, open_rcon_listener)
+            .add_systems(Update, poll_rcon.run_if(resource_exists::<RconListener>));
+    }
+}
+
+#[derive(Resource)]
+struct RconListener {
+    listener: TcpListener,
+    connections: Vec<RconConnection>,
+    failures: HashMap<IpAddr, AuthFailures>,
+}
+
+struct RconConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    buffer: Vec<u8>,
+    authenticated: bool,
+    opened: Instant,
+    closed: bool,
+}
+
+impl RconConnection {
+    fn send(&mut self, response: &RconResponse) {
+        if write_frame(&mut self.stream, response).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn deny(&mut self, reason: &str) {
+        self.send(&RconResponse::Denied(reason.to_string()));
+        self.closed = true;
+    }
+}
+
+// wrong passwords from one address
+#[derive(Default)]
+struct AuthFailures {
+    count: u32,
+    window_start: Option<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl AuthFailures {
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| now < until)
+    }
+
+    // true when this failure locks the address out
+    fn record(&mut self, now: Instant) -> bool {
+        if self.window_start.is_none_or(|start| now - start > AUTH_FAILURE_WINDOW) {
+            self.window_start = Some(now);
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count >= MAX_AUTH_FAILURES {
+            self.locked_until = Some(now + AUTH_LOCKOUT);
+            self.count = 0;
+            self.window_start = None;
+            return true;
+        }
+        false
+    }
+}
+
+fn open_rcon_listener(mut commands: Commands, settings: Res<ServerSettings>) {
+    if settings.rcon_password.as_deref().is_none_or(str::is_empty) {
+        info!("RCON disabled, start with --rcon-password to enable it");
+        return;
+    }
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], settings.rcon_port)))
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener));
+    match listener {
+        Ok(listener) => {
+            info!("RCON listening on 127.0.0.1:{}", settings.rcon_port);
+            commands.insert_resource(RconListener {
+                listener,
+                connections: Vec::new(),
+                failures: HashMap::new(),
+            });
+        }
+        Err(err) => warn!("Could not open RCON port {}: {}", settings.rcon_port, err),
+    }
+}
+
+// Exclusive so commands can reach anything on the server.
+fn poll_rcon(world: &mut World) {
+    let Some(mut rcon) = world.remove_resource::<RconListener>() else {
+        return;
+    };
+    let now = Instant::now();
+
+    while let Ok((stream, addr)) = rcon.listener.accept() {
+        if stream.set_nonblocking(true).is_err() {
+            continue;
+        }
+        let mut connection = RconConnection {
+            stream,
+            addr,
+            buffer: Vec::new(),
+            authenticated: false,
+            opened: now,
+            closed: false,
+        };
+        if rcon.failures.get(&addr.ip()).is_some_and(|failures| failures.is_locked(now)) {
+            warn!("RCON connection from {} refused, locked out after failed logins", addr);
+            connection.deny("too many failed logins, try again later");
+            continue;
+        }
+        rcon.connections.push(connection);
+    }
+
+    let password = world.resource::<ServerSettings>().rcon_password.clone().unwrap_or_default();
+    let RconListener { connections, failures, .. } = &mut rcon;
+    for connection in connections.iter_mut() {
+        let mut chunk = [0; 1024];
+        loop {
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => {
+                    connection.closed = true;
+                    break;
+                }
+                Ok(len) => connection.buffer.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    connection.closed = true;
+                    break;
+                }
+            }
+        }
+
+        while !connection.closed {
+            let request = match take_frame::<RconRequest>(&mut connection.buffer) {
+                None => break,
+                Some(Ok(request)) => request,
+                Some(Err(err)) => {
+                    warn!("RCON: bad request from {}: {}", connection.addr, err);
+                    connection.closed = true;
+                    break;
+                }
+            };
+            match request {
+                RconRequest::Auth { password: attempt } if attempt == password => {
+                    info!("RCON: {} authenticated", connection.addr);
+                    connection.authenticated = true;
+                    connection.send(&RconResponse::Authenticated);
+                }
+                RconRequest::Auth { .. } => {
+                    let locked = failures.entry(connection.addr.ip()).or_default().record(now);
+                    warn!(
+                        "RCON: wrong password from {}{}",
+                        connection.addr,
+                        if locked { ", locking the address out" } else { "" }
+                    );
+                    connection.deny("wrong password");
+                }
+                RconRequest::Command(_) if !connection.authenticated => {
+                    warn!("RCON: command from {} before authenticating", connection.addr);
+                    connection.deny("not authenticated");
+                }
+                RconRequest::Command(line) => {
+                    info!("RCON {}: {}", connection.addr, line);
+                    let response = match RconCommand::parse(&line).and_then(|command| execute(world, command)) {
+                        Ok(output) => RconResponse::Output(output),
+                        Err(err) => RconResponse::Error(err),
+                    };
+                    connection.send(&response);
+                }
+            }
+        }
+
+        if !connection.authenticated && !connection.closed && now - connection.opened > AUTH_TIMEOUT {
+            connection.deny("authentication timed out");
+        }
+    }
+    connections.retain(|connection| !connection.closed);
+    failures.retain(|_, failures| {
+        failures.is_locked(now) || failures.window_start.is_some_and(|start| now - start <= AUTH_FAILURE_WINDOW)
+    });
+
+    world.insert_resource(rcon);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::net::protocol::MAX_FRAME_SIZE;
+
+    #[test]
+    fn commands_parse() {
+        assert_eq!(RconCommand::parse(" status "), Ok(RconCommand::Status));
+        assert_eq!(
+            RconCommand::parse("kick Player stop camping"),
+            Ok(RconCommand::Kick {
+                target: "Player".to_string(),
+                reason: "stop camping".to_string(),
+            })
+        );
+        assert_eq!(
+            RconCommand::parse("set max_rewind 150"),
+            Ok(RconCommand::Set {
+                cvar: Some("max_rewind".to_string()),
+                value: Some("150".to_string()),
+            })
+        );
+        assert_eq!(RconCommand::parse("say gl hf"), Ok(RconCommand::Say("gl hf".to_string())));
+        assert_eq!(
+            RconCommand::parse("ban Player 60 wallhack"),
+            Ok(RconCommand::Ban {
+                target: "Player".to_string(),
+                ip: false,
+                minutes: Some(60),
+                reason: "wallhack".to_string(),
+            })
+        );
+        assert_eq!(
+            RconCommand::parse("ban Player griefing spawn"),
+            Ok(RconCommand::Ban {
+                target: "Player".to_string(),
+                ip: false,
+                minutes: None,
+                reason: "griefing spawn".to_string(),
+            })
+        );
+        assert_eq!(
+            RconCommand::parse("ban 42 ip 30 aimbot"),
+            Ok(RconCommand::Ban {
+                target: "42".to_string(),
+                ip: true,
+                minutes: Some(30),
+                reason: "aimbot".to_string(),
+            })
+        );
+        assert_eq!(
+            RconCommand::parse("ban 42 IP"),
+            Ok(RconCommand::Ban {
+                target: "42".to_string(),
+                ip: true,
+                minutes: None,
+                reason: String::new(),
+            })
+        );
+        assert!(RconCommand::parse("kick").is_err());
+        assert!(RconCommand::parse("changelevel").is_err());
+        assert!(RconCommand::parse("rm -rf").is_err());
+    }
+
+    #[test]
+    fn frames_arrive_in_pieces() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &RconRequest::Command("status".to_string())).unwrap();
+        write_frame(&mut bytes, &RconRequest::Auth { password: "hunter2".to_string() }).unwrap();
+
+        let mut buffer = bytes[..3].to_vec();
+        assert!(take_frame::<RconRequest>(&mut buffer).is_none());
+        buffer.extend_from_slice(&bytes[3..]);
+        assert_eq!(
+            take_frame::<RconRequest>(&mut buffer).unwrap().unwrap(),
+            RconRequest::Command("status".to_string())
+        );
+        assert_eq!(
+            take_frame::<RconRequest>(&mut buffer).unwrap().unwrap(),
+            RconRequest::Auth { password: "hunter2".to_string() }
+        );
+        assert!(buffer.is_empty());
+
+        let mut oversized = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes().to_vec();
+        assert!(take_frame::<RconRequest>(&mut oversized).unwrap().is_err());
+    }
+
+    #[test]
+    fn repeated_failures_lock_out() {
+        let start = Instant::now();
+        let mut failures = AuthFailures::default();
+        for _ in 1..MAX_AUTH_FAILURES {
+            assert!(!failures.record(start));
+        }
+        assert!(failures.record(start));
+        assert!(failures.is_locked(start));
+        assert!(!failures.is_locked(start + AUTH_LOCKOUT + Duration::from_secs(1)));
+
+        // failures spread out over more than the window never add up
+        let mut failures = AuthFailures::default();
+        for i in 0..MAX_AUTH_FAILURES * 2 {
+            assert!(!failures.record(start + (AUTH_FAILURE_WINDOW + Duration::from_secs(1)) * i));
+        }
+    }
+}
@@ -0,0 +1,626 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bevy::{app::ScheduleRunnerPlugin, log::LogPlugin, prelude::*, scene::ScenePlugin};
+use bevy_rapier3d::plugin::{NoUserData, PhysicsSet, RapierPhysicsPlugin};
+use bevy_renet::{
+    renet::{
+        transport::{ServerAuthentication, ServerConfig},
+        ClientId, RenetServer, ServerEvent,
+    },
+    RenetReceive, RenetSend, RenetServerPlugin,
+};
+
+use super::{
+    auth::{load_private_key, name_from_user_data, parse_private_key, PrivateKey},
+    bans::BanList,
+    conditioner::{ConditionerSettings, ServerConditioner},
+    discovery::{default_query_port, ServerDiscoveryPlugin},
+    lag_compensation::LagCompensationPlugin,
+    protocol::{
+        check_hello, connection_config, ClientChannel, ClientMessage, DisconnectReason, ServerMessage,
+        PROTOCOL_ID,
+    },
+    rates::{clamp_tickrate, ClientRates, NetRates, RateLimits, DEFAULT_TICKRATE},
+    rcon::RconPlugin,
+    server_chat::ServerChatPlugin,
+    server_combat::ServerCombatPlugin,
+    server_players::{DisconnectedPlayers, ServerPlayersPlugin},
+    transport::{ServerTransport, ServerTransportPlugin},
+};
+use crate::game::{
+    config::args::Args,
+    level::level::LevelPlugin,
+    player::player_movement::PlayerMovementPlugin,
+    weapons::weapons::WeaponsPlugin,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// time given to a rejection message to reach the client before the connection is closed
+const REJECT_GRACE: Duration = Duration::from_millis(500);
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+const SERVER_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Resource, Clone, Debug)]
+pub struct ServerSettings {
+    pub name: String,
+    pub map: String,
+    pub mode: String,
+    pub port: u16,
+    // UDP port answering server browser queries
+    pub query_port: u16,
+    // announce the server on the LAN instead of only answering queries
+    pub beacon: bool,
+    // address clients connect to, must match the one in their connect token
+    pub public_addr: SocketAddr,
+    pub max_clients: usize,
+    // within MIN_TICKRATE..=MAX_TICKRATE
+    pub tickrate: f64,
+    // bounds for the clients' cmdrate and updaterate
+    pub rate_limits: RateLimits,
+    // lag compensation never rewinds further than this
+    pub max_rewind: Duration,
+    // how long the slot and state of a player whose connection timed out are kept for them,
+    // zero turns it off
+    pub reconnect_grace: Duration,
+    pub conditioner: ConditionerSettings,
+    // RCON stays closed without a password
+    pub rcon_password: Option<String>,
+    // TCP, only bound on localhost
+    pub rcon_port: u16,
+    // shared with the auth service, clients then need a connect token from it
+    pub private_key: Option<PrivateKey>,
+    pub ban_file: PathBuf,
+}
+
+impl ServerSettings {
+    pub fn from_args(args: &Args) -> ServerSettings {
+        let port = args.parse_or("--port", 5000);
+        ServerSettings {
+            name: args.value("--server-name").unwrap_or("rust_fps_net server").to_string(),
+            map: args.value("--map").unwrap_or("gridshot").to_string(),
+            mode: args.value("--mode").unwrap_or("Competitive").to_string(),
+            port,
+            query_port: query_port_from_args(args, port),
+            beacon: args.flag("--beacon"),
+            public_addr: args.parse_or("--public-addr", SocketAddr::from(([127, 0, 0, 1], port))),
+            max_clients: args.parse_or("--max-clients", 10),
+            tickrate: clamp_tickrate(args.parse_or("--tickrate", DEFAULT_TICKRATE)),
+            rate_limits: RateLimits::from_args(args),
+            max_rewind: Duration::from_millis(args.parse_or("--max-rewind", 200)),
+            reconnect_grace: Duration::from_secs(args.parse_or("--reconnect-grace", 60)),
+            conditioner: ConditionerSettings::from_args(args),
+            rcon_password: args.value("--rcon-password").map(str::to_string),
+            rcon_port: args.parse_or("--rcon-port", port),
+            private_key: private_key_from_args(args),
+            ban_file: PathBuf::from(args.value("--ban-file").unwrap_or("bans.txt")),
+        }
+    }
+}
+
+// "--query-port", which can't share the game port
+fn query_port_from_args(args: &Args, port: u16) -> u16 {
+    let query_port = args.parse_or("--query-port", default_query_port(port));
+    if query_port == port {
+        panic!("--query-port {} is the game port", query_port);
+    }
+    query_port
+}
+
+// "--private-key <hex>" or "--private-key-file <path>". A bad key is fatal, quietly
+// falling back to an open server would be worse.
+fn private_key_from_args(args: &Args) -> Option<PrivateKey> {
+    if let Some(hex) = args.value("--private-key") {
+        return Some(parse_private_key(hex).unwrap_or_else(|err| panic!("--private-key: {}", err)));
+    }
+    let path = args.value("--private-key-file")?;
+    Some(load_private_key(path.as_ref()).unwrap_or_else(|err| panic!("--private-key-file {}: {}", path, err)))
+}
+
+// simulation tick counter, advanced once per FixedUpdate
+#[derive(Resource, Default)]
+pub struct ServerTick(pub u32);
+
+// Advances ServerTick before the physics step, so the hitboxes recorded after it and the
+// snapshot sent for it carry the same tick.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdvanceTick;
+
+// clients that completed the protocol handshake
+#[derive(Resource, Default)]
+pub struct ConnectedClients {
+    pub clients: HashMap<ClientId, ConnectedClient>,
+}
+
+pub struct ConnectedClient {
+    pub name: String,
+    pub rates: NetRates,
+}
+
+// Bytes handed to renet for each client, before packet headers.
+#[derive(Resource, Default)]
+pub struct BandwidthStats {
+    pub clients: HashMap<ClientId, ClientBandwidth>,
+}
+
+#[derive(Default)]
+pub struct ClientBandwidth {
+    pub total_bytes: u64,
+    pub snapshot_bytes: u64,
+    // averaged over the last BANDWIDTH_WINDOW
+    pub bytes_per_second: f64,
+    window_bytes: u64,
+}
+
+impl BandwidthStats {
+    fn record(&mut self, client_id: ClientId, bytes: usize, snapshot: bool) {
+        let stats = self.clients.entry(client_id).or_default();
+        stats.total_bytes += bytes as u64;
+        stats.window_bytes += bytes as u64;
+        if snapshot {
+            stats.snapshot_bytes += bytes as u64;
+        }
+    }
+}
+
+// Wall time of each server frame, First to Last. A dedicated server runs one tick per frame.
+#[derive(Resource, Default)]
+pub struct TickTimes {
+    started: Option<Instant>,
+    pub last: Duration,
+    // since the last ServerStats went out
+    window: Vec<Duration>,
+}
+
+impl TickTimes {
+    pub fn mean(&self) -> Duration {
+        if self.window.is_empty() {
+            return Duration::ZERO;
+        }
+        self.window.iter().sum::<Duration>() / self.window.len() as u32
+    }
+}
+
+#[derive(Event)]
+pub struct FromClient {
+    pub client_id: ClientId,
+    pub message: ClientMessage,
+}
+
+// sent once a client passed the handshake
+#[derive(Event)]
+pub struct ClientJoined {
+    pub client_id: ClientId,
+}
+
+#[derive(Event)]
+pub struct ToClient {
+    pub target: MessageTarget,
+    pub message: ServerMessage,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageTarget {
+    Client(ClientId),
+    All,
+    AllExcept(ClientId),
+}
+
+impl ToClient {
+    pub fn new(client_id: ClientId, message: ServerMessage) -> ToClient {
+        ToClient {
+            target: MessageTarget::Client(client_id),
+            message,
+        }
+    }
+
+    pub fn all(message: ServerMessage) -> ToClient {
+        ToClient {
+            target: MessageTarget::All,
+            message,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct PendingHandshakes(HashMap<ClientId, Timer>);
+
+#[derive(Resource, Default)]
+pub struct PendingDisconnects(Vec<(ClientId, Timer)>);
+
+impl PendingDisconnects {
+    // Rejection is sent reliably, the connection is closed once it had time to arrive.
+    pub fn schedule(&mut self, client_id: ClientId) {
+        self.0.push((client_id, Timer::new(REJECT_GRACE, TimerMode::Once)));
+    }
+}
+
+pub struct ServerPlugin {
+    pub settings: ServerSettings,
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        let (server, transport) = open_server(&self.settings).expect("failed to open server socket");
+        info!(
+            "Server listening on port {} ({} tick, max {} clients)",
+            self.settings.port, self.settings.tickrate, self.settings.max_clients
+        );
+
+        app.add_plugins((RenetServerPlugin, ServerTransportPlugin))
+            .insert_resource(self.settings.clone())
+            .insert_resource(Time::<Fixed>::from_hz(self.settings.tickrate))
+            .insert_resource(server)
+            .insert_resource(transport)
+            .init_resource::<ServerTick>()
+            .init_resource::<ConnectedClients>()
+            .init_resource::<PendingHandshakes>()
+            .init_resource::<PendingDisconnects>()
+            .init_resource::<BandwidthStats>()
+            .init_resource::<TickTimes>()
+            .insert_resource(load_bans(&self.settings))
+            .insert_resource(ServerConditioner::new(self.settings.conditioner.clone()))
+            .add_event::<FromClient>()
+            .add_event::<ToClient>()
+            .add_event::<ClientJoined>()
+            .add_plugins((
+                ServerPlayersPlugin,
+                LagCompensationPlugin,
+                ServerCombatPlugin,
+                ServerChatPlugin,
+                ServerDiscoveryPlugin,
+                RconPlugin,
+            ))
+            .add_systems(First, start_tick_timer)
+            .add_systems(Last, finish_tick_timer)
+            .configure_sets(FixedUpdate, AdvanceTick.before(PhysicsSet::SyncBackend))
+            .add_systems(FixedUpdate, advance_tick.in_set(AdvanceTick))
+            .add_systems(
+                PreUpdate,
+                (handle_server_events, receive_client_messages)
+                    .chain()
+                    .after(RenetReceive),
+            )
+            .add_systems(
+                Update,
+                (
+                    handle_hello,
+                    handle_rates,
+                    update_pending_connections,
+                    update_bandwidth_stats,
+                    send_server_stats,
+                ),
+            )
+            .add_systems(PostUpdate, send_server_messages.before(RenetSend));
+    }
+}
+
+// Everything the dedicated server runs, without a window, renderer or egui. `run()` loops
+// at the tickrate, the load test steps it with `update()` instead.
+pub fn headless_server_app(settings: ServerSettings) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            1.0 / settings.tickrate,
+        ))),
+        LogPlugin::default(),
+        TransformPlugin,
+        HierarchyPlugin,
+        AssetPlugin::default(),
+        ScenePlugin,
+    ))
+    // rapier's async colliders expect mesh assets to exist even without a renderer
+    .init_asset::<Mesh>()
+    .add_plugins((
+        RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule(),
+        LevelPlugin,
+        PlayerMovementPlugin,
+        WeaponsPlugin,
+        ServerPlugin { settings },
+    ));
+    app
+}
+
+fn load_bans(settings: &ServerSettings) -> BanList {
+    match BanList::load(&settings.ban_file) {
+        Ok(mut bans) => {
+            bans.remove_expired(SystemTime::now());
+            info!("{} bans loaded from {}", bans.bans.len(), settings.ban_file.display());
+            bans
+        }
+        Err(err) => {
+            warn!("Could not read {}, bans won't be saved: {}", settings.ban_file.display(), err);
+            BanList::default()
+        }
+    }
+}
+
+fn open_server(settings: &ServerSettings) -> std::io::Result<(RenetServer, ServerTransport)> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], settings.port)))?;
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let server_config = ServerConfig {
+        current_time,
+        max_clients: settings.max_clients,
+        protocol_id: PROTOCOL_ID,
+        public_addresses: vec![settings.public_addr],
+        authentication: match settings.private_key {
+            Some(private_key) => ServerAuthentication::Secure { private_key },
+            None => ServerAuthentication::Unsecure,
+        },
+    };
+    let transport = ServerTransport::new(server_config, socket)?;
+    let server = RenetServer::new(connection_config());
+
+    Ok((server, transport))
+}
+
+fn advance_tick(mut tick: ResMut<ServerTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+fn start_tick_timer(mut tick_times: ResMut<TickTimes>) {
+    tick_times.started = Some(Instant::now());
+}
+
+fn finish_tick_timer(mut tick_times: ResMut<TickTimes>) {
+    let Some(started) = tick_times.started.take() else {
+        return;
+    };
+    let elapsed = started.elapsed();
+    tick_times.last = elapsed;
+    tick_times.window.push(elapsed);
+}
+
+fn handle_server_events(
+    mut server_events: EventReader<ServerEvent>,
+    transport: Res<ServerTransport>,
+    bans: Res<BanList>,
+    mut to_client: EventWriter<ToClient>,
+    mut pending: ResMut<PendingHandshakes>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut clients: ResMut<ConnectedClients>,
+    mut bandwidth: ResMut<BandwidthStats>,
+) {
+    for event in server_events.read() {
+        match event {
+            ServerEvent::ClientConnected { client_id } => {
+                let addr = transport.client_addr(*client_id);
+                if let Some(ban) = bans.find(*client_id, addr.map(|addr| addr.ip()), SystemTime::now()) {
+                    warn!("Client {} ({:?}) is banned: {}", client_id, addr, ban);
+                    to_client.send(ToClient::new(*client_id, ServerMessage::Rejected(DisconnectReason::Banned)));
+                    pending_disconnects.schedule(*client_id);
+                    continue;
+                }
+                info!("Client {} connected, waiting for hello", client_id);
+                pending
+                    .0
+                    .insert(*client_id, Timer::new(HANDSHAKE_TIMEOUT, TimerMode::Once));
+            }
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                info!("Client {} disconnected: {}", client_id, reason);
+                pending.0.remove(client_id);
+                clients.clients.remove(client_id);
+                bandwidth.clients.remove(client_id);
+            }
+        }
+    }
+}
+
+fn receive_client_messages(
+    mut server: ResMut<RenetServer>,
+    clients: Res<ConnectedClients>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut from_client: EventWriter<FromClient>,
+) {
+    for client_id in server.clients_id() {
+        for channel in [ClientChannel::Input, ClientChannel::Command] {
+            let channel: u8 = channel.into();
+            while let Some(bytes) = server.receive_message(client_id, channel) {
+                let message = match ClientMessage::decode(&bytes) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("Dropping client {}: {}", client_id, err);
+                        pending_disconnects.schedule(client_id);
+                        break;
+                    }
+                };
+                // nothing but the hello is accepted before the handshake is done
+                let handshaked = clients.clients.contains_key(&client_id);
+                if handshaked || matches!(message, ClientMessage::Hello { .. }) {
+                    from_client.send(FromClient { client_id, message });
+                }
+            }
+        }
+    }
+}
+
+fn handle_hello(
+    mut from_client: EventReader<FromClient>,
+    mut to_client: EventWriter<ToClient>,
+    mut joined: EventWriter<ClientJoined>,
+    mut pending: ResMut<PendingHandshakes>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+    mut clients: ResMut<ConnectedClients>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    transport: Res<ServerTransport>,
+    disconnected: Res<DisconnectedPlayers>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Hello { protocol_version, name } = message else {
+            continue;
+        };
+        if pending.0.remove(client_id).is_none() {
+            // duplicate hello
+            continue;
+        }
+        // with connect tokens the name the auth service signed wins over the one in the hello,
+        // unsecure clients leave the user data zeroed
+        let name = transport
+            .user_data(*client_id)
+            .and_then(|data| name_from_user_data(&data))
+            .filter(|signed| !signed.is_empty())
+            .unwrap_or_else(|| name.clone());
+        // slots held for dropped players count as taken for everyone else
+        let verdict = check_hello(*protocol_version, &name).and_then(|()| {
+            let taken = clients.clients.len() + disconnected.len();
+            if !disconnected.contains(*client_id) && taken >= settings.max_clients {
+                return Err(DisconnectReason::ServerFull);
+            }
+            Ok(())
+        });
+        match verdict {
+            Ok(()) => {
+                info!("Client {} joined as '{}'", client_id, name.trim());
+                clients.clients.insert(
+                    *client_id,
+                    ConnectedClient {
+                        name: name.trim().to_string(),
+                        rates: settings.rate_limits.negotiate(settings.tickrate, ClientRates::default()),
+                    },
+                );
+                to_client.send(ToClient::new(
+                    *client_id,
+                    ServerMessage::Welcome {
+                        client_id: client_id.raw(),
+                        tick: tick.0,
+                        tickrate: settings.tickrate,
+                    },
+                ));
+                joined.send(ClientJoined {
+                    client_id: *client_id,
+                });
+            }
+            Err(reason) => {
+                warn!("Rejecting client {}: {}", client_id, reason);
+                to_client.send(ToClient::new(*client_id, ServerMessage::Rejected(reason)));
+                pending_disconnects.schedule(*client_id);
+            }
+        }
+    }
+}
+
+fn handle_rates(
+    mut from_client: EventReader<FromClient>,
+    mut to_client: EventWriter<ToClient>,
+    mut clients: ResMut<ConnectedClients>,
+    settings: Res<ServerSettings>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Rates(requested) = message else {
+            continue;
+        };
+        let Some(client) = clients.clients.get_mut(client_id) else {
+            continue;
+        };
+        client.rates = settings.rate_limits.negotiate(settings.tickrate, *requested);
+        debug!(
+            "Client {} rates: cmdrate {}, updaterate {}",
+            client_id, client.rates.cmdrate, client.rates.updaterate
+        );
+        to_client.send(ToClient::new(*client_id, ServerMessage::Rates(client.rates)));
+    }
+}
+
+fn update_pending_connections(
+    time: Res<Time>,
+    mut server: ResMut<RenetServer>,
+    mut pending: ResMut<PendingHandshakes>,
+    mut pending_disconnects: ResMut<PendingDisconnects>,
+) {
+    pending.0.retain(|client_id, timer| {
+        if timer.tick(time.delta()).finished() {
+            warn!("Client {} never completed the handshake", client_id);
+            server.disconnect(*client_id);
+            return false;
+        }
+        true
+    });
+    pending_disconnects.0.retain_mut(|(client_id, timer)| {
+        if timer.tick(time.delta()).finished() {
+            server.disconnect(*client_id);
+            return false;
+        }
+        true
+    });
+}
+
+fn update_bandwidth_stats(
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    mut bandwidth: ResMut<BandwidthStats>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::new(BANDWIDTH_WINDOW, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    for (client_id, stats) in bandwidth.clients.iter_mut() {
+        stats.bytes_per_second = stats.window_bytes as f64 / BANDWIDTH_WINDOW.as_secs_f64();
+        stats.window_bytes = 0;
+        debug!(
+            "Client {}: {:.1} KiB/s, {} KiB total ({} KiB snapshots)",
+            client_id,
+            stats.bytes_per_second / 1024.,
+            stats.total_bytes / 1024,
+            stats.snapshot_bytes / 1024
+        );
+    }
+}
+
+fn send_server_stats(
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    server: Res<RenetServer>,
+    clients: Res<ConnectedClients>,
+    mut tick_times: ResMut<TickTimes>,
+    mut to_client: EventWriter<ToClient>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::new(SERVER_STATS_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let tick_time = tick_times.mean().as_secs_f32() * 1000.;
+    tick_times.window.clear();
+    for client_id in clients.clients.keys() {
+        let packet_loss = server.network_info(*client_id).map_or(0., |info| info.packet_loss as f32);
+        to_client.send(ToClient::new(*client_id, ServerMessage::ServerStats { tick_time, packet_loss }));
+    }
+}
+
+fn send_server_messages(
+    mut server: ResMut<RenetServer>,
+    clients: Res<ConnectedClients>,
+    mut bandwidth: ResMut<BandwidthStats>,
+    mut to_client: EventReader<ToClient>,
+) {
+    let mut send = |client_id: ClientId, message: &ServerMessage, bytes: Vec<u8>| {
+        bandwidth.record(client_id, bytes.len(), matches!(message, ServerMessage::Snapshot(_)));
+        let channel: u8 = message.channel().into();
+        server.send_message(client_id, channel, bytes);
+    };
+
+    for ToClient { target, message } in to_client.read() {
+        let bytes = message.encode();
+        match *target {
+            MessageTarget::Client(client_id) => {
+                send(client_id, message, bytes);
+            }
+            MessageTarget::All => {
+                for client_id in clients.clients.keys() {
+                    send(*client_id, message, bytes.clone());
+                }
+            }
+            MessageTarget::AllExcept(except) => {
+                for client_id in clients.clients.keys().filter(|id| **id != except) {
+                    send(*client_id, message, bytes.clone());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use super::{
+    protocol::{sanitize_chat, ChatChannel, ChatSender, ClientMessage, ServerMessage},
+    server::{FromClient, ToClient},
+    server_players::{PlayerName, ServerPlayers},
+};
+use crate::game::player::player::{Health, NetworkId, Team};
+
+// messages a player may send back to back before the rate limit kicks in
+const CHAT_BURST: f32 = 4.0;
+// after that, one message per interval
+const CHAT_INTERVAL: Duration = Duration::from_millis(1500);
+
+// Relays player chat to the players allowed to read it. Server messages (joins, leaves,
+// round results) are sent as SystemChat events and take the same path to the clients.
+pub struct ServerChatPlugin;
+
+impl Plugin for ServerChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SystemChat>()
+            .add_systems(Update, (refill_chat_throttles, relay_chat, send_system_chat).chain());
+    }
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct SystemChat(pub String);
+
+// token bucket limiting how fast one player can chat
+#[derive(Component)]
+pub struct ChatThrottle {
+    tokens: f32,
+}
+
+impl Default for ChatThrottle {
+    fn default() -> Self {
+        ChatThrottle { tokens: CHAT_BURST }
+    }
+}
+
+impl ChatThrottle {
+    fn try_take(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Competitive rules: the dead can't talk to the living, team chat stays within the team.
+fn may_read(channel: ChatChannel, sender: (Team, bool), reader: (Team, bool)) -> bool {
+    let (sender_team, sender_dead) = sender;
+    let (reader_team, reader_dead) = reader;
+    if sender_dead && !reader_dead {
+        return false;
+    }
+    channel == ChatChannel::All || sender_team == reader_team
+}
+
+fn refill_chat_throttles(time: Res<Time>, mut throttles: Query<&mut ChatThrottle>) {
+    let refill = time.delta_seconds() / CHAT_INTERVAL.as_secs_f32();
+    for mut throttle in throttles.iter_mut() {
+        throttle.tokens = (throttle.tokens + refill).min(CHAT_BURST);
+    }
+}
+
+fn relay_chat(
+    mut from_client: EventReader<FromClient>,
+    players: Res<ServerPlayers>,
+    mut sender_query: Query<(&PlayerName, &Team, &Health, &mut ChatThrottle)>,
+    reader_query: Query<(&NetworkId, &Team, &Health)>,
+    mut to_client: EventWriter<ToClient>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Chat { channel, text } = message else {
+            continue;
+        };
+        let Some(&entity) = players.entities.get(client_id) else {
+            continue;
+        };
+        let Ok((name, team, health, mut throttle)) = sender_query.get_mut(entity) else {
+            continue;
+        };
+        let Some(text) = sanitize_chat(text) else {
+            continue;
+        };
+        if !throttle.try_take() {
+            to_client.send(ToClient::new(
+                *client_id,
+                ServerMessage::Chat {
+                    from: None,
+                    channel: ChatChannel::All,
+                    text: "You are sending messages too fast".to_string(),
+                },
+            ));
+            continue;
+        }
+
+        info!("[{:?}] {}: {}", channel, name.0, text);
+        let sender = (*team, health.is_dead());
+        let message = ServerMessage::Chat {
+            from: Some(ChatSender {
+                id: client_id.raw(),
+                name: name.0.clone(),
+                dead: health.is_dead(),
+            }),
+            channel: *channel,
+            text,
+        };
+        for (id, team, health) in reader_query.iter() {
+            if may_read(*channel, sender, (*team, health.is_dead())) {
+                to_client.send(ToClient::new(id.client_id(), message.clone()));
+            }
+        }
+    }
+}
+
+fn send_system_chat(mut system_chat: EventReader<SystemChat>, mut to_client: EventWriter<ToClient>) {
+    for SystemChat(text) in system_chat.read() {
+        info!("[Server] {}", text);
+        to_client.send(ToClient::all(ServerMessage::Chat {
+            from: None,
+            channel: ChatChannel::All,
+            text: text.clone(),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    const T: Team = Team::Terrorists;
+    const CT: Team = Team::CounterTerrorists;
+
+    #[test]
+    fn the_dead_only_reach_the_dead() {
+        assert!(!may_read(ChatChannel::All, (T, true), (CT, false)));
+        assert!(!may_read(ChatChannel::All, (T, true), (T, false)));
+        assert!(may_read(ChatChannel::All, (T, true), (CT, true)));
+        // the living are heard by everyone
+        assert!(may_read(ChatChannel::All, (T, false), (CT, true)));
+        assert!(may_read(ChatChannel::All, (T, false), (CT, false)));
+    }
+
+    #[test]
+    fn team_chat_stays_within_the_team() {
+        assert!(may_read(ChatChannel::Team, (T, false), (T, false)));
+        assert!(!may_read(ChatChannel::Team, (T, false), (CT, false)));
+        assert!(!may_read(ChatChannel::Team, (CT, false), (T, true)));
+        assert!(may_read(ChatChannel::Team, (CT, true), (CT, true)));
+        assert!(!may_read(ChatChannel::Team, (CT, true), (T, true)));
+    }
+
+    #[test]
+    fn a_burst_of_four_then_one_per_interval() {
+        let mut world = World::new();
+        world.insert_resource(Time::<()>::default());
+        let player = world.spawn(ChatThrottle::default()).id();
+        let take = |world: &mut World| world.get_mut::<ChatThrottle>(player).unwrap().try_take();
+
+        assert_eq!((0..5).map(|_| take(&mut world)).collect::<Vec<_>>(), [true, true, true, true, false]);
+
+        // half an interval isn't enough for another message, a whole one is
+        world.resource_mut::<Time>().advance_by(CHAT_INTERVAL / 2);
+        world.run_system_once(refill_chat_throttles);
+        assert!(!take(&mut world));
+        world.resource_mut::<Time>().advance_by(CHAT_INTERVAL / 2);
+        world.run_system_once(refill_chat_throttles);
+        assert!(take(&mut world));
+        assert!(!take(&mut world));
+
+        // a long silence never saves up more than a burst
+        world.resource_mut::<Time>().advance_by(CHAT_INTERVAL * 20);
+        world.run_system_once(refill_chat_throttles);
+        assert_eq!((0..5).filter(|_| take(&mut world)).count(), 4);
+    }
+}
@@ -0,0 +1,500 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use super::{
+    lag_compensation::{lag_compensated_ray_cast, HitboxHistory},
+    protocol::{ClientMessage, ServerMessage},
+    server::{FromClient, MessageTarget, ServerSettings, ServerTick, ToClient},
+    server_chat::SystemChat,
+    server_players::{PlayerName, ServerPlayers},
+};
+use crate::game::{
+    player::{
+        camera_controller::view_direction,
+        player::{
+            apply_damage, Health, HitEvent, Hitbox, HitboxPart, Inventory, NetworkId, Player, PlayerDied, Score,
+            WeaponType,
+        },
+    },
+    weapons::{
+        ammo::Reloading,
+        definition::WeaponDefinition,
+        recoil::{spray_tick, Spray},
+        weapons::WeaponStats,
+    },
+};
+
+// how far the client's eye may be from ours, a few ticks of running plus crouch/jump
+const FIRE_POSITION_TOLERANCE: f32 = 1.5;
+// shots may arrive this many seconds ahead of the cyclic rate when the network bunches them up
+const FIRE_RATE_JITTER: f64 = 0.05;
+
+// Fire commands are only claims: the server checks them against the shooter's weapon
+// state and position, then does the ray cast itself.
+pub struct ServerCombatPlugin;
+
+impl Plugin for ServerCombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<HitEvent>()
+            .add_event::<PlayerDied>()
+            .add_systems(
+                Update,
+                (
+                    switch_weapons,
+                    set_fire_mode,
+                    handle_reload,
+                    handle_fire,
+                    apply_hits,
+                    count_kills,
+                    send_ammo,
+                )
+                    .chain(),
+            );
+    }
+}
+
+// rounds are kept in the Inventory
+#[derive(Component, Default)]
+pub struct WeaponState {
+    // server time in seconds when the next shot may come, the client's tick is never trusted for this
+    ready_at: f64,
+    // server time when a full cycle has passed since the last shot, a new burst may start then
+    cycled_at: f64,
+    // the client's burst toggle, weapons without a burst ignore it
+    pub burst: bool,
+    // rounds fired in the current burst
+    burst_fired: u32,
+    spray: Spray,
+    pub rejected_shots: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShotRejection {
+    WrongWeapon { held: WeaponType, claimed: WeaponType },
+    TooFast { early: f64 },
+    Reloading,
+    EmptyMagazine,
+    PositionMismatch { distance: f32 },
+}
+
+impl fmt::Display for ShotRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShotRejection::WrongWeapon { held, claimed } => {
+                write!(f, "fired {:?} while holding {:?}", claimed, held)
+            }
+            ShotRejection::TooFast { early } => {
+                write!(f, "fired {:.0} ms before the weapon had cycled", early * 1000.)
+            }
+            ShotRejection::Reloading => write!(f, "fired while reloading"),
+            ShotRejection::EmptyMagazine => write!(f, "fired with an empty magazine"),
+            ShotRejection::PositionMismatch { distance } => {
+                write!(f, "fired from {:.2} m away from the server position", distance)
+            }
+        }
+    }
+}
+
+impl WeaponState {
+    // a weapon switched to at server time `now` fires once it is drawn
+    pub fn draw(&mut self, now: f64, definition: &WeaponDefinition) {
+        self.ready_at = self.ready_at.max(now + definition.draw_time as f64);
+        self.cycled_at = self.ready_at;
+        self.burst_fired = 0;
+    }
+
+    // Checks a shot received at server time `now` and uses up a round from the inventory if
+    // it is allowed.
+    pub fn try_fire(
+        &mut self,
+        held: WeaponType,
+        claimed: WeaponType,
+        now: f64,
+        definition: &WeaponDefinition,
+        inventory: &mut Inventory,
+    ) -> Result<(), ShotRejection> {
+        if held != claimed {
+            return Err(ShotRejection::WrongWeapon { held, claimed });
+        }
+        if now + FIRE_RATE_JITTER < self.ready_at {
+            return Err(ShotRejection::TooFast { early: self.ready_at - now });
+        }
+        if !inventory.take_round(held, definition) {
+            return Err(ShotRejection::EmptyMagazine);
+        }
+        // a shot let in early is still booked at the time it was due, so the slack never adds up
+        let at = now.max(self.ready_at);
+        let cycle = definition.cycle_time().as_secs_f64();
+        if now + FIRE_RATE_JITTER >= self.cycled_at {
+            self.burst_fired = 0;
+        }
+        self.burst_fired += 1;
+        self.cycled_at = at + cycle;
+        // a burst fires its rounds at the burst rate, then waits out a full cycle like any other shot
+        self.ready_at = match definition.burst {
+            Some(burst) if self.burst && self.burst_fired < burst.rounds => at + 60.0 / burst.fire_rate as f64,
+            _ => at + cycle,
+        };
+        Ok(())
+    }
+}
+
+fn switch_weapons(
+    mut from_client: EventReader<FromClient>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<ServerPlayers>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(&mut Player, &mut WeaponState, &Inventory)>,
+    mut to_client: EventWriter<ToClient>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::SwitchWeapon(weapon) = message else {
+            continue;
+        };
+        let Some(&entity) = players.entities.get(client_id) else {
+            continue;
+        };
+        // a running reload is cancelled by finish_reloads once the weapon changes
+        if let Ok((mut player, mut weapon_state, inventory)) = player_query.get_mut(entity) {
+            if inventory.carries(*weapon) && player.weapon != *weapon {
+                player.weapon = *weapon;
+                weapon_state.draw(tick.0 as f64 / settings.tickrate, weapons.get(*weapon));
+                to_client.send(ToClient {
+                    target: MessageTarget::AllExcept(*client_id),
+                    message: ServerMessage::WeaponSwitched {
+                        id: client_id.raw(),
+                        weapon: *weapon,
+                    },
+                });
+            }
+        }
+    }
+}
+
+fn set_fire_mode(
+    mut from_client: EventReader<FromClient>,
+    players: Res<ServerPlayers>,
+    mut weapon_query: Query<&mut WeaponState>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::FireMode { burst } = message else {
+            continue;
+        };
+        let Some(&entity) = players.entities.get(client_id) else {
+            continue;
+        };
+        if let Ok(mut weapon_state) = weapon_query.get_mut(entity) {
+            weapon_state.burst = *burst;
+            weapon_state.burst_fired = 0;
+        }
+    }
+}
+
+fn handle_reload(
+    mut commands: Commands,
+    mut from_client: EventReader<FromClient>,
+    players: Res<ServerPlayers>,
+    weapons: Res<WeaponStats>,
+    player_query: Query<(&Player, &Inventory, Has<Reloading>)>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Reload = message else {
+            continue;
+        };
+        let Some(&entity) = players.entities.get(client_id) else {
+            continue;
+        };
+        let Ok((player, inventory, reloading)) = player_query.get(entity) else {
+            continue;
+        };
+        let definition = weapons.get(player.weapon);
+        let can_reload = inventory.ammo.get(&player.weapon).is_some_and(|ammo| ammo.can_reload(definition));
+        if !reloading && can_reload {
+            commands.entity(entity).insert(Reloading::new(player.weapon, definition));
+        }
+    }
+}
+
+fn handle_fire(
+    mut from_client: EventReader<FromClient>,
+    tick: Res<ServerTick>,
+    settings: Res<ServerSettings>,
+    players: Res<ServerPlayers>,
+    rapier_context: Res<RapierContext>,
+    weapons: Res<WeaponStats>,
+    mut shooter_query: Query<(&Transform, &Player, &mut WeaponState, &mut Inventory, Has<Reloading>)>,
+    health_query: Query<&Health>,
+    mut hit_events: EventWriter<HitEvent>,
+    history_query: Query<(Entity, &HitboxHistory)>,
+    hitbox_colliders: Query<&Collider, With<Hitbox>>,
+    player_parts: Query<(), Or<(With<Player>, With<Hitbox>)>>,
+) {
+    let max_rewind_ticks = (settings.max_rewind.as_secs_f64() * settings.tickrate).round() as u32;
+
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Fire {
+            tick: seen_tick,
+            origin: client_origin,
+            rotation,
+            weapon,
+            spray,
+        } = message
+        else {
+            continue;
+        };
+        let Some(&shooter) = players.entities.get(client_id) else {
+            continue;
+        };
+        let Ok((transform, player, mut weapon_state, mut inventory, reloading)) = shooter_query.get_mut(shooter) else {
+            continue;
+        };
+        // every shot the client sent moves its spray on, rejected or not, so the counts stay in step
+        let definition = weapons.get(*weapon);
+        let index =
+            weapon_state.spray.next_at_tick(spray_tick(*seen_tick, tick.0), settings.tickrate, &definition.recoil);
+        if *spray as usize != index {
+            warn!("Client {} sent spray index {}, expected {}", client_id, spray, index);
+        }
+        // the dead don't shoot
+        if health_query.get(shooter).is_ok_and(Health::is_dead) {
+            continue;
+        }
+
+        // high ping players don't get to shoot further back than the cap, and nobody shoots from the future
+        let oldest_tick = tick.0.saturating_sub(max_rewind_ticks);
+        if *seen_tick < oldest_tick {
+            debug!(
+                "Client {} fired {} ticks in the past, rewind capped at {}",
+                client_id,
+                tick.0 - seen_tick,
+                max_rewind_ticks
+            );
+        }
+        let rewind_tick = (*seen_tick).clamp(oldest_tick, tick.0);
+
+        let origin = transform.translation + Vec3::Y * player.eye_height;
+        let validation = if !client_origin.is_finite() || !rotation.is_finite() {
+            Err(ShotRejection::PositionMismatch { distance: f32::INFINITY })
+        } else if origin.distance(*client_origin) > FIRE_POSITION_TOLERANCE {
+            Err(ShotRejection::PositionMismatch {
+                distance: origin.distance(*client_origin),
+            })
+        } else if reloading {
+            Err(ShotRejection::Reloading)
+        } else {
+            // the client counts its own shots, it's only told when the counts may have drifted
+            // the claimed tick only picks what to rewind to
+            weapon_state.try_fire(
+                player.weapon,
+                *weapon,
+                tick.0 as f64 / settings.tickrate,
+                weapons.get(player.weapon),
+                inventory.bypass_change_detection(),
+            )
+        };
+        if let Err(rejection) = validation {
+            weapon_state.rejected_shots += 1;
+            warn!(
+                "Rejected shot from client {} ({} rejected so far): {}",
+                client_id, weapon_state.rejected_shots, rejection
+            );
+            if matches!(rejection, ShotRejection::Reloading | ShotRejection::EmptyMagazine) {
+                inventory.set_changed();
+            }
+            continue;
+        }
+
+        // rotation is the aim before recoil, the spray offset is ours to add
+        let hit = lag_compensated_ray_cast(
+            &rapier_context,
+            shooter,
+            origin,
+            view_direction(*rotation + definition.recoil.offset(index)),
+            rewind_tick,
+            &history_query,
+            &hitbox_colliders,
+            &player_parts,
+        );
+        let Some(hit) = hit.filter(|hit| hit.distance <= definition.falloff.range) else {
+            continue;
+        };
+        debug!(
+            "Client {} hit {:?} of {:?} at {:.1} m, rewound {} ticks",
+            client_id,
+            hit.part,
+            hit.victim,
+            hit.distance,
+            tick.0 - rewind_tick
+        );
+        hit_events.send(HitEvent {
+            shooter,
+            victim: hit.victim,
+            part: hit.part,
+            point: hit.point,
+            normal: hit.normal,
+            distance: hit.distance,
+            weapon: *weapon,
+        });
+    }
+}
+
+fn apply_hits(
+    mut hit_events: EventReader<HitEvent>,
+    weapons: Res<WeaponStats>,
+    mut health_query: Query<&mut Health>,
+    mut player_died: EventWriter<PlayerDied>,
+) {
+    for hit in hit_events.read() {
+        let Ok(mut health) = health_query.get_mut(hit.victim) else {
+            continue;
+        };
+        // two shots in one frame can both land on a player the first one killed
+        if health.is_dead() {
+            continue;
+        }
+        let damage = apply_damage(&mut health, weapons.get(hit.weapon), hit.part, hit.distance);
+        debug!(
+            "{:?} took {:.0} damage ({:.0} armor) to the {:?}",
+            hit.victim, damage.health, damage.armor, hit.part
+        );
+        if health.is_dead() {
+            player_died.send(PlayerDied {
+                victim: hit.victim,
+                killer: Some(hit.shooter),
+                weapon: hit.weapon,
+                headshot: hit.part == HitboxPart::Head,
+            });
+        }
+    }
+}
+
+fn count_kills(
+    mut player_died: EventReader<PlayerDied>,
+    mut system_chat: EventWriter<SystemChat>,
+    mut score_query: Query<(&PlayerName, &mut Score)>,
+) {
+    for died in player_died.read() {
+        let Ok((victim, mut score)) = score_query.get_mut(died.victim) else {
+            continue;
+        };
+        score.deaths += 1;
+        let victim = victim.0.clone();
+
+        let headshot = if died.headshot { " (headshot)" } else { "" };
+        // killing yourself doesn't count
+        let killer = died.killer.filter(|killer| *killer != died.victim);
+        match killer.and_then(|killer| score_query.get_mut(killer).ok()) {
+            Some((killer, mut score)) => {
+                score.kills += 1;
+                system_chat.send(SystemChat(format!(
+                    "{} killed {} with {:?}{}",
+                    killer.0, victim, died.weapon, headshot
+                )));
+            }
+            None => {
+                system_chat.send(SystemChat(format!("{} died", victim)));
+            }
+        }
+    }
+}
+
+// Shots are counted on both ends, so this only goes out when the client's count may be
+// off: spawns, finished reloads, round restarts and rejected shots.
+fn send_ammo(mut to_client: EventWriter<ToClient>, player_query: Query<(&NetworkId, &Inventory), Changed<Inventory>>) {
+    for (id, inventory) in player_query.iter() {
+        let ammo = inventory.ammo.iter().map(|(weapon, ammo)| (*weapon, *ammo)).collect();
+        to_client.send(ToClient::new(id.client_id(), ServerMessage::Ammo(ammo)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rifle() -> (WeaponDefinition, Inventory) {
+        let weapons = WeaponStats::default();
+        (weapons.get(WeaponType::Rifle).clone(), Inventory::new(&weapons))
+    }
+
+    #[test]
+    fn back_dated_shots_cant_beat_the_cyclic_rate() {
+        let (rifle, mut inventory) = rifle();
+        let mut state = WeaponState::default();
+        // after a long pause a client sends five shots in one server frame, claiming ticks a
+        // cycle apart; only the time they arrive counts
+        let now = 30.0;
+        let accepted = (0..5)
+            .filter(|_| state.try_fire(WeaponType::Rifle, WeaponType::Rifle, now, &rifle, &mut inventory).is_ok())
+            .count();
+        assert_eq!(accepted, 1);
+        assert!(matches!(
+            state.try_fire(WeaponType::Rifle, WeaponType::Rifle, now + 0.02, &rifle, &mut inventory),
+            Err(ShotRejection::TooFast { .. })
+        ));
+        assert_eq!(inventory.ammo[&WeaponType::Rifle].magazine, 29);
+    }
+
+    #[test]
+    fn jitter_is_allowed_but_never_adds_up() {
+        let (rifle, mut inventory) = rifle();
+        let mut state = WeaponState::default();
+        // 600 rpm, every other shot arrives 40 ms late and the next one catches up
+        let arrivals = [0.0, 0.14, 0.2, 0.34, 0.4, 0.54, 0.6];
+        for now in arrivals {
+            assert_eq!(state.try_fire(WeaponType::Rifle, WeaponType::Rifle, now, &rifle, &mut inventory), Ok(()));
+        }
+        // shots sent every 60 ms: the slack lets a few through early but the rate still holds
+        let mut state = WeaponState::default();
+        let accepted = (0..10)
+            .filter(|shot| {
+                let now = *shot as f64 * 0.06;
+                state.try_fire(WeaponType::Rifle, WeaponType::Rifle, now, &rifle, &mut inventory).is_ok()
+            })
+            .count();
+        assert!(accepted <= 6, "{} shots in 0.6 s", accepted);
+    }
+
+    #[test]
+    fn a_switched_weapon_fires_once_drawn() {
+        let (rifle, mut inventory) = rifle();
+        let mut state = WeaponState::default();
+        state.draw(10.0, &rifle);
+        let early = 10.0 + rifle.draw_time as f64 - 0.1;
+        assert!(matches!(
+            state.try_fire(WeaponType::Rifle, WeaponType::Rifle, early, &rifle, &mut inventory),
+            Err(ShotRejection::TooFast { .. })
+        ));
+        let drawn = 10.0 + rifle.draw_time as f64;
+        assert_eq!(state.try_fire(WeaponType::Rifle, WeaponType::Rifle, drawn, &rifle, &mut inventory), Ok(()));
+    }
+
+    #[test]
+    fn burst_rate_only_lasts_one_burst() {
+        let weapons = WeaponStats::default();
+        let pistol = weapons.get(WeaponType::Pistol).clone();
+        // shots at the burst rate, 1200 rpm for a second
+        let fire = |burst: bool| {
+            let mut inventory = Inventory::new(&weapons);
+            let mut state = WeaponState { burst, ..default() };
+            (0..20)
+                .map(|shot| shot as f64 * 0.05)
+                .filter(|now| state.try_fire(WeaponType::Pistol, WeaponType::Pistol, *now, &pistol, &mut inventory).is_ok())
+                .collect::<Vec<f64>>()
+        };
+
+        // semi-auto is held to the 150 ms cycle whatever the weapon's burst, shots come in up
+        // to the jitter early but are booked on the cycle
+        let semi = fire(false);
+        assert_eq!(semi[..3], [0.0, 0.1, 0.25]);
+        assert!(semi.len() <= 7, "{} shots in a second", semi.len());
+
+        // the first burst goes through whole, then a full 150 ms cycle before the next one
+        let burst = fire(true);
+        assert_eq!(burst[..4], [0.0, 0.05, 0.1, 0.2]);
+        // three rounds per burst plus a cycle is 250 ms, a little more with the jitter slack
+        assert!(burst.len() <= 13, "{} shots in a second", burst.len());
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use bevy_renet::renet::{ClientId, DisconnectReason, RenetServer, ServerEvent};
+
+use super::{
+    lag_compensation::HitboxHistory,
+    protocol::{ClientMessage, InputCommand, PlayerState, ScoreboardEntry, ServerMessage, WorldSnapshot},
+    server::{AdvanceTick, ClientJoined, ConnectedClients, FromClient, ServerSettings, ServerTick, ToClient},
+    server_chat::{ChatThrottle, SystemChat},
+    server_combat::WeaponState,
+    snapshot::{encode_snapshot, QuantizedSnapshot, SnapshotHistory},
+};
+use crate::game::{
+    player::{
+        input::PlayerInput,
+        player::{player_body, spawn_hitboxes, Economy, Health, Inventory, NetworkId, Player, Score, Team},
+        player_movement::update_movement,
+    },
+    weapons::{ammo::Reloading, weapons::WeaponStats},
+};
+
+// commands buffered beyond this (plus one packet's worth) are dropped, keeps a flooding
+// client from running ahead
+const MAX_QUEUED_INPUTS: usize = 16;
+const SCOREBOARD_INTERVAL: Duration = Duration::from_secs(1);
+
+// Spawns a simulated player for every connected client and drives it from their input stream.
+pub struct ServerPlayersPlugin;
+
+impl Plugin for ServerPlayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerPlayers>()
+            .init_resource::<DisconnectedPlayers>()
+            .add_event::<RestartRound>()
+            .add_systems(
+                Update,
+                (
+                    spawn_joined_players,
+                    mark_leaving_players,
+                    despawn_left_players,
+                    expire_reserved_slots,
+                    apply_player_inputs,
+                    restart_round,
+                    send_scoreboard,
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    consume_input.before(update_movement),
+                    send_snapshots.after(AdvanceTick).after(PhysicsSet::Writeback),
+                ),
+            );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ServerPlayers {
+    pub entities: HashMap<ClientId, Entity>,
+}
+
+#[derive(Component)]
+pub struct PlayerName(pub String);
+
+// Players that dropped mid-match, by player id. They get their state back if they
+// reconnect before the timer runs out.
+#[derive(Resource, Default)]
+pub struct DisconnectedPlayers {
+    slots: HashMap<ClientId, ReservedSlot>,
+}
+
+struct ReservedSlot {
+    name: String,
+    team: Team,
+    score: Score,
+    economy: Economy,
+    inventory: Inventory,
+    health: Health,
+    timer: Timer,
+}
+
+impl DisconnectedPlayers {
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn contains(&self, client_id: ClientId) -> bool {
+        self.slots.contains_key(&client_id)
+    }
+}
+
+// everyone back to a spawn point with full health and magazines
+#[derive(Event, Clone, Copy, Debug)]
+pub struct RestartRound;
+
+// Received commands waiting to be simulated, one per tick.
+#[derive(Component, Default)]
+pub struct InputQueue {
+    commands: VecDeque<InputCommand>,
+    last_received: u32,
+    pub last_processed: u32,
+}
+
+impl InputQueue {
+    fn push(&mut self, command: InputCommand, max_queued: usize) {
+        // redundant copies and reordered packets
+        if command.sequence <= self.last_received {
+            return;
+        }
+        self.last_received = command.sequence;
+        self.commands.push_back(command);
+        while self.commands.len() > max_queued {
+            self.commands.pop_front();
+        }
+    }
+}
+
+// Snapshots sent to one client and the newest one it confirmed, deltas are encoded against that.
+#[derive(Component, Default)]
+pub struct SnapshotBaselines {
+    sent: SnapshotHistory,
+    acked: Option<u32>,
+}
+
+fn spawn_position(index: usize) -> Vec3 {
+    Vec3::new(index as f32 * 2.0, 30., 0.)
+}
+
+// newcomers join the smaller team, terrorists on a tie
+fn smaller_team(terrorists: usize, counter_terrorists: usize) -> Team {
+    if counter_terrorists < terrorists {
+        Team::CounterTerrorists
+    } else {
+        Team::Terrorists
+    }
+}
+
+fn spawn_joined_players(
+    mut commands: Commands,
+    mut joined: EventReader<ClientJoined>,
+    clients: Res<ConnectedClients>,
+    mut players: ResMut<ServerPlayers>,
+    mut disconnected: ResMut<DisconnectedPlayers>,
+    mut to_client: EventWriter<ToClient>,
+    mut system_chat: EventWriter<SystemChat>,
+    weapons: Res<WeaponStats>,
+    existing: Query<(&NetworkId, &PlayerName, &Transform, &Player)>,
+    teams: Query<&Team>,
+) {
+    // counted once, players spawned below only exist after the commands are applied;
+    // held slots count, their owners come back to the same team
+    let all_teams: Vec<Team> = teams.iter().copied().chain(disconnected.slots.values().map(|slot| slot.team)).collect();
+    let mut terrorists = all_teams.iter().filter(|team| **team == Team::Terrorists).count();
+    let mut counter_terrorists = all_teams.len() - terrorists;
+
+    for ClientJoined { client_id } in joined.read() {
+        let Some(client) = clients.clients.get(client_id) else {
+            continue;
+        };
+
+        // tell the newcomer about everyone already in the game
+        for (id, name, transform, player) in existing.iter() {
+            to_client.send(ToClient::new(
+                *client_id,
+                ServerMessage::PlayerSpawned {
+                    id: id.0,
+                    name: name.0.clone(),
+                    position: transform.translation,
+                    weapon: player.weapon,
+                },
+            ));
+        }
+
+        let position = spawn_position(players.entities.len());
+        let reserved = disconnected.slots.remove(client_id);
+        let state = match reserved {
+            Some(slot) => {
+                info!("Client {} reconnected as '{}', restoring their state", client_id, client.name);
+                system_chat.send(SystemChat(format!("{} reconnected", client.name)));
+                (slot.team, slot.score, slot.economy, slot.inventory, slot.health)
+            }
+            None => {
+                let team = smaller_team(terrorists, counter_terrorists);
+                match team {
+                    Team::Terrorists => terrorists += 1,
+                    Team::CounterTerrorists => counter_terrorists += 1,
+                }
+                system_chat.send(SystemChat(format!("{} joined the game", client.name)));
+                (team, Score::default(), Economy::default(), Inventory::new(&weapons), Health::default())
+            }
+        };
+        let entity = commands
+            .spawn((
+                Player::default(),
+                PlayerInput::default(),
+                InputQueue::default(),
+                SnapshotBaselines::default(),
+                HitboxHistory::default(),
+                WeaponState::default(),
+                state,
+                ChatThrottle::default(),
+                NetworkId(client_id.raw()),
+                PlayerName(client.name.clone()),
+                player_body(position),
+            ))
+            .with_children(|parent| spawn_hitboxes(parent, None))
+            .id();
+        players.entities.insert(*client_id, entity);
+
+        to_client.send(ToClient::all(ServerMessage::PlayerSpawned {
+            id: client_id.raw(),
+            name: client.name.clone(),
+            position,
+            weapon: Player::default().weapon,
+        }));
+    }
+}
+
+// The client said it is quitting, its slot is freed when the disconnect follows.
+#[derive(Component)]
+struct Leaving;
+
+fn mark_leaving_players(mut commands: Commands, mut from_client: EventReader<FromClient>, players: Res<ServerPlayers>) {
+    for FromClient { client_id, message } in from_client.read() {
+        if !matches!(message, ClientMessage::Leave) {
+            continue;
+        }
+        if let Some(entity) = players.entities.get(client_id) {
+            commands.entity(*entity).insert(Leaving);
+        }
+    }
+}
+
+fn despawn_left_players(
+    mut commands: Commands,
+    mut server_events: EventReader<ServerEvent>,
+    mut players: ResMut<ServerPlayers>,
+    mut disconnected: ResMut<DisconnectedPlayers>,
+    settings: Res<ServerSettings>,
+    mut to_client: EventWriter<ToClient>,
+    mut system_chat: EventWriter<SystemChat>,
+    player_query: Query<(&PlayerName, &Team, &Score, &Economy, &Inventory, &Health, Has<Leaving>)>,
+) {
+    for event in server_events.read() {
+        let ServerEvent::ClientDisconnected { client_id, reason } = event else {
+            continue;
+        };
+        let Some(entity) = players.entities.remove(client_id) else {
+            continue;
+        };
+        if let Ok((name, team, score, economy, inventory, health, leaving)) = player_query.get(entity) {
+            // the slot is held unless the player quit or the server kicked or banned them
+            let dropped = matches!(reason, DisconnectReason::Transport) && !leaving && !settings.reconnect_grace.is_zero();
+            if dropped {
+                system_chat.send(SystemChat(format!("{} disconnected", name.0)));
+                disconnected.slots.insert(
+                    *client_id,
+                    ReservedSlot {
+                        name: name.0.clone(),
+                        team: *team,
+                        score: *score,
+                        economy: economy.clone(),
+                        inventory: inventory.clone(),
+                        health: health.clone(),
+                        timer: Timer::new(settings.reconnect_grace, TimerMode::Once),
+                    },
+                );
+            } else {
+                system_chat.send(SystemChat(format!("{} left the game", name.0)));
+            }
+        }
+        commands.entity(entity).despawn_recursive();
+        to_client.send(ToClient::all(ServerMessage::PlayerDespawned { id: client_id.raw() }));
+    }
+}
+
+fn expire_reserved_slots(
+    time: Res<Time>,
+    mut disconnected: ResMut<DisconnectedPlayers>,
+    mut system_chat: EventWriter<SystemChat>,
+) {
+    disconnected.slots.retain(|_, slot| {
+        if slot.timer.tick(time.delta()).finished() {
+            system_chat.send(SystemChat(format!("{} left the game", slot.name)));
+            return false;
+        }
+        true
+    });
+}
+
+fn restart_round(
+    mut commands: Commands,
+    mut restart: EventReader<RestartRound>,
+    mut system_chat: EventWriter<SystemChat>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(Entity, &mut Transform, &mut Player, &mut Health, &mut Inventory)>,
+) {
+    if restart.read().count() == 0 {
+        return;
+    }
+    for (index, (entity, mut transform, mut player, mut health, mut inventory)) in player_query.iter_mut().enumerate() {
+        transform.translation = spawn_position(index);
+        player.velocity = Vec3::ZERO;
+        *health = Health::default();
+        inventory.refill(&weapons);
+        commands.entity(entity).remove::<Reloading>();
+    }
+    system_chat.send(SystemChat("Round restarted".to_string()));
+}
+
+fn apply_player_inputs(
+    mut from_client: EventReader<FromClient>,
+    players: Res<ServerPlayers>,
+    clients: Res<ConnectedClients>,
+    mut queue_query: Query<(&mut InputQueue, &mut SnapshotBaselines)>,
+) {
+    for FromClient { client_id, message } in from_client.read() {
+        let ClientMessage::Input { commands, last_snapshot } = message else {
+            continue;
+        };
+        let (Some(entity), Some(client)) = (players.entities.get(client_id), clients.clients.get(client_id)) else {
+            continue;
+        };
+        // a low cmdrate sends a whole batch of ticks at once
+        let max_queued = MAX_QUEUED_INPUTS + client.rates.command_interval() as usize;
+        if let Ok((mut queue, mut baselines)) = queue_query.get_mut(*entity) {
+            for command in commands {
+                queue.push(*command, max_queued);
+            }
+            // input packets can arrive out of order, never move the baseline backwards
+            if let Some(tick) = *last_snapshot {
+                if baselines.acked.is_none_or(|acked| tick > acked) {
+                    baselines.acked = Some(tick);
+                }
+            }
+        }
+    }
+}
+
+// When the queue runs dry the previous input is kept, a late packet shouldn't stop the player dead.
+fn consume_input(mut player_query: Query<(&mut PlayerInput, &mut InputQueue)>) {
+    for (mut input, mut queue) in player_query.iter_mut() {
+        if let Some(command) = queue.commands.pop_front() {
+            *input = command.to_input();
+            queue.last_processed = command.sequence;
+        }
+    }
+}
+
+fn send_snapshots(
+    tick: Res<ServerTick>,
+    clients: Res<ConnectedClients>,
+    player_query: Query<(&NetworkId, &Transform, &Player, &PlayerInput)>,
+    mut client_query: Query<(&NetworkId, &InputQueue, &mut SnapshotBaselines)>,
+    mut to_client: EventWriter<ToClient>,
+) {
+    // clients with an updaterate below the tickrate skip some ticks
+    let due = |id: &NetworkId| {
+        clients
+            .clients
+            .get(&id.client_id())
+            .is_some_and(|client| tick.0.is_multiple_of(client.rates.snapshot_interval()))
+    };
+    if !client_query.iter().any(|(id, _, _)| due(id)) {
+        return;
+    }
+
+    let players: Vec<PlayerState> = player_query
+        .iter()
+        .map(|(id, transform, player, input)| PlayerState {
+            id: id.0,
+            position: transform.translation,
+            velocity: player.velocity,
+            rotation: input.rotation,
+            eye_height: player.eye_height,
+        })
+        .collect();
+    let world = QuantizedSnapshot::from_world(&WorldSnapshot {
+        tick: tick.0,
+        ack: 0,
+        players,
+    });
+
+    // same world for everyone, only the acknowledged input and the baseline differ
+    for (id, queue, mut baselines) in client_query.iter_mut().filter(|(id, _, _)| due(id)) {
+        let snapshot = QuantizedSnapshot {
+            ack: queue.last_processed,
+            ..world.clone()
+        };
+        // baseline fell out of the history (or nothing acked yet), send everything
+        let baseline = baselines.acked.and_then(|acked| baselines.sent.get(acked));
+        let bytes = encode_snapshot(&snapshot, baseline);
+        baselines.sent.insert(snapshot);
+
+        to_client.send(ToClient::new(id.client_id(), ServerMessage::Snapshot(bytes)));
+    }
+}
+
+fn send_scoreboard(
+    time: Res<Time>,
+    mut timer: Local<Option<Timer>>,
+    server: Res<RenetServer>,
+    disconnected: Res<DisconnectedPlayers>,
+    player_query: Query<(&NetworkId, &PlayerName, &Team, &Score)>,
+    mut to_client: EventWriter<ToClient>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::new(SCOREBOARD_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let connected = player_query.iter().map(|(id, name, team, score)| ScoreboardEntry {
+        id: id.0,
+        name: name.0.clone(),
+        team: *team,
+        kills: score.kills,
+        deaths: score.deaths,
+        ping: server
+            .network_info(id.client_id())
+            .map_or(0, |info| (info.rtt * 1000.).round() as u32),
+        connected: true,
+    });
+    let dropped = disconnected.slots.iter().map(|(id, slot)| ScoreboardEntry {
+        id: id.raw(),
+        name: slot.name.clone(),
+        team: slot.team,
+        kills: slot.score.kills,
+        deaths: slot.score.deaths,
+        ping: 0,
+        connected: false,
+    });
+    to_client.send(ToClient::all(ServerMessage::Scoreboard(connected.chain(dropped).collect())));
+}
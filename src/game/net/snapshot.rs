@@ -0,0 +1,557 @@
+use std::{collections::VecDeque, fmt};
+
+use bevy::prelude::*;
+
+use super::protocol::{PlayerState, WorldSnapshot};
+
+// Snapshots on the wire: every field quantized to a fixed number of bits, and encoded
+// against the last snapshot the client acknowledged so unchanged players cost a few bits.
+
+const POSITION_RANGE: f32 = 1024.;
+// steps per metre
+const POSITION_PRECISION: f32 = 256.;
+const POSITION_BITS: u32 = 19;
+const POSITION_DELTA_BITS: u32 = 10;
+const VELOCITY_RANGE: f32 = 64.;
+const VELOCITY_PRECISION: f32 = 64.;
+const VELOCITY_BITS: u32 = 13;
+const VELOCITY_DELTA_BITS: u32 = 8;
+const YAW_BITS: u32 = 16;
+const PITCH_BITS: u32 = 14;
+const EYE_HEIGHT_PRECISION: f32 = 128.;
+const EYE_HEIGHT_BITS: u32 = 9;
+const PLAYER_COUNT_BITS: u32 = 8;
+pub const MAX_SNAPSHOT_PLAYERS: usize = (1 << PLAYER_COUNT_BITS) - 1;
+// baselines kept per client, ~1 s at 64 tick
+const SNAPSHOT_HISTORY_LENGTH: usize = 64;
+
+const FIELD_UNCHANGED: u64 = 0;
+const FIELD_DELTA: u64 = 1;
+const FIELD_FULL: u64 = 2;
+
+/* ---------------- Kvantisering ---------------- */
+
+fn quantize(value: f32, range: f32, precision: f32, bits: u32) -> u32 {
+    let max = ((1u64 << bits) - 1) as f32;
+    ((value + range) * precision).round().clamp(0., max) as u32
+}
+
+fn dequantize(value: u32, range: f32, precision: f32) -> f32 {
+    value as f32 / precision - range
+}
+
+fn quantize_yaw(degrees: f32) -> u32 {
+    let steps = (1u64 << YAW_BITS) as f32;
+    ((degrees.rem_euclid(360.) / 360. * steps).round() as u64 % (1u64 << YAW_BITS)) as u32
+}
+
+fn dequantize_yaw(value: u32) -> f32 {
+    value as f32 * 360. / (1u64 << YAW_BITS) as f32
+}
+
+fn quantize_pitch(degrees: f32) -> u32 {
+    let max = ((1u64 << PITCH_BITS) - 1) as f32;
+    ((degrees.clamp(-90., 90.) + 90.) / 180. * max).round() as u32
+}
+
+fn dequantize_pitch(value: u32) -> f32 {
+    value as f32 / ((1u64 << PITCH_BITS) - 1) as f32 * 180. - 90.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantizedPlayer {
+    pub id: u64,
+    pub position: [u32; 3],
+    pub velocity: [u32; 3],
+    pub yaw: u32,
+    pub pitch: u32,
+    pub eye_height: u32,
+}
+
+impl QuantizedPlayer {
+    pub fn from_state(state: &PlayerState) -> QuantizedPlayer {
+        let position = state.position.to_array();
+        let velocity = state.velocity.to_array();
+        QuantizedPlayer {
+            id: state.id,
+            position: position.map(|v| quantize(v, POSITION_RANGE, POSITION_PRECISION, POSITION_BITS)),
+            velocity: velocity.map(|v| quantize(v, VELOCITY_RANGE, VELOCITY_PRECISION, VELOCITY_BITS)),
+            yaw: quantize_yaw(state.rotation.y),
+            pitch: quantize_pitch(state.rotation.x),
+            eye_height: quantize(state.eye_height, 0., EYE_HEIGHT_PRECISION, EYE_HEIGHT_BITS),
+        }
+    }
+
+    pub fn to_state(&self) -> PlayerState {
+        PlayerState {
+            id: self.id,
+            position: Vec3::from_array(
+                self.position.map(|v| dequantize(v, POSITION_RANGE, POSITION_PRECISION)),
+            ),
+            velocity: Vec3::from_array(
+                self.velocity.map(|v| dequantize(v, VELOCITY_RANGE, VELOCITY_PRECISION)),
+            ),
+            rotation: Vec2::new(dequantize_pitch(self.pitch), dequantize_yaw(self.yaw)),
+            eye_height: dequantize(self.eye_height, 0., EYE_HEIGHT_PRECISION),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuantizedSnapshot {
+    pub tick: u32,
+    pub ack: u32,
+    // sorted by id
+    pub players: Vec<QuantizedPlayer>,
+}
+
+impl QuantizedSnapshot {
+    pub fn from_world(snapshot: &WorldSnapshot) -> QuantizedSnapshot {
+        let mut players: Vec<QuantizedPlayer> = snapshot
+            .players
+            .iter()
+            .take(MAX_SNAPSHOT_PLAYERS)
+            .map(QuantizedPlayer::from_state)
+            .collect();
+        players.sort_by_key(|player| player.id);
+        QuantizedSnapshot {
+            tick: snapshot.tick,
+            ack: snapshot.ack,
+            players,
+        }
+    }
+
+    pub fn to_world(&self) -> WorldSnapshot {
+        WorldSnapshot {
+            tick: self.tick,
+            ack: self.ack,
+            players: self.players.iter().map(QuantizedPlayer::to_state).collect(),
+        }
+    }
+}
+
+// Snapshots sent to (or received from) one peer, looked up by tick when used as a baseline.
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<QuantizedSnapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn insert(&mut self, snapshot: QuantizedSnapshot) {
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&QuantizedSnapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn latest_tick(&self) -> Option<u32> {
+        self.snapshots.iter().map(|snapshot| snapshot.tick).max()
+    }
+}
+
+/* ---------------- Bitar ---------------- */
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    Truncated,
+    MissingBaseline(u32),
+    BaselineMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Truncated => write!(f, "snapshot ended early"),
+            SnapshotError::MissingBaseline(tick) => write!(f, "no baseline for tick {}", tick),
+            SnapshotError::BaselineMismatch => write!(f, "player count differs from the baseline"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    // at most 32 bits at a time
+    pub fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32 && value < (1u64 << bits));
+        self.scratch |= (value & ((1u64 << bits) - 1)) << self.scratch_bits;
+        self.scratch_bits += bits;
+        while self.scratch_bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write(value as u64, 1);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write(value & 0xffff_ffff, 32);
+        self.write(value >> 32, 32);
+    }
+
+    pub fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write((value + (1i64 << (bits - 1))) as u64, bits);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    pub fn read(&mut self, bits: u32) -> Result<u64, SnapshotError> {
+        if self.position + bits as usize > self.bytes.len() * 8 {
+            return Err(SnapshotError::Truncated);
+        }
+        let mut value = 0u64;
+        for i in 0..bits as usize {
+            let bit = (self.bytes[self.position / 8] >> (self.position % 8)) & 1;
+            value |= (bit as u64) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read(1)? == 1)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let low = self.read(32)?;
+        let high = self.read(32)?;
+        Ok(low | (high << 32))
+    }
+
+    pub fn read_signed(&mut self, bits: u32) -> Result<i64, SnapshotError> {
+        Ok(self.read(bits)? as i64 - (1i64 << (bits - 1)))
+    }
+}
+
+/* ---------------- Delta-kodning ---------------- */
+
+fn fits_delta(current: &[u32; 3], base: &[u32; 3], bits: u32) -> bool {
+    let limit = 1i64 << (bits - 1);
+    current
+        .iter()
+        .zip(base)
+        .all(|(c, b)| (*c as i64 - *b as i64).abs() < limit)
+}
+
+fn write_vector(writer: &mut BitWriter, current: &[u32; 3], base: &[u32; 3], bits: u32, delta_bits: u32) {
+    if current == base {
+        writer.write(FIELD_UNCHANGED, 2);
+    } else if fits_delta(current, base, delta_bits) {
+        writer.write(FIELD_DELTA, 2);
+        for (c, b) in current.iter().zip(base) {
+            writer.write_signed(*c as i64 - *b as i64, delta_bits);
+        }
+    } else {
+        writer.write(FIELD_FULL, 2);
+        for c in current {
+            writer.write(*c as u64, bits);
+        }
+    }
+}
+
+fn read_vector(
+    reader: &mut BitReader,
+    base: &[u32; 3],
+    bits: u32,
+    delta_bits: u32,
+) -> Result<[u32; 3], SnapshotError> {
+    let mut value = *base;
+    match reader.read(2)? {
+        FIELD_UNCHANGED => {}
+        FIELD_DELTA => {
+            for v in value.iter_mut() {
+                let max = (1i64 << bits) - 1;
+                *v = (*v as i64 + reader.read_signed(delta_bits)?).clamp(0, max) as u32;
+            }
+        }
+        _ => {
+            for v in value.iter_mut() {
+                *v = reader.read(bits)? as u32;
+            }
+        }
+    }
+    Ok(value)
+}
+
+fn write_scalar(writer: &mut BitWriter, current: u32, base: u32, bits: u32) {
+    writer.write_bool(current != base);
+    if current != base {
+        writer.write(current as u64, bits);
+    }
+}
+
+fn read_scalar(reader: &mut BitReader, base: u32, bits: u32) -> Result<u32, SnapshotError> {
+    if reader.read_bool()? {
+        Ok(reader.read(bits)? as u32)
+    } else {
+        Ok(base)
+    }
+}
+
+fn write_player(writer: &mut BitWriter, player: &QuantizedPlayer, base: &QuantizedPlayer) {
+    write_vector(writer, &player.position, &base.position, POSITION_BITS, POSITION_DELTA_BITS);
+    write_vector(writer, &player.velocity, &base.velocity, VELOCITY_BITS, VELOCITY_DELTA_BITS);
+    write_scalar(writer, player.yaw, base.yaw, YAW_BITS);
+    write_scalar(writer, player.pitch, base.pitch, PITCH_BITS);
+    write_scalar(writer, player.eye_height, base.eye_height, EYE_HEIGHT_BITS);
+}
+
+fn read_player(reader: &mut BitReader, base: &QuantizedPlayer) -> Result<QuantizedPlayer, SnapshotError> {
+    Ok(QuantizedPlayer {
+        id: base.id,
+        position: read_vector(reader, &base.position, POSITION_BITS, POSITION_DELTA_BITS)?,
+        velocity: read_vector(reader, &base.velocity, VELOCITY_BITS, VELOCITY_DELTA_BITS)?,
+        yaw: read_scalar(reader, base.yaw, YAW_BITS)?,
+        pitch: read_scalar(reader, base.pitch, PITCH_BITS)?,
+        eye_height: read_scalar(reader, base.eye_height, EYE_HEIGHT_BITS)?,
+    })
+}
+
+// new players are written against an all-zero player, so every changed field goes out in full
+fn empty_player(id: u64) -> QuantizedPlayer {
+    QuantizedPlayer {
+        id,
+        position: [0; 3],
+        velocity: [0; 3],
+        yaw: 0,
+        pitch: 0,
+        eye_height: 0,
+    }
+}
+
+// Layout: tick, ack, optional baseline tick, then one "still here" bit per baseline player
+// followed by its changed fields, then the players that are new since the baseline.
+pub fn encode_snapshot(snapshot: &QuantizedSnapshot, baseline: Option<&QuantizedSnapshot>) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.write(snapshot.tick as u64, 32);
+    writer.write(snapshot.ack as u64, 32);
+    writer.write_bool(baseline.is_some());
+
+    let base_players: &[QuantizedPlayer] = baseline.map_or(&[], |baseline| &baseline.players);
+    if let Some(baseline) = baseline {
+        writer.write(baseline.tick as u64, 32);
+        writer.write(base_players.len() as u64, PLAYER_COUNT_BITS);
+        for base in base_players {
+            let current = snapshot.players.iter().find(|player| player.id == base.id);
+            writer.write_bool(current.is_some());
+            if let Some(current) = current {
+                write_player(&mut writer, current, base);
+            }
+        }
+    }
+
+    let new_players: Vec<&QuantizedPlayer> = snapshot
+        .players
+        .iter()
+        .filter(|player| !base_players.iter().any(|base| base.id == player.id))
+        .collect();
+    writer.write(new_players.len() as u64, PLAYER_COUNT_BITS);
+    for player in new_players {
+        writer.write_u64(player.id);
+        write_player(&mut writer, player, &empty_player(player.id));
+    }
+
+    writer.finish()
+}
+
+pub fn decode_snapshot<'a>(
+    bytes: &[u8],
+    baseline: impl FnOnce(u32) -> Option<&'a QuantizedSnapshot>,
+) -> Result<QuantizedSnapshot, SnapshotError> {
+    let mut reader = BitReader::new(bytes);
+    let tick = reader.read(32)? as u32;
+    let ack = reader.read(32)? as u32;
+    let mut players = Vec::new();
+
+    if reader.read_bool()? {
+        let baseline_tick = reader.read(32)? as u32;
+        let baseline = baseline(baseline_tick).ok_or(SnapshotError::MissingBaseline(baseline_tick))?;
+        let count = reader.read(PLAYER_COUNT_BITS)? as usize;
+        if count != baseline.players.len() {
+            return Err(SnapshotError::BaselineMismatch);
+        }
+        for base in baseline.players.iter() {
+            if reader.read_bool()? {
+                players.push(read_player(&mut reader, base)?);
+            }
+        }
+    }
+
+    let new_count = reader.read(PLAYER_COUNT_BITS)?;
+    for _ in 0..new_count {
+        let id = reader.read_u64()?;
+        players.push(read_player(&mut reader, &empty_player(id))?);
+    }
+    players.sort_by_key(|player| player.id);
+
+    Ok(QuantizedSnapshot { tick, ack, players })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(id: u64, position: Vec3, yaw: f32) -> PlayerState {
+        PlayerState {
+            id,
+            position,
+            velocity: Vec3::new(4.1, -3.2, 0.),
+            rotation: Vec2::new(-12.3, yaw),
+            eye_height: 1.7,
+        }
+    }
+
+    fn quantized(tick: u32, players: Vec<PlayerState>) -> QuantizedSnapshot {
+        QuantizedSnapshot::from_world(&WorldSnapshot { tick, ack: tick, players })
+    }
+
+    fn round_trip(snapshot: &QuantizedSnapshot, baseline: Option<&QuantizedSnapshot>) -> QuantizedSnapshot {
+        let bytes = encode_snapshot(snapshot, baseline);
+        decode_snapshot(&bytes, |tick| baseline.filter(|baseline| baseline.tick == tick)).unwrap()
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        let mut writer = BitWriter::default();
+        writer.write(5, 3);
+        writer.write_bool(true);
+        writer.write(0xdead_beef, 32);
+        writer.write_signed(-200, 10);
+        writer.write_u64(u64::MAX - 7);
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read(3), Ok(5));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read(32), Ok(0xdead_beef));
+        assert_eq!(reader.read_signed(10), Ok(-200));
+        assert_eq!(reader.read_u64(), Ok(u64::MAX - 7));
+    }
+
+    #[test]
+    fn quantization_stays_within_precision() {
+        let original = state(1, Vec3::new(-512.123, 30.5, 999.9), 271.3);
+        let restored = QuantizedPlayer::from_state(&original).to_state();
+        assert!(original.position.distance(restored.position) < 0.005);
+        assert!(original.velocity.distance(restored.velocity) < 0.02);
+        assert!((original.rotation.y - restored.rotation.y).abs() < 0.01);
+        assert!((original.rotation.x - restored.rotation.x).abs() < 0.01);
+        assert!((original.eye_height - restored.eye_height).abs() < 0.005);
+    }
+
+    #[test]
+    fn quantizing_twice_changes_nothing() {
+        let once = QuantizedPlayer::from_state(&state(1, Vec3::new(3.3, 1.1, -7.7), 45.));
+        let twice = QuantizedPlayer::from_state(&once.to_state());
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn full_snapshot_round_trips() {
+        let snapshot = quantized(
+            10,
+            vec![
+                state(7, Vec3::new(1., 2., 3.), 90.),
+                state(3, Vec3::new(-1000., 0., 1000.), 359.9),
+            ],
+        );
+        assert_eq!(round_trip(&snapshot, None), snapshot);
+    }
+
+    #[test]
+    fn delta_against_baseline_reproduces_full_state() {
+        let baseline = quantized(
+            10,
+            vec![
+                state(1, Vec3::new(0., 30., 0.), 0.),
+                state(2, Vec3::new(10., 1., 10.), 180.),
+                state(3, Vec3::new(-5., 1., 2.), 45.),
+            ],
+        );
+        let snapshot = quantized(
+            14,
+            vec![
+                // unchanged
+                state(1, Vec3::new(0., 30., 0.), 0.),
+                // small move and turn
+                state(2, Vec3::new(10.08, 1., 10.02), 182.5),
+                // 3 left, 4 joined
+                state(4, Vec3::new(100., 1., -100.), 270.),
+            ],
+        );
+        let mut teleported = snapshot.clone();
+        teleported.players[1].position = QuantizedPlayer::from_state(&state(2, Vec3::new(-800., 5., 600.), 0.)).position;
+
+        assert_eq!(round_trip(&snapshot, Some(&baseline)), snapshot);
+        assert_eq!(round_trip(&teleported, Some(&baseline)), teleported);
+    }
+
+    #[test]
+    fn delta_is_smaller_than_full_snapshot() {
+        let players: Vec<PlayerState> = (0..20)
+            .map(|id| state(id, Vec3::new(id as f32, 1., 0.), 0.))
+            .collect();
+        let baseline = quantized(1, players.clone());
+        let moved: Vec<PlayerState> = players
+            .iter()
+            .map(|player| PlayerState {
+                position: player.position + Vec3::new(0.07, 0., 0.),
+                ..*player
+            })
+            .collect();
+        let snapshot = quantized(2, moved);
+
+        let full = encode_snapshot(&snapshot, None);
+        let delta = encode_snapshot(&snapshot, Some(&baseline));
+        assert!(delta.len() * 2 < full.len());
+        assert!(encode_snapshot(&baseline, Some(&baseline)).len() * 10 < full.len());
+    }
+
+    #[test]
+    fn missing_baseline_is_an_error() {
+        let baseline = quantized(1, vec![state(1, Vec3::ZERO, 0.)]);
+        let snapshot = quantized(2, vec![state(1, Vec3::ONE, 0.)]);
+        let bytes = encode_snapshot(&snapshot, Some(&baseline));
+        assert_eq!(decode_snapshot(&bytes, |_| None), Err(SnapshotError::MissingBaseline(1)));
+    }
+
+    #[test]
+    fn truncated_snapshot_is_an_error() {
+        let snapshot = quantized(2, vec![state(1, Vec3::ONE, 0.), state(2, Vec3::ZERO, 10.)]);
+        let bytes = encode_snapshot(&snapshot, None);
+        assert_eq!(
+            decode_snapshot(&bytes[..bytes.len() - 3], |_| None),
+            Err(SnapshotError::Truncated)
+        );
+    }
+}
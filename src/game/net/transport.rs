@@ -0,0 +1,563 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_renet::{
+    renet::{transport::NetcodeTransportError, ClientId, RenetClient, RenetServer},
+    RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin,
+};
+use renetcode::{
+    ClientAuthentication, DisconnectReason, NetcodeClient, NetcodeError, NetcodeServer, ServerConfig,
+    ServerResult, NETCODE_MAX_PACKET_BYTES, NETCODE_USER_DATA_BYTES,
+};
+
+use super::{
+    conditioner::{ClientConditioner, ConditionedPacket, ServerConditioner},
+    protocol::ClientMessage,
+};
+
+// renet's netcode transports with the conditioner between netcode and the UDP socket. Lost,
+// duplicated and reordered packets show up in renet's packet loss and get resent on the
+// reliable channel, the way they would on a bad connection.
+
+/* ---------------- Server ---------------- */
+
+#[derive(Resource)]
+pub struct ServerTransport {
+    socket: UdpSocket,
+    netcode: NetcodeServer,
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+    // clients whose disconnect packet came in, renet drops them on the next update so the
+    // messages that arrived just before it (a Leave) are still read
+    left: Vec<u64>,
+}
+
+impl ServerTransport {
+    pub fn new(config: ServerConfig, socket: UdpSocket) -> io::Result<ServerTransport> {
+        socket.set_nonblocking(true)?;
+        Ok(ServerTransport {
+            socket,
+            netcode: NetcodeServer::new(config),
+            buffer: [0; NETCODE_MAX_PACKET_BYTES],
+            left: Vec::new(),
+        })
+    }
+
+    pub fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        self.netcode.user_data(client_id.raw())
+    }
+
+    pub fn client_addr(&self, client_id: ClientId) -> Option<SocketAddr> {
+        self.netcode.client_addr(client_id.raw())
+    }
+
+    pub fn update(
+        &mut self,
+        now: Duration,
+        delta: Duration,
+        server: &mut RenetServer,
+        conditioner: &mut ServerConditioner,
+    ) -> Result<(), NetcodeTransportError> {
+        self.netcode.update(delta);
+        for client_id in self.left.drain(..) {
+            server.remove_connection(ClientId::from_raw(client_id));
+        }
+
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    let bytes = self.buffer[..len].to_vec();
+                    conditioner.receive(now, ConditionedPacket { peer: addr, bytes });
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => break,
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        for mut packet in conditioner.ready_to_receive(now) {
+            match self.netcode.process_packet(packet.peer, &mut packet.bytes) {
+                ServerResult::ClientDisconnected { client_id, .. } => self.left.push(client_id),
+                result => handle_server_result(result, now, server, conditioner),
+            }
+        }
+
+        for client_id in self.netcode.clients_id() {
+            let result = self.netcode.update_client(client_id);
+            handle_server_result(result, now, server, conditioner);
+        }
+        for client_id in server.disconnections_id() {
+            let result = self.netcode.disconnect(client_id.raw());
+            handle_server_result(result, now, server, conditioner);
+        }
+
+        self.flush(now, conditioner);
+        Ok(())
+    }
+
+    pub fn send_packets(&mut self, now: Duration, server: &mut RenetServer, conditioner: &mut ServerConditioner) {
+        for client_id in server.clients_id() {
+            if self.left.contains(&client_id.raw()) {
+                continue;
+            }
+            let Ok(packets) = server.get_packets_to_send(client_id) else {
+                continue;
+            };
+            for packet in packets {
+                match self.netcode.generate_payload_packet(client_id.raw(), &packet) {
+                    Ok((addr, payload)) => {
+                        conditioner.send(now, ConditionedPacket { peer: addr, bytes: payload.to_vec() });
+                    }
+                    Err(err) => {
+                        error!("Failed to encrypt a packet for client {}: {}", client_id, err);
+                        break;
+                    }
+                }
+            }
+        }
+        self.flush(now, conditioner);
+    }
+
+    // the disconnect packets skip the conditioner, the app is about to exit
+    pub fn disconnect_all(&mut self, server: &mut RenetServer) {
+        for client_id in self.netcode.clients_id() {
+            let ServerResult::ClientDisconnected { client_id, addr, payload } = self.netcode.disconnect(client_id)
+            else {
+                continue;
+            };
+            server.remove_connection(ClientId::from_raw(client_id));
+            if let Some(payload) = payload {
+                if let Err(err) = self.socket.send_to(payload, addr) {
+                    error!("Failed to send a packet to {}: {}", addr, err);
+                }
+            }
+        }
+    }
+
+    fn flush(&self, now: Duration, conditioner: &mut ServerConditioner) {
+        for ConditionedPacket { peer, bytes } in conditioner.ready_to_send(now) {
+            if let Err(err) = self.socket.send_to(&bytes, peer) {
+                error!("Failed to send a packet to {}: {}", peer, err);
+            }
+        }
+    }
+}
+
+fn handle_server_result(
+    result: ServerResult,
+    now: Duration,
+    server: &mut RenetServer,
+    conditioner: &mut ServerConditioner,
+) {
+    let mut send = |addr: SocketAddr, payload: &[u8]| {
+        conditioner.send(now, ConditionedPacket { peer: addr, bytes: payload.to_vec() });
+    };
+    match result {
+        ServerResult::None => {}
+        ServerResult::PacketToSend { addr, payload } => send(addr, payload),
+        ServerResult::Payload { client_id, payload } => {
+            let client_id = ClientId::from_raw(client_id);
+            if let Err(err) = server.process_packet_from(payload, client_id) {
+                error!("Failed to process a packet from client {}: {}", client_id, err);
+            }
+        }
+        ServerResult::ClientConnected { client_id, addr, payload, .. } => {
+            server.add_connection(ClientId::from_raw(client_id));
+            send(addr, payload);
+        }
+        ServerResult::ClientDisconnected { client_id, addr, payload } => {
+            server.remove_connection(ClientId::from_raw(client_id));
+            if let Some(payload) = payload {
+                send(addr, payload);
+            }
+        }
+    }
+}
+
+pub struct ServerTransportPlugin;
+
+impl Plugin for ServerTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<NetcodeTransportError>()
+            .add_systems(
+                PreUpdate,
+                update_server_transport
+                    .in_set(RenetReceive)
+                    .run_if(resource_exists::<ServerTransport>)
+                    .run_if(resource_exists::<RenetServer>)
+                    .after(RenetServerPlugin::update_system)
+                    .before(RenetServerPlugin::emit_server_events_system),
+            )
+            .add_systems(
+                PostUpdate,
+                (send_server_packets.in_set(RenetSend), disconnect_clients_on_exit)
+                    .run_if(resource_exists::<ServerTransport>)
+                    .run_if(resource_exists::<RenetServer>),
+            );
+    }
+}
+
+fn update_server_transport(
+    time: Res<Time<Real>>,
+    mut transport: ResMut<ServerTransport>,
+    mut server: ResMut<RenetServer>,
+    mut conditioner: ResMut<ServerConditioner>,
+    mut errors: EventWriter<NetcodeTransportError>,
+) {
+    if let Err(err) = transport.update(time.elapsed(), time.delta(), &mut server, &mut conditioner) {
+        errors.send(err);
+    }
+}
+
+fn send_server_packets(
+    time: Res<Time<Real>>,
+    mut transport: ResMut<ServerTransport>,
+    mut server: ResMut<RenetServer>,
+    mut conditioner: ResMut<ServerConditioner>,
+) {
+    transport.send_packets(time.elapsed(), &mut server, &mut conditioner);
+}
+
+fn disconnect_clients_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<ServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    if !exit.is_empty() {
+        transport.disconnect_all(&mut server);
+    }
+}
+
+/* ---------------- Klient ---------------- */
+
+#[derive(Resource)]
+pub struct ClientTransport {
+    socket: UdpSocket,
+    netcode: NetcodeClient,
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+}
+
+impl ClientTransport {
+    pub fn new(
+        current_time: Duration,
+        authentication: ClientAuthentication,
+        socket: UdpSocket,
+    ) -> Result<ClientTransport, NetcodeError> {
+        socket.set_nonblocking(true)?;
+        Ok(ClientTransport {
+            socket,
+            netcode: NetcodeClient::new(current_time, authentication)?,
+            buffer: [0; NETCODE_MAX_PACKET_BYTES],
+        })
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.netcode.disconnect_reason()
+    }
+
+    // Tells the server the player quit, so it frees their slot instead of holding it for a
+    // reconnect. Like the disconnect packet the Leave skips the conditioner, the connection is
+    // gone before the conditioner would let it out.
+    pub fn leave(&mut self, client: &mut RenetClient) {
+        if self.netcode.is_connected() {
+            let message = ClientMessage::Leave;
+            client.send_message(message.channel(), message.encode());
+            for packet in client.get_packets_to_send() {
+                match self.netcode.generate_payload_packet(&packet) {
+                    Ok((addr, payload)) => {
+                        if let Err(err) = self.socket.send_to(payload, addr) {
+                            error!("Failed to send the leave packet: {}", err);
+                        }
+                    }
+                    Err(err) => error!("Failed to encrypt the leave packet: {}", err),
+                }
+            }
+        }
+        client.disconnect();
+        self.disconnect();
+    }
+
+    // skips the conditioner, the disconnect packet goes out right away
+    pub fn disconnect(&mut self) {
+        if self.netcode.is_disconnected() {
+            return;
+        }
+        match self.netcode.disconnect() {
+            Ok((addr, packet)) => {
+                if let Err(err) = self.socket.send_to(packet, addr) {
+                    error!("Failed to send the disconnect packet: {}", err);
+                }
+            }
+            Err(err) => error!("Failed to generate the disconnect packet: {}", err),
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        now: Duration,
+        delta: Duration,
+        client: &mut RenetClient,
+        conditioner: &mut ClientConditioner,
+    ) -> Result<(), NetcodeTransportError> {
+        if let Some(reason) = self.netcode.disconnect_reason() {
+            client.disconnect_due_to_transport();
+            return Err(NetcodeError::Disconnected(reason).into());
+        }
+        if let Some(reason) = client.disconnect_reason() {
+            let (addr, packet) = self.netcode.disconnect()?;
+            self.socket.send_to(packet, addr)?;
+            return Err(reason.into());
+        }
+        if self.netcode.is_connected() {
+            client.set_connected();
+        } else if self.netcode.is_connecting() {
+            client.set_connecting();
+        }
+
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, addr)) => {
+                    if addr != self.netcode.server_addr() {
+                        debug!("Discarded a packet from {}", addr);
+                        continue;
+                    }
+                    let bytes = self.buffer[..len].to_vec();
+                    conditioner.receive(now, ConditionedPacket { peer: (), bytes });
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        for mut packet in conditioner.ready_to_receive(now) {
+            if let Some(payload) = self.netcode.process_packet(&mut packet.bytes) {
+                client.process_packet(payload);
+            }
+        }
+
+        if let Some((packet, _)) = self.netcode.update(delta) {
+            conditioner.send(now, ConditionedPacket { peer: (), bytes: packet.to_vec() });
+        }
+        self.flush(now, conditioner)
+    }
+
+    pub fn send_packets(
+        &mut self,
+        now: Duration,
+        client: &mut RenetClient,
+        conditioner: &mut ClientConditioner,
+    ) -> Result<(), NetcodeTransportError> {
+        if let Some(reason) = self.netcode.disconnect_reason() {
+            return Err(NetcodeError::Disconnected(reason).into());
+        }
+        for packet in client.get_packets_to_send() {
+            let (_, payload) = self.netcode.generate_payload_packet(&packet)?;
+            conditioner.send(now, ConditionedPacket { peer: (), bytes: payload.to_vec() });
+        }
+        self.flush(now, conditioner)
+    }
+
+    fn flush(&self, now: Duration, conditioner: &mut ClientConditioner) -> Result<(), NetcodeTransportError> {
+        for packet in conditioner.ready_to_send(now) {
+            self.socket.send_to(&packet.bytes, self.netcode.server_addr())?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ClientTransportPlugin;
+
+impl Plugin for ClientTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<NetcodeTransportError>()
+            .add_systems(
+                PreUpdate,
+                update_client_transport
+                    .in_set(RenetReceive)
+                    .run_if(resource_exists::<ClientTransport>)
+                    .run_if(resource_exists::<RenetClient>)
+                    .after(RenetClientPlugin::update_system),
+            )
+            .add_systems(
+                PostUpdate,
+                (send_client_packets.in_set(RenetSend), disconnect_on_exit)
+                    .run_if(resource_exists::<ClientTransport>)
+                    .run_if(resource_exists::<RenetClient>),
+            );
+    }
+}
+
+fn update_client_transport(
+    time: Res<Time<Real>>,
+    mut transport: ResMut<ClientTransport>,
+    mut client: ResMut<RenetClient>,
+    mut conditioner: ResMut<ClientConditioner>,
+    mut errors: EventWriter<NetcodeTransportError>,
+) {
+    if let Err(err) = transport.update(time.elapsed(), time.delta(), &mut client, &mut conditioner) {
+        errors.send(err);
+    }
+}
+
+fn send_client_packets(
+    time: Res<Time<Real>>,
+    mut transport: ResMut<ClientTransport>,
+    mut client: ResMut<RenetClient>,
+    mut conditioner: ResMut<ClientConditioner>,
+    mut errors: EventWriter<NetcodeTransportError>,
+) {
+    if let Err(err) = transport.send_packets(time.elapsed(), &mut client, &mut conditioner) {
+        errors.send(err);
+    }
+}
+
+fn disconnect_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<ClientTransport>,
+    mut client: ResMut<RenetClient>,
+) {
+    if !exit.is_empty() {
+        transport.leave(&mut client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::SystemTime};
+
+    use bevy_renet::renet::ServerEvent;
+    use renetcode::ServerAuthentication;
+
+    use super::*;
+    use crate::game::net::{
+        conditioner::ConditionerSettings,
+        protocol::{connection_config, ClientChannel, PROTOCOL_ID},
+    };
+
+    const DELTA: Duration = Duration::from_millis(10);
+
+    // a client and a server on loopback sockets, each behind its own conditioner
+    struct Loopback {
+        now: Duration,
+        server_transport: ServerTransport,
+        server: RenetServer,
+        server_conditioner: ServerConditioner,
+        client_transport: ClientTransport,
+        client: RenetClient,
+        client_conditioner: ClientConditioner,
+    }
+
+    impl Loopback {
+        fn new(settings: ConditionerSettings) -> Loopback {
+            let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_addr = server_socket.local_addr().unwrap();
+            let config = ServerConfig {
+                current_time,
+                max_clients: 1,
+                protocol_id: PROTOCOL_ID,
+                public_addresses: vec![server_addr],
+                authentication: ServerAuthentication::Unsecure,
+            };
+            let authentication = ClientAuthentication::Unsecure {
+                protocol_id: PROTOCOL_ID,
+                client_id: 7,
+                server_addr,
+                user_data: None,
+            };
+            let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            Loopback {
+                now: Duration::ZERO,
+                server_transport: ServerTransport::new(config, server_socket).unwrap(),
+                server: RenetServer::new(connection_config()),
+                server_conditioner: ServerConditioner::new(settings.clone()),
+                client_transport: ClientTransport::new(current_time, authentication, client_socket).unwrap(),
+                client: RenetClient::new(connection_config()),
+                client_conditioner: ClientConditioner::new(settings),
+            }
+        }
+
+        fn client_frame(&mut self) {
+            self.now += DELTA;
+            self.client.update(DELTA);
+            let Loopback { now, client_transport, client, client_conditioner, .. } = self;
+            client_transport.update(*now, DELTA, client, client_conditioner).unwrap();
+            client_transport.send_packets(*now, client, client_conditioner).unwrap();
+            // real sockets, give the packets a moment to cross loopback
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // returns what arrived on the command channel
+        fn server_frame(&mut self) -> Vec<Vec<u8>> {
+            let Loopback { now, server_transport, server, server_conditioner, .. } = self;
+            server.update(DELTA);
+            server_transport.update(*now, DELTA, server, server_conditioner).unwrap();
+            let mut received = Vec::new();
+            for client_id in server.clients_id() {
+                while let Some(bytes) = server.receive_message(client_id, ClientChannel::Command) {
+                    received.push(bytes.to_vec());
+                }
+            }
+            server_transport.send_packets(*now, server, server_conditioner);
+            thread::sleep(Duration::from_millis(1));
+            received
+        }
+    }
+
+    #[test]
+    fn reliable_messages_arrive_through_a_lossy_conditioner() {
+        let mut loopback = Loopback::new(ConditionerSettings {
+            latency: Duration::from_millis(20),
+            loss: 0.3,
+            duplicate: 0.1,
+            reorder: 0.1,
+            seed: 99,
+            ..default()
+        });
+        let mut sent = 0_u8;
+        let mut received = Vec::new();
+        // two seconds, renet leaves the last 900 ms out of its packet loss
+        for _ in 0..200 {
+            if loopback.client.is_connected() && sent < 20 {
+                loopback.client.send_message(ClientChannel::Command, vec![sent]);
+                sent += 1;
+            }
+            loopback.client_frame();
+            received.extend(loopback.server_frame().concat());
+        }
+
+        assert_eq!(received, (0..20).collect::<Vec<u8>>());
+        // renet saw the drops, that is what the net graph shows
+        assert!(loopback.client.network_info().packet_loss > 0.);
+    }
+
+    #[test]
+    fn the_leave_is_read_before_the_client_is_dropped() {
+        let mut loopback = Loopback::new(ConditionerSettings::default());
+        for _ in 0..100 {
+            if loopback.client.is_connected() {
+                break;
+            }
+            loopback.client_frame();
+            loopback.server_frame();
+        }
+        assert!(loopback.client.is_connected());
+        while loopback.server.get_event().is_some() {}
+
+        loopback.client_transport.leave(&mut loopback.client);
+        thread::sleep(Duration::from_millis(1));
+        let received = loopback.server_frame();
+        assert_eq!(
+            received.iter().map(|bytes| ClientMessage::decode(bytes).unwrap()).collect::<Vec<_>>(),
+            vec![ClientMessage::Leave]
+        );
+        assert!(loopback.server.get_event().is_none());
+
+        loopback.server_frame();
+        assert!(loopback.server.clients_id().is_empty());
+        assert!(matches!(loopback.server.get_event(), Some(ServerEvent::ClientDisconnected { .. })));
+    }
+}
@@ -22,8 +22,17 @@ pub fn update_camera_controller(
                 camera_controller.rotation_lock,
             );
         }
-        let y_quat = Quat::from_axis_angle(Vec3::Y, camera_controller.rotation.y.to_radians());
-        let x_quat = Quat::from_axis_angle(Vec3::X, camera_controller.rotation.x.to_radians());
-        transform.rotation = y_quat * x_quat;
+        transform.rotation = view_rotation(camera_controller.rotation);
     }
+}
+
+// x is pitch and y is yaw in degrees, same as CameraController::rotation
+pub fn view_rotation(rotation: Vec2) -> Quat {
+    let y_quat = Quat::from_axis_angle(Vec3::Y, rotation.y.to_radians());
+    let x_quat = Quat::from_axis_angle(Vec3::X, rotation.x.to_radians());
+    y_quat * x_quat
+}
+
+pub fn view_direction(rotation: Vec2) -> Vec3 {
+    view_rotation(rotation) * Vec3::NEG_Z
 }
\ No newline at end of file
@@ -1,9 +1,13 @@
 use bevy::prelude::*;
 
-#[derive(Resource,Default)]
+// One per player entity. Filled from the keyboard for the local player and from
+// received input commands on the server.
+#[derive(Component, Default, Clone, Copy, Debug, PartialEq)]
 pub struct PlayerInput{
     //x component is forward and y direction is right
     pub movement : Vec2,
     pub jump: bool,
     pub crouch: bool,
-}
\ No newline at end of file
+    //same layout as CameraController::rotation, x is pitch and y is yaw (degrees)
+    pub rotation: Vec2,
+}
@@ -1,25 +1,60 @@
+use std::collections::HashMap;
+
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
+use bevy_renet::renet::{ClientId, RenetClient};
+use serde::{Deserialize, Serialize};
 
-use super::{camera_controller, input::*, player_movement::*, player_shooting::{update_player, TracerSpawnSpot}};
-use crate::game::{math::coordinates::blender_to_world, shooting};
+use super::{
+    camera_controller,
+    input::*,
+    player_movement::*,
+    player_shooting::{
+        pull_trigger, receive_ammo, reload_input, select_weapon, toggle_burst, update_player, TracerSpawnSpot,
+        WeaponFired,
+    },
+};
+use crate::game::{
+    app_state::AppState,
+    net::rates::DEFAULT_TICKRATE,
+    shooting,
+    weapons::{
+        ammo::Ammo,
+        definition::WeaponDefinition,
+        recoil::{recover_view_punch, Spray, ViewPunch},
+        trigger::Trigger,
+        weapons::WeaponStats,
+    },
+};
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(shooting::tracer::TracerPlugin)
-            .init_resource::<PlayerInput>()
+        app.add_plugins((shooting::tracer::TracerPlugin, PlayerMovementPlugin))
+            // until a server says otherwise
+            .insert_resource(Time::<Fixed>::from_hz(DEFAULT_TICKRATE))
+            .add_event::<HitEvent>()
+            .add_event::<WeaponFired>()
             .add_systems(
                 Update,
                 (
                     update_movement_input,
-                    update_player, 
-                    camera_controller::update_camera_controller
+                    (
+                        select_weapon,
+                        toggle_burst,
+                        reload_input,
+                        pull_trigger,
+                        update_player,
+                        recover_view_punch,
+                        camera_controller::update_camera_controller,
+                    )
+                        .chain(),
+                    receive_ammo,
+                    update_camera_height,
                 ),
             )
-            //physics timestep
-            .add_systems(FixedUpdate, update_movement)
-            .add_systems(Startup, init_player);
+            // connected clients get their rig when the server spawns them
+            .add_systems(OnEnter(AppState::InGame), init_player.run_if(not(resource_exists::<RenetClient>)));
     }
 }
 
@@ -34,29 +69,149 @@ pub struct Player {
     pub weapon: WeaponType, 
 }
 
+impl Default for Player {
+    fn default() -> Self {
+        Player {
+            velocity : Vec3::ZERO,
+            gravity : 20.0,
+            base_speed : 5.0,
+            eye_height: 1.7,
+            stand_height: 1.7,
+            crouch_height: 1.2,
+            weapon: WeaponType::Rifle 
+        }
+    }
+}
+
+// the player controlled by this client
 #[derive(Component)]
+pub struct LocalPlayer;
+
+// client id of the owning connection, same on the server and every client
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NetworkId(pub u64);
+
+impl NetworkId {
+    pub fn client_id(&self) -> ClientId {
+        ClientId::from_raw(self.0)
+    }
+}
+
+#[derive(Component, Clone, Debug)]
 pub struct Health {
     pub current: f32,
     pub max: f32,
-    pub armor: bool,
+    // kevlar durability, 0 is no vest
+    pub armor: f32,
     pub helmet: bool,
 }
 
-#[derive(Component)]
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            current: 100.0,
+            max: 100.0,
+            armor: 0.0,
+            helmet: false,
+        }
+    }
+}
+
+impl Health {
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0.0
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Team {
+    Terrorists,
+    CounterTerrorists,
+}
+
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Score {
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+#[derive(Component, Clone, Debug)]
 pub struct Economy {
     pub money: i32,
 }
 
-#[derive(Component)]
+impl Default for Economy {
+    fn default() -> Self {
+        Economy { money: 800 }
+    }
+}
+
+#[derive(Component, Clone, Debug)]
 pub struct Inventory {
     pub primary: Option<WeaponType>,
     pub secondary: Option<WeaponType>,
     pub knife: WeaponType,
     pub grenades: Vec<WeaponType>,
+    // rounds left for every gun carried
+    pub ammo: HashMap<WeaponType, Ammo>,
 }
 
+impl Inventory {
+    // what everyone starts the match with, fully loaded
+    pub fn new(weapons: &WeaponStats) -> Inventory {
+        let mut inventory = Inventory {
+            primary: Some(WeaponType::Rifle),
+            secondary: Some(WeaponType::Pistol),
+            knife: WeaponType::Knife,
+            grenades: Vec::new(),
+            ammo: HashMap::new(),
+        };
+        inventory.refill(weapons);
+        inventory
+    }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub fn carries(&self, weapon: WeaponType) -> bool {
+        self.primary == Some(weapon) || self.secondary == Some(weapon) || self.knife == weapon
+    }
+
+    // 1 primary, 2 secondary, 3 knife, like the number keys
+    pub fn slot(&self, slot: u8) -> Option<WeaponType> {
+        match slot {
+            1 => self.primary,
+            2 => self.secondary,
+            3 => Some(self.knife),
+            _ => None,
+        }
+    }
+
+    pub fn refill(&mut self, weapons: &WeaponStats) {
+        self.ammo = [self.primary, self.secondary]
+            .into_iter()
+            .flatten()
+            .filter_map(|weapon| Some((weapon, Ammo::full(weapons.get(weapon))?)))
+            .collect();
+    }
+
+    // Uses up a round if there is one, melee never runs out.
+    pub fn take_round(&mut self, weapon: WeaponType, definition: &WeaponDefinition) -> bool {
+        if !self.carries(weapon) {
+            return false;
+        }
+        if definition.magazine_size.is_none() {
+            return true;
+        }
+        match self.ammo.get_mut(&weapon) {
+            Some(ammo) if ammo.magazine > 0 => {
+                ammo.magazine -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum WeaponType {
     Knife,
     Pistol,
@@ -64,6 +219,10 @@ pub enum WeaponType {
     Sniper,
 }
 
+impl WeaponType {
+    pub const ALL: [WeaponType; 4] = [WeaponType::Knife, WeaponType::Pistol, WeaponType::Rifle, WeaponType::Sniper];
+}
+
 #[derive(Component)]
 pub struct Hitbox {
     pub part: HitboxPart,
@@ -76,15 +235,52 @@ pub enum HitboxPart {
     Legs,
 }
 
+impl HitboxPart {
+    pub fn damage_multiplier(&self) -> f32 {
+        match self {
+            HitboxPart::Head => 4.0,
+            HitboxPart::Body => 1.0,
+            HitboxPart::Legs => 0.75,
+        }
+    }
+}
+
+// armor loses this many points per point of damage it stops
+const ARMOR_RATIO: f32 = 0.5;
+
+// A shot that landed on a player's hitbox. The server sends it after its own lag compensated
+// check and deals the damage from it; on the client it is only what we saw, for the hit marker.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct HitEvent {
+    pub shooter: Entity,
+    pub victim: Entity,
+    pub part: HitboxPart,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub distance: f32,
+    pub weapon: WeaponType,
+}
+
+// Sent when a hit takes a player's health to zero.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PlayerDied {
+    pub victim: Entity,
+    pub killer: Option<Entity>,
+    pub weapon: WeaponType,
+    pub headshot: bool,
+}
+
+// what one hit took, in health and armor points
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Damage {
+    pub health: f32,
+    pub armor: f32,
+}
+
 // helper
 impl Player {
-    pub fn current_speed(&self, crouching: bool) -> f32 {
-        let weapon_speed = match self.weapon {
-            WeaponType::Knife => 5.0,   // ~250 u/s
-            WeaponType::Pistol => 4.8, // ~240 u/s
-            WeaponType::Rifle => 4.1,  // ~215 u/s
-            WeaponType::Sniper => 3.9, // ~200 u/s
-        };
+    pub fn current_speed(&self, crouching: bool, weapons: &WeaponStats) -> f32 {
+        let weapon_speed = weapons.get(self.weapon).move_speed;
 
         if crouching {
             weapon_speed * 0.55 // crouch sänker farten
@@ -99,13 +295,33 @@ fn init_player(
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    weapons: Res<WeaponStats>,
+    existing: Query<(), With<LocalPlayer>>,
 ) {
+    // resuming from the pause menu enters InGame again
+    if !existing.is_empty() {
+        return;
+    }
+    spawn_local_player(&mut commands, &asset_server, &mut meshes, &mut materials, &weapons, Vec3::new(0., 30., 0.));
+}
+
+// The rig for the player this client controls: camera, viewmodel and input.
+pub fn spawn_local_player(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    weapons: &WeaponStats,
+    position: Vec3,
+) -> Entity {
+    let player = Player::default();
+    let weapon = weapons.get(player.weapon);
     let fov = 103.0_f32.to_radians();
     let camera_entity = commands.spawn((
         Camera3dBundle {
             transform: Transform::from_xyz(0.0, 1.7, 0.0),
             projection: Projection::Perspective(PerspectiveProjection {
-                fov: fov,
+                fov,
                 ..default()
             }),
             ..default()
@@ -115,8 +331,9 @@ fn init_player(
             rotation: Vec2::ZERO,
             rotation_lock: 88.0,
         },
+        ViewPunch::default(),
     )).id();
-    let gun_model = asset_server.load("models/ak.glb#Scene0");
+    let gun_model = asset_server.load(weapon.model.clone());
     let gun_entity = commands.spawn(
         SceneBundle{
             scene : gun_model,
@@ -124,7 +341,7 @@ fn init_player(
             ..Default::default()
         }
     ).id();
-    let spawn_spot = blender_to_world(Vec3::new(0.530462,2.10557,-0.466568));
+    let spawn_spot = weapon.muzzle_offset;
     let tracer_spawn_entity = commands.spawn(
         (
             TransformBundle{
@@ -135,17 +352,26 @@ fn init_player(
         )
     ).id();
     let player_entity = commands.spawn((
-        Player {
-            velocity : Vec3::ZERO,
-            gravity : 20.0,
-            base_speed : 5.0,
-            eye_height: 1.7,
-            stand_height: 1.7,
-            crouch_height: 1.2,
-            weapon: WeaponType::Rifle 
-        },
+        player,
+        Inventory::new(weapons),
+        Trigger::default(),
+        Spray::default(),
+        PlayerInput::default(),
+        LocalPlayer,
+        player_body(position),
+    ))
+    .with_children(|parent| spawn_hitboxes(parent, Some((&mut *meshes, &mut *materials))))
+    .id();
+    commands.entity(camera_entity).push_children(&[tracer_spawn_entity,gun_entity]);
+    commands.entity(player_entity).add_child(camera_entity);
+    player_entity
+}
+
+// physics body shared by every player, local or simulated on the server
+pub fn player_body(position : Vec3) -> impl Bundle {
+    (
         SpatialBundle{
-            transform : Transform::from_translation(Vec3::new(0., 30., 0.)),
+            transform : Transform::from_translation(position),
             ..Default::default()
         },
         Collider::capsule_y(0.8, 0.3),
@@ -155,51 +381,140 @@ fn init_player(
             offset : CharacterLength::Absolute(0.01),
             ..default()
         },
-    ))
-.with_children(|parent| {
-    // Head hitbox (boll)
-    parent.spawn((
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(Sphere { radius: 0.25 })),
-            material: materials.add(Color::srgb(1.0, 0.0, 0.0)), // röd
-            transform: Transform::from_xyz(0.0, 1.8, 0.0),
-            ..default()
-        },
-        Collider::ball(0.25),
-        Hitbox { part: HitboxPart::Head },
-    ));
-
-    // Body hitbox (capsule)
-    parent.spawn((
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(Capsule3d::new(0.3, 0.9))),
-            material: materials.add(Color::srgb(0.0, 1.0, 0.0)), // grön
-            transform: Transform::from_xyz(0.0, 0.9, 0.0),
-            ..default()
-        },
-        Collider::capsule_y(0.9, 0.3),
-        Hitbox { part: HitboxPart::Body },
-    ));
-
-    // Legs hitbox (kub)
-    parent.spawn((
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(Cuboid::new(0.3, 0.5, 0.3))),
-            material: materials.add(Color::srgb(0.0, 0.0, 1.0)), // blå
-            transform: Transform::from_xyz(0.0, 0.3, 0.0),
-            ..default()
+    )
+}
+
+// other players on a client, same shape as player_body but moved by interpolation
+pub fn remote_player_body(position : Vec3) -> impl Bundle {
+    (
+        SpatialBundle{
+            transform : Transform::from_translation(position),
+            ..Default::default()
         },
-        Collider::cuboid(0.3, 0.5, 0.3),
-        Hitbox { part: HitboxPart::Legs },
-    ));
-})
-    .id();
-    commands.entity(camera_entity).push_children(&[tracer_spawn_entity,gun_entity]);
-    commands.entity(player_entity).add_child(camera_entity);
+        Collider::capsule_y(0.8, 0.3),
+        RigidBody::KinematicPositionBased,
+    )
+}
+
+// Head/Body/Legs hitboxes, with colored debug meshes when there is a renderer
+pub fn spawn_hitboxes(
+    parent: &mut ChildBuilder,
+    mut visuals: Option<(&mut Assets<Mesh>, &mut Assets<StandardMaterial>)>,
+) {
+    let hitboxes = [
+        // Head hitbox (boll)
+        (HitboxPart::Head, Collider::ball(0.25), Mesh::from(Sphere { radius: 0.25 }), Color::srgb(1.0, 0.0, 0.0), 1.8),
+        // Body hitbox (capsule)
+        (HitboxPart::Body, Collider::capsule_y(0.9, 0.3), Mesh::from(Capsule3d::new(0.3, 0.9)), Color::srgb(0.0, 1.0, 0.0), 0.9),
+        // Legs hitbox (kub)
+        (HitboxPart::Legs, Collider::cuboid(0.3, 0.5, 0.3), Mesh::from(Cuboid::new(0.3, 0.5, 0.3)), Color::srgb(0.0, 0.0, 1.0), 0.3),
+    ];
+
+    for (part, collider, mesh, color, height) in hitboxes {
+        let mut hitbox = parent.spawn((
+            SpatialBundle::from_transform(Transform::from_xyz(0.0, height, 0.0)),
+            collider,
+            Hitbox { part },
+        ));
+        if let Some((meshes, materials)) = visuals.as_mut() {
+            hitbox.insert((meshes.add(mesh), materials.add(color)));
+        }
+    }
+}
+
+// Kevlar covers the body, the helmet the head (only while the vest has durability left),
+// legs are never protected. Armor stops what the weapon doesn't penetrate and wears down
+// by ARMOR_RATIO of that; once it runs out the rest goes through.
+pub fn apply_damage(health: &mut Health, weapon: &WeaponDefinition, part: HitboxPart, distance: f32) -> Damage {
+    let damage = weapon.damage_at(distance) * part.damage_multiplier();
+    let protected = health.armor > 0.0
+        && match part {
+            HitboxPart::Head => health.helmet,
+            HitboxPart::Body => true,
+            HitboxPart::Legs => false,
+        };
+
+    let mut to_health = damage;
+    let mut to_armor = 0.0;
+    if protected {
+        to_health = damage * weapon.armor_penetration;
+        to_armor = (damage - to_health) * ARMOR_RATIO;
+        if to_armor > health.armor {
+            to_armor = health.armor;
+            to_health = damage - to_armor / ARMOR_RATIO;
+        }
+    }
+
+    let to_health = to_health.min(health.current.max(0.0));
+    health.current -= to_health;
+    health.armor -= to_armor;
+    Damage {
+        health: to_health,
+        armor: to_armor,
+    }
 }
 
-//fn apply_damage(commands: &mut Commands, entity: Entity, base_damage: f32) {
-    // här kan du hämta & mutera Health
-    // t.ex. om armor är aktiv: halvera skadan på body
-    // om helmet + headshot: reducera damage
-//}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn armored(armor: f32, helmet: bool) -> Health {
+        Health {
+            armor,
+            helmet,
+            ..default()
+        }
+    }
+
+    // point blank with the builtin stats
+    fn hit(health: &mut Health, weapon: WeaponType, part: HitboxPart) -> Damage {
+        apply_damage(health, &WeaponDefinition::builtin(weapon), part, 0.0)
+    }
+
+    #[test]
+    fn hitbox_multipliers_without_armor() {
+        let mut health = Health::default();
+        assert_eq!(hit(&mut health, WeaponType::Pistol, HitboxPart::Legs).health, 26.25);
+        assert_eq!(hit(&mut health, WeaponType::Pistol, HitboxPart::Body).health, 35.0);
+        // only what was left is taken
+        let damage = hit(&mut health, WeaponType::Pistol, HitboxPart::Head);
+        assert_eq!(damage.health, 38.75);
+        assert!(health.is_dead());
+    }
+
+    #[test]
+    fn armor_reduces_body_and_helmet_head_damage() {
+        let mut health = armored(100.0, false);
+        let damage = hit(&mut health, WeaponType::Pistol, HitboxPart::Body);
+        assert_eq!(damage, Damage { health: 17.5, armor: 8.75 });
+        assert_eq!(health.armor, 91.25);
+
+        // vest without helmet does nothing for the head, and nothing protects the legs
+        assert_eq!(hit(&mut armored(100.0, false), WeaponType::Rifle, HitboxPart::Head).health, 100.0);
+        assert_eq!(hit(&mut armored(100.0, true), WeaponType::Rifle, HitboxPart::Legs).health, 27.0);
+
+        let mut health = armored(100.0, true);
+        health.current = 200.0;
+        let damage = hit(&mut health, WeaponType::Rifle, HitboxPart::Head);
+        assert!((damage.health - 111.6).abs() < 0.01);
+        assert!((damage.armor - 16.2).abs() < 0.01);
+    }
+
+    #[test]
+    fn worn_out_armor_lets_the_rest_through() {
+        let mut health = armored(2.0, false);
+        let damage = hit(&mut health, WeaponType::Pistol, HitboxPart::Body);
+        // 4 points stopped for 2 armor, 31 through
+        assert_eq!(damage, Damage { health: 31.0, armor: 2.0 });
+        assert_eq!(health.armor, 0.0);
+        assert_eq!(hit(&mut health, WeaponType::Pistol, HitboxPart::Body).health, 35.0);
+    }
+
+    #[test]
+    fn damage_falls_off_with_distance() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        let near = apply_damage(&mut Health::default(), &rifle, HitboxPart::Body, 0.0);
+        let far = apply_damage(&mut Health::default(), &rifle, HitboxPart::Body, 50.0);
+        assert!(far.health < near.health);
+    }
+}
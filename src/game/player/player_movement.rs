@@ -1,12 +1,29 @@
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-use super::{camera_controller::CameraController, input::*, player::Player};
+use super::{camera_controller::CameraController, input::*, player::{LocalPlayer, Player}};
+use crate::game::{net::prediction::PredictionSmoothing, weapons::weapons::WeaponStats};
+
+// Movement simulation shared by the client and the dedicated server.
+pub struct PlayerMovementPlugin;
+
+impl Plugin for PlayerMovementPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            update_movement.before(PhysicsSet::SyncBackend),
+        );
+    }
+}
 
 pub fn update_movement_input(
     keys : Res<ButtonInput<KeyCode>>,
-    mut input : ResMut<PlayerInput>,
+    camera_query : Query<&CameraController>,
+    mut input_query : Query<&mut PlayerInput, With<LocalPlayer>>,
 ){
+    let Ok(mut input) = input_query.get_single_mut() else {
+        return;
+    };
     input.movement = Vec2::ZERO;
 
     if keys.pressed(KeyCode::KeyW){
@@ -24,70 +41,93 @@ pub fn update_movement_input(
 
     input.jump = keys.pressed(KeyCode::Space);
     input.crouch = keys.pressed(KeyCode::ControlLeft) || keys.pressed(KeyCode::ControlRight);
+
+    if let Ok(camera) = camera_query.get_single() {
+        input.rotation = camera.rotation;
+    }
 }
 
 pub fn update_movement(
     time : Res<Time<Fixed>>,
-    input : Res<PlayerInput>,
-    camera_query : Query<&CameraController>,
+    weapons : Res<WeaponStats>,
     mut player_query : Query<(
-        &mut Player, 
+        &mut Player,
+        &PlayerInput,
         &mut KinematicCharacterController,
         Option<&KinematicCharacterControllerOutput>
     )>,
-    mut cam_transforms: Query<&mut Transform, With<Camera>>,
 ){
-    let camera = camera_query.get_single().unwrap();
-
-    for(mut player,mut controller,controller_output) in player_query.iter_mut(){
-        if let Some(output) = controller_output{
-            if output.grounded{
-                player.velocity = Vec3::ZERO;
-            }
-            if input.jump && output.grounded {
-                player.velocity.y = 8.0;
-            } 
-        }
-        let camera_rotation_converted = -camera.rotation.y.to_radians() - 90.0_f32.to_radians();
+    let delta = time.timestep().as_secs_f32();
 
-        let forward = Vec2::new(
-            f32::cos(camera_rotation_converted),
-            f32::sin(camera_rotation_converted)
-        );
+    for(mut player,input,mut controller,controller_output) in player_query.iter_mut(){
+        let grounded = controller_output.is_some_and(|output| output.grounded);
 
-        let right = Vec2::new(-forward.y,forward.x);
+        // collider offset (för att kännas lägre)
+        controller.offset = controller_offset(input.crouch);
 
-        if let Some(movement_direction) = (forward*input.movement.x + right*input.movement.y).try_normalize(){
-            let speed = player.current_speed(input.crouch);
-            player.velocity.x = movement_direction.x*speed;
-            player.velocity.z = movement_direction.y*speed;
-        }
+        // Flytta spelaren
+        controller.translation = Some(simulate_movement(&mut player, input, grounded, delta, &weapons));
+    }
+}
 
-        // Gravitation
-        player.velocity.y -= player.gravity*time.timestep().as_secs_f32();
-        
-        let target_height = if input.crouch {
-            player.crouch_height
-        } else {
-            player.stand_height
-        };
-
-        // smooth transition (lerp)
-        player.eye_height = player.eye_height + (target_height - player.eye_height) * 10.0 * time.timestep().as_secs_f32();
-
-        // flytta kameran till rätt höjd
-        if let Ok(mut cam_transform) = cam_transforms.get_single_mut() {
-            cam_transform.translation.y = player.eye_height;
+// One tick of movement for a single player. Returns the translation to hand to the
+// character controller.
+pub fn simulate_movement(player : &mut Player, input : &PlayerInput, grounded : bool, delta : f32, weapons : &WeaponStats) -> Vec3 {
+    if grounded{
+        player.velocity = Vec3::ZERO;
+        if input.jump {
+            player.velocity.y = 8.0;
         }
+    }
 
-        // collider offset (för att kännas lägre)
-        controller.offset = if input.crouch {
-            CharacterLength::Absolute(0.5)
-        } else {
-            CharacterLength::Absolute(0.01)
-        };
+    let camera_rotation_converted = -input.rotation.y.to_radians() - 90.0_f32.to_radians();
 
-        // Flytta spelaren
-        controller.translation = Some(player.velocity*time.timestep().as_secs_f32());
+    let forward = Vec2::new(
+        f32::cos(camera_rotation_converted),
+        f32::sin(camera_rotation_converted)
+    );
+
+    let right = Vec2::new(-forward.y,forward.x);
+
+    if let Some(movement_direction) = (forward*input.movement.x + right*input.movement.y).try_normalize(){
+        let speed = player.current_speed(input.crouch, weapons);
+        player.velocity.x = movement_direction.x*speed;
+        player.velocity.z = movement_direction.y*speed;
+    }
+
+    // Gravitation
+    player.velocity.y -= player.gravity*delta;
+
+    let target_height = if input.crouch {
+        player.crouch_height
+    } else {
+        player.stand_height
+    };
+
+    // smooth transition (lerp)
+    player.eye_height = player.eye_height + (target_height - player.eye_height) * 10.0 * delta;
+
+    player.velocity*delta
+}
+
+pub fn controller_offset(crouch : bool) -> CharacterLength {
+    if crouch {
+        CharacterLength::Absolute(0.5)
+    } else {
+        CharacterLength::Absolute(0.01)
+    }
+}
+
+// flytta kameran till rätt höjd, plus whatever is left of a smoothed prediction correction
+pub fn update_camera_height(
+    player_query : Query<(&Player, Option<&PredictionSmoothing>), With<LocalPlayer>>,
+    mut cam_transforms: Query<&mut Transform, With<CameraController>>,
+){
+    let Ok((player, smoothing)) = player_query.get_single() else {
+        return;
+    };
+    if let Ok(mut cam_transform) = cam_transforms.get_single_mut() {
+        let offset = smoothing.map_or(Vec3::ZERO, |smoothing| smoothing.offset);
+        cam_transform.translation = Vec3::new(0.0, player.eye_height, 0.0) + offset;
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,24 @@
-use bevy::{prelude::*, window::PrimaryWindow};
+use bevy::{ecs::system::SystemParam, prelude::*};
 use bevy_rapier3d::{plugin::RapierContext, prelude::*};
 
-use super::{camera_controller::CameraController, player::Player};
+use super::{
+    camera_controller::{view_direction, CameraController},
+    player::{HitEvent, Hitbox, HitboxPart, Inventory, LocalPlayer, Player, WeaponType},
+};
 use crate::game::{
     level::targets::{DeadTarget, Target},
+    net::{
+        client::{FromServer, LocalClientId, ToServer},
+        interpolation::{InterpolationClock, RemotePlayer},
+        protocol::{ClientMessage, ServerMessage},
+    },
     shooting,
+    weapons::{
+        ammo::{DryFire, Reloading},
+        recoil::{Spray, ViewPunch},
+        trigger::Trigger,
+        weapons::WeaponStats,
+    },
 };
 
 #[derive(Component)]
@@ -12,46 +26,267 @@ pub struct Shootable;
 
 #[derive(Component)]
 pub struct TracerSpawnSpot;
-pub fn update_player(
+
+// A round left the barrel, sent by pull_trigger once ammo and reload allow it.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct WeaponFired {
+    pub weapon: WeaponType,
+    // seconds ago, when several rounds were due during one frame
+    pub ago: f32,
+}
+
+// Finds the player a collider belongs to.
+#[derive(SystemParam)]
+pub struct HitboxOwners<'w, 's> {
+    hitboxes: Query<'w, 's, &'static Hitbox>,
+    parents: Query<'w, 's, &'static Parent>,
+    players: Query<'w, 's, (), Or<(With<Player>, With<RemotePlayer>)>>,
+}
+
+impl HitboxOwners<'_, '_> {
+    // hitboxes are children of the player, walk up until we find it
+    pub fn owner(&self, entity: Entity) -> Option<Entity> {
+        let mut current = entity;
+        loop {
+            if self.players.contains(current) {
+                return Some(current);
+            }
+            current = self.parents.get(current).ok()?.get();
+        }
+    }
+
+    // the hit part and its player, None for anything that isn't a hitbox
+    pub fn resolve(&self, entity: Entity) -> Option<(Entity, HitboxPart)> {
+        let hitbox = self.hitboxes.get(entity).ok()?;
+        Some((self.owner(entity)?, hitbox.part))
+    }
+
+    // player bodies are only for movement, shots go through them to the hitboxes
+    fn is_player_body(&self, entity: Entity) -> bool {
+        self.players.contains(entity)
+    }
+}
+
+// 1 primary, 2 secondary, 3 knife
+pub fn select_weapon(
+    keys: Res<ButtonInput<KeyCode>>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(&mut Player, &Inventory, &mut Trigger), With<LocalPlayer>>,
+    mut to_server: EventWriter<ToServer>,
+) {
+    let Ok((mut player, inventory, mut trigger)) = player_query.get_single_mut() else {
+        return;
+    };
+    let slot = [(KeyCode::Digit1, 1), (KeyCode::Digit2, 2), (KeyCode::Digit3, 3)]
+        .into_iter()
+        .find_map(|(key, slot)| keys.just_pressed(key).then_some(slot));
+    let Some(weapon) = slot.and_then(|slot| inventory.slot(slot)) else {
+        return;
+    };
+    // switching away cancels a reload, see finish_reloads
+    if weapon != player.weapon {
+        player.weapon = weapon;
+        trigger.draw(weapons.get(weapon));
+        to_server.send(ToServer(ClientMessage::SwitchWeapon(weapon)));
+    }
+}
+
+// B, for weapons that have a burst
+pub fn toggle_burst(
+    keys: Res<ButtonInput<KeyCode>>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(&Player, &mut Trigger), With<LocalPlayer>>,
+    mut to_server: EventWriter<ToServer>,
+) {
+    if !keys.just_pressed(KeyCode::KeyB) {
+        return;
+    }
+    let Ok((player, mut trigger)) = player_query.get_single_mut() else {
+        return;
+    };
+    let definition = weapons.get(player.weapon);
+    if definition.burst.is_some() {
+        trigger.burst = !trigger.burst;
+        trigger.interrupt();
+        // the server holds shots to the burst cadence only while it is on
+        to_server.send(ToServer(ClientMessage::FireMode { burst: trigger.burst }));
+        info!("{} fire mode: {:?}", definition.name, trigger.mode(definition));
+    }
+}
+
+pub fn reload_input(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut commands: Commands,
+    weapons: Res<WeaponStats>,
+    player_query: Query<(Entity, &Player, &Inventory, Has<Reloading>), With<LocalPlayer>>,
+    mut to_server: EventWriter<ToServer>,
+) {
+    if !keys.just_pressed(KeyCode::KeyR) {
+        return;
+    }
+    if let Ok((entity, player, inventory, reloading)) = player_query.get_single() {
+        if !reloading {
+            start_reload(&mut commands, entity, player.weapon, inventory, &weapons, &mut to_server);
+        }
+    }
+}
+
+// Turns the mouse button into rounds at the weapon's cyclic rate and fire mode. An empty
+// magazine flashes the ammo counter and reloads by itself when there is reserve ammo left.
+pub fn pull_trigger(
+    time: Res<Time>,
     mouse_input: Res<ButtonInput<MouseButton>>,
     mut commands: Commands,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(Entity, &Player, &mut Inventory, &mut Trigger, Has<Reloading>), With<LocalPlayer>>,
+    mut to_server: EventWriter<ToServer>,
+    mut dry_fire: EventWriter<DryFire>,
+    mut fired: EventWriter<WeaponFired>,
+) {
+    let Ok((entity, player, mut inventory, mut trigger, reloading)) = player_query.get_single_mut() else {
+        return;
+    };
+    let weapon = player.weapon;
+    let definition = weapons.get(weapon);
+    let pressed = mouse_input.just_pressed(MouseButton::Left);
+    let held = mouse_input.pressed(MouseButton::Left);
+    if reloading {
+        trigger.interrupt();
+        trigger.update(time.delta_seconds(), false, false, definition);
+        return;
+    }
+    let empty = definition.magazine_size.is_some() && inventory.ammo.get(&weapon).is_none_or(|ammo| ammo.magazine == 0);
+    if empty {
+        trigger.interrupt();
+        if pressed {
+            dry_fire.send(DryFire { weapon });
+            start_reload(&mut commands, entity, weapon, &inventory, &weapons, &mut to_server);
+        }
+        return;
+    }
+
+    for ago in trigger.update(time.delta_seconds(), pressed, held, definition) {
+        if !inventory.take_round(weapon, definition) {
+            break;
+        }
+        fired.send(WeaponFired { weapon, ago });
+    }
+    if inventory.ammo.get(&weapon).is_some_and(|ammo| ammo.magazine == 0) {
+        trigger.interrupt();
+        start_reload(&mut commands, entity, weapon, &inventory, &weapons, &mut to_server);
+    }
+}
+
+// Starts a reload here and on the server, if there is anything to reload.
+fn start_reload(
+    commands: &mut Commands,
+    entity: Entity,
+    weapon: WeaponType,
+    inventory: &Inventory,
+    weapons: &WeaponStats,
+    to_server: &mut EventWriter<ToServer>,
+) {
+    let definition = weapons.get(weapon);
+    if inventory.ammo.get(&weapon).is_some_and(|ammo| ammo.can_reload(definition)) {
+        commands.entity(entity).insert(Reloading::new(weapon, definition));
+        to_server.send(ToServer(ClientMessage::Reload));
+    }
+}
+
+// the server's count replaces ours whenever it thinks they may differ
+pub fn receive_ammo(
+    mut from_server: EventReader<FromServer>,
+    mut inventory_query: Query<&mut Inventory, With<LocalPlayer>>,
+) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::Ammo(ammo) = message else {
+            continue;
+        };
+        if let Ok(mut inventory) = inventory_query.get_single_mut() {
+            inventory.ammo = ammo.iter().copied().collect();
+        }
+    }
+}
+
+pub fn update_player(
+    mut fired: EventReader<WeaponFired>,
+    mut commands: Commands,
+    time: Res<Time>,
     rapier_context: Res<RapierContext>,
-    mut player_query: Query<(
-        &mut Player,
-        &mut Transform,
-    )>,
-    camera_query : Query<(&Camera,&GlobalTransform),With<CameraController>>,
-    window_query: Query<&Window, With<PrimaryWindow>>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(Entity, &mut Spray), With<LocalPlayer>>,
+    mut camera_query : Query<(&GlobalTransform,&mut CameraController,&mut ViewPunch)>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     target_query: Query<Option<&Target>,With<Shootable>>,
-    spawn_spot : Query<&GlobalTransform,With<TracerSpawnSpot>>
+    hitbox_owners: HitboxOwners,
+    spawn_spot : Query<&GlobalTransform,With<TracerSpawnSpot>>,
+    local_id: Option<Res<LocalClientId>>,
+    clock: Res<InterpolationClock>,
+    mut to_server: EventWriter<ToServer>,
+    mut hit_events: EventWriter<HitEvent>,
 ) {
-    let spawn_spot = spawn_spot.get_single().unwrap();
-    let window = window_query.get_single().unwrap();
-    let (camera,camera_global_transform) = camera_query.get_single().unwrap();
-    if let Ok((_player, _transform)) = player_query.get_single_mut() {
-        if mouse_input.just_pressed(MouseButton::Left) {
-            let Some(ray) = camera.viewport_to_world(
-                &camera_global_transform,
-                Vec2::new(window.width() / 2., window.height() / 2.),
-            ) else {
-                return;
+    // no rig until the server has spawned us
+    let (Ok(spawn_spot), Ok((camera_global_transform,mut camera_controller,mut punch))) =
+        (spawn_spot.get_single(), camera_query.get_single_mut())
+    else {
+        return;
+    };
+    if let Ok((shooter, mut spray)) = player_query.get_single_mut() {
+        for WeaponFired { weapon, ago } in fired.read() {
+            // the server validates the shot and rewinds everyone to the tick we are looking at
+            let online = local_id.is_some();
+            let tick = clock.render_tick_before(*ago as f64);
+            let aim = camera_controller.rotation - punch.offset;
+            let definition = weapons.get(*weapon);
+            let recoil = &definition.recoil;
+            // the server counts the spray by the same tick, offline there is no server clock
+            let index = if online {
+                spray.next_at_tick(tick, clock.tickrate, recoil)
+            } else {
+                spray.next(time.elapsed_seconds_f64() - *ago as f64, recoil)
             };
+            if online {
+                to_server.send(ToServer(ClientMessage::Fire {
+                    tick,
+                    origin: camera_global_transform.translation(),
+                    rotation: aim,
+                    weapon: *weapon,
+                    spray: index as u32,
+                }));
+            }
+            let direction = view_direction(aim + recoil.offset(index));
+            camera_controller.rotation += punch.kick(recoil, index);
+            let ray = Ray3d::new(camera_global_transform.translation(), direction);
+            // level geometry and other players' hitboxes, never our own
             let predicate = |handle| {
-                target_query.get(handle).is_ok()
+                !hitbox_owners.is_player_body(handle)
+                    && hitbox_owners.resolve(handle).is_none_or(|(owner, _)| owner != shooter)
             };
-            let query_filter = QueryFilter::new().predicate(&predicate);
+            let query_filter = QueryFilter::new().exclude_sensors().predicate(&predicate);
+            // the server drops hits past the weapon's range, so nothing further is shown either
             let hit = rapier_context.cast_ray_and_get_normal(
                 ray.origin,
                 ray.direction.into(),
-                f32::MAX,
+                definition.falloff.range,
                 true,
                 query_filter,
             );
             if let Some((entity, ray_intersection)) = hit {
+                if let Some((victim, part)) = hitbox_owners.resolve(entity) {
+                    hit_events.send(HitEvent {
+                        shooter,
+                        victim,
+                        part,
+                        point: ray_intersection.point,
+                        normal: ray_intersection.normal,
+                        distance: ray_intersection.time_of_impact,
+                        weapon: *weapon,
+                    });
+                }
                 if let Ok(target) = target_query.get(entity) {
-                    if target.is_some(){
+                    // when connected the server decides what was hit, the tracer is only cosmetic
+                    if target.is_some() && !online {
                         commands.entity(entity).insert(DeadTarget);
                     }
                 }
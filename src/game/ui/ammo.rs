@@ -0,0 +1,64 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::game::{
+    player::player::{Inventory, LocalPlayer, Player},
+    weapons::{
+        ammo::{DryFire, Reloading},
+        weapons::WeaponStats,
+    },
+};
+
+// how long the counter stays red after a click on an empty magazine
+const EMPTY_FLASH_TIME: f32 = 0.4;
+
+#[derive(Resource, Default)]
+pub struct AmmoHud {
+    // seconds left of the red flash
+    flash: f32,
+}
+
+// Held weapon and rounds left, bottom centre.
+pub fn ammo_ui(
+    mut egui_ctx: EguiContexts,
+    time: Res<Time>,
+    mut hud: ResMut<AmmoHud>,
+    mut dry_fire: EventReader<DryFire>,
+    weapons: Res<WeaponStats>,
+    player_query: Query<(&Player, &Inventory, Option<&Reloading>), With<LocalPlayer>>,
+) {
+    if dry_fire.read().count() > 0 {
+        hud.flash = EMPTY_FLASH_TIME;
+    }
+    hud.flash = (hud.flash - time.delta_seconds()).max(0.0);
+    let Ok((player, inventory, reloading)) = player_query.get_single() else {
+        return;
+    };
+
+    let definition = weapons.get(player.weapon);
+    let rounds = match (inventory.ammo.get(&player.weapon), reloading) {
+        (Some(_), Some(reloading)) => format!("reloading {:.0}%", reloading.timer.fraction() * 100.),
+        (Some(ammo), None) => format!("{} / {}", ammo.magazine, ammo.reserve),
+        (None, _) => String::new(),
+    };
+    let color = if hud.flash > 0.0 {
+        egui::Color32::from_rgb(255, 70, 60)
+    } else {
+        egui::Color32::from_gray(225)
+    };
+
+    egui::Area::new(egui::Id::new("ammo"))
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -16.0))
+        .interactable(false)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(120))
+                .inner_margin(egui::Margin::same(6.0))
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(egui::RichText::new(&definition.name).color(egui::Color32::LIGHT_GRAY));
+                        ui.label(egui::RichText::new(rounds).monospace().strong().color(color));
+                    });
+                });
+        });
+}
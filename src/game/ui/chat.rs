@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::game::net::{
+    client::{FromServer, ToServer},
+    protocol::{ChatChannel, ChatSender, ClientMessage, ServerMessage, MAX_CHAT_LENGTH},
+};
+
+// lines stay fully visible this long, then fade out over CHAT_FADE
+const CHAT_VISIBLE: f32 = 8.0;
+const CHAT_FADE: f32 = 2.0;
+const MAX_CHAT_LINES: usize = 8;
+
+/* ---------------- State för chatten ---------------- */
+
+#[derive(Resource, Default)]
+pub struct ChatInput {
+    // Some while the input line is open
+    channel: Option<ChatChannel>,
+    text: String,
+}
+
+#[derive(Resource, Default)]
+pub struct ChatHistory {
+    lines: VecDeque<ChatLine>,
+}
+
+struct ChatLine {
+    from: Option<ChatSender>,
+    channel: ChatChannel,
+    text: String,
+    // Time<Real> elapsed when it arrived
+    received: f32,
+}
+
+/* ---------------- Input ---------------- */
+
+// Y for all-chat, U for team chat
+pub fn open_chat(keys: Res<ButtonInput<KeyCode>>, mut chat: ResMut<ChatInput>) {
+    if chat.channel.is_some() {
+        return;
+    }
+    if keys.just_pressed(KeyCode::KeyY) {
+        chat.channel = Some(ChatChannel::All);
+    } else if keys.just_pressed(KeyCode::KeyU) {
+        chat.channel = Some(ChatChannel::Team);
+    }
+}
+
+pub fn close_chat(mut chat: ResMut<ChatInput>) {
+    chat.channel = None;
+    chat.text.clear();
+}
+
+// While typing nothing reaches movement, shooting or the pause menu, egui reads its own events.
+pub fn block_game_input(
+    chat: Res<ChatInput>,
+    mut keys: ResMut<ButtonInput<KeyCode>>,
+    mut mouse: ResMut<ButtonInput<MouseButton>>,
+) {
+    if chat.channel.is_some() {
+        keys.reset_all();
+        mouse.reset_all();
+    }
+}
+
+pub fn receive_chat(time: Res<Time<Real>>, mut from_server: EventReader<FromServer>, mut history: ResMut<ChatHistory>) {
+    for FromServer(message) in from_server.read() {
+        let ServerMessage::Chat { from, channel, text } = message else {
+            continue;
+        };
+        history.lines.push_back(ChatLine {
+            from: from.clone(),
+            channel: *channel,
+            text: text.clone(),
+            received: time.elapsed_seconds(),
+        });
+        while history.lines.len() > MAX_CHAT_LINES {
+            history.lines.pop_front();
+        }
+    }
+}
+
+/* ---------------- Overlay ---------------- */
+
+fn line_text(line: &ChatLine) -> (String, egui::Color32) {
+    let Some(from) = &line.from else {
+        return (line.text.clone(), egui::Color32::from_rgb(230, 200, 90));
+    };
+    let mut prefix = String::new();
+    if from.dead {
+        prefix.push_str("*DEAD* ");
+    }
+    if line.channel == ChatChannel::Team {
+        prefix.push_str("(Team) ");
+    }
+    (format!("{}{}: {}", prefix, from.name, line.text), egui::Color32::WHITE)
+}
+
+pub fn chat_ui(
+    mut egui_ctx: EguiContexts,
+    time: Res<Time<Real>>,
+    mut chat: ResMut<ChatInput>,
+    history: Res<ChatHistory>,
+    mut to_server: EventWriter<ToServer>,
+) {
+    let now = time.elapsed_seconds();
+    let typing = chat.channel.is_some();
+    let mut send = false;
+    let mut cancel = false;
+
+    egui::Area::new(egui::Id::new("chat"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(16.0, -140.0))
+        .interactable(typing)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.set_width(420.0);
+            for line in history.lines.iter() {
+                // everything stays readable while typing
+                let age = now - line.received;
+                let alpha = if typing { 1.0 } else { (1.0 - (age - CHAT_VISIBLE) / CHAT_FADE).clamp(0.0, 1.0) };
+                if alpha <= 0.0 {
+                    continue;
+                }
+                let (text, color) = line_text(line);
+                ui.label(
+                    egui::RichText::new(text)
+                        .color(color.gamma_multiply(alpha))
+                        .background_color(egui::Color32::from_black_alpha((120.0 * alpha) as u8)),
+                );
+            }
+
+            let Some(channel) = chat.channel else {
+                return;
+            };
+            ui.horizontal(|ui| {
+                ui.label(match channel {
+                    ChatChannel::All => "Say:",
+                    ChatChannel::Team => "Say (team):",
+                });
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut chat.text)
+                        .char_limit(MAX_CHAT_LENGTH)
+                        .desired_width(f32::INFINITY),
+                );
+                response.request_focus();
+            });
+            send = ui.input(|input| input.key_pressed(egui::Key::Enter));
+            cancel = ui.input(|input| input.key_pressed(egui::Key::Escape));
+        });
+
+    if send {
+        if let Some(channel) = chat.channel {
+            let text = chat.text.trim().to_string();
+            if !text.is_empty() {
+                to_server.send(ToServer(ClientMessage::Chat { channel, text }));
+            }
+        }
+    }
+    if send || cancel {
+        chat.channel = None;
+        chat.text.clear();
+    }
+}
@@ -0,0 +1,185 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use super::ui::frame_card;
+use crate::game::{
+    app_state::AppState,
+    net::{
+        client::{ClientAuth, ConnectSettings},
+        connection::{connect, DisconnectCause},
+        protocol::MAX_NAME_LENGTH,
+    },
+};
+
+/* ---------------- Connect-dialog ---------------- */
+
+#[derive(Resource)]
+pub struct ConnectDialog {
+    pub open: bool,
+    address: String,
+    port: u16,
+    name: String,
+    error: Option<String>,
+}
+
+impl Default for ConnectDialog {
+    fn default() -> Self {
+        ConnectDialog {
+            open: false,
+            address: "127.0.0.1".to_string(),
+            port: 5000,
+            name: "Player".to_string(),
+            error: None,
+        }
+    }
+}
+
+impl ConnectDialog {
+    // the server browser joins under the same name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// hostnames are resolved here, the transport wants an address
+fn resolve(address: &str, port: u16) -> Result<SocketAddr, String> {
+    (address.trim(), port)
+        .to_socket_addrs()
+        .map_err(|err| err.to_string())?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| format!("no IPv4 address for '{}'", address.trim()))
+}
+
+pub fn connect_dialog_ui(
+    mut commands: Commands,
+    mut egui_ctx: EguiContexts,
+    mut dialog: ResMut<ConnectDialog>,
+    auth: Res<ClientAuth>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if !dialog.open {
+        return;
+    }
+    let mut open = true;
+    let mut join = false;
+    let mut offline = false;
+
+    egui::Window::new("Find Match")
+        .open(&mut open)
+        .collapsible(false)
+        .resizable(false)
+        .frame(frame_card())
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::Grid::new("connect_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Address");
+                ui.text_edit_singleline(&mut dialog.address);
+                ui.end_row();
+                ui.label("Port");
+                ui.add(egui::DragValue::new(&mut dialog.port));
+                ui.end_row();
+                ui.label("Name");
+                ui.add(egui::TextEdit::singleline(&mut dialog.name).char_limit(MAX_NAME_LENGTH));
+                ui.end_row();
+            });
+            if let Some(error) = &dialog.error {
+                ui.colored_label(egui::Color32::from_rgb(230, 80, 80), error);
+            }
+            ui.horizontal(|ui| {
+                join = ui.button("Connect").clicked();
+                offline = ui.button("Play offline").clicked();
+            });
+        });
+    dialog.open = open;
+
+    if offline {
+        dialog.open = false;
+        next_state.set(AppState::InGame);
+    }
+    if join {
+        let settings = resolve(&dialog.address, dialog.port).map(|server_addr| ConnectSettings {
+            server_addr,
+            name: dialog.name.trim().to_string(),
+            auth: auth.clone(),
+        });
+        match settings.and_then(|settings| connect(&mut commands, settings).map_err(|err| err.to_string())) {
+            Ok(()) => {
+                dialog.open = false;
+                dialog.error = None;
+                next_state.set(AppState::Connecting);
+            }
+            Err(err) => dialog.error = Some(err),
+        }
+    }
+}
+
+/* ---------------- Anslutning / laddning ---------------- */
+
+pub fn connecting_ui(
+    mut egui_ctx: EguiContexts,
+    state: Res<State<AppState>>,
+    settings: Option<Res<ConnectSettings>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    let status = match state.get() {
+        AppState::Loading => "Loading...".to_string(),
+        _ => match settings {
+            Some(settings) => format!("Connecting to {}...", settings.server_addr),
+            None => "Connecting...".to_string(),
+        },
+    };
+
+    egui::Window::new("Connecting")
+        .title_bar(false)
+        .collapsible(false)
+        .resizable(false)
+        .frame(frame_card())
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(status);
+            });
+            // leaving for the main menu tears the connection down
+            if ui.button("Cancel").clicked() {
+                next_state.set(AppState::MainMenu);
+            }
+        });
+}
+
+/* ---------------- Frånkopplad ---------------- */
+
+pub fn disconnected_ui(
+    mut commands: Commands,
+    mut egui_ctx: EguiContexts,
+    cause: Option<Res<DisconnectCause>>,
+    settings: Option<Res<ConnectSettings>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    egui::Window::new("Disconnected")
+        .collapsible(false)
+        .resizable(false)
+        .frame(frame_card())
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .show(egui_ctx.ctx_mut(), |ui| {
+            match &cause {
+                Some(cause) => ui.label(cause.to_string()),
+                None => ui.label("Disconnected from server"),
+            };
+            ui.horizontal(|ui| {
+                if let Some(settings) = &settings {
+                    if ui.button("Reconnect").clicked() {
+                        match connect(&mut commands, (**settings).clone()) {
+                            Ok(()) => next_state.set(AppState::Connecting),
+                            Err(err) => commands.insert_resource(DisconnectCause::Failed(err.to_string())),
+                        }
+                    }
+                }
+                if ui.button("Main Menu").clicked() {
+                    next_state.set(AppState::MainMenu);
+                }
+            });
+        });
+}
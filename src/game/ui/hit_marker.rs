@@ -0,0 +1,48 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::game::player::player::{HitEvent, HitboxPart, LocalPlayer};
+
+const HIT_MARKER_TIME: f32 = 0.25;
+
+// The X around the crosshair when one of our shots lands on a player. Online it shows
+// what we saw, the server may still disagree.
+#[derive(Resource, Default)]
+pub struct HitMarker {
+    // seconds left to show it
+    remaining: f32,
+    headshot: bool,
+}
+
+pub fn hit_marker_ui(
+    mut egui_ctx: EguiContexts,
+    time: Res<Time>,
+    mut marker: ResMut<HitMarker>,
+    mut hit_events: EventReader<HitEvent>,
+    local_player: Query<Entity, With<LocalPlayer>>,
+) {
+    let local = local_player.get_single().ok();
+    for hit in hit_events.read().filter(|hit| Some(hit.shooter) == local) {
+        marker.remaining = HIT_MARKER_TIME;
+        marker.headshot = hit.part == HitboxPart::Head;
+    }
+    if marker.remaining <= 0.0 {
+        return;
+    }
+    marker.remaining -= time.delta_seconds();
+
+    let ctx = egui_ctx.ctx_mut();
+    let center = ctx.screen_rect().center();
+    let alpha = (marker.remaining / HIT_MARKER_TIME).clamp(0.0, 1.0);
+    let color = if marker.headshot {
+        egui::Color32::from_rgb(255, 80, 60)
+    } else {
+        egui::Color32::WHITE
+    };
+    let stroke = egui::Stroke::new(2.0, color.gamma_multiply(alpha));
+    let painter = ctx.layer_painter(egui::LayerId::background());
+    for (x, y) in [(1.0, 1.0), (1.0, -1.0), (-1.0, 1.0), (-1.0, -1.0)] {
+        let direction = egui::vec2(x, y);
+        painter.line_segment([center + direction * 6.0, center + direction * 14.0], stroke);
+    }
+}
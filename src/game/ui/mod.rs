@@ -1,2 +1,11 @@
+pub mod ammo;
+pub mod chat;
+pub mod connect;
 pub mod crosshair;
-pub mod ui;
\ No newline at end of file
+pub mod hit_marker;
+pub mod nameplates;
+pub mod net_graph;
+pub mod network_conditions;
+pub mod scoreboard;
+pub mod server_browser;
+pub mod ui;
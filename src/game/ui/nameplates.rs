@@ -0,0 +1,34 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::game::{net::client_players::Nameplate, player::camera_controller::CameraController};
+
+// above the top of the head
+const NAMEPLATE_HEIGHT: f32 = 2.2;
+
+// Remote player names projected to the screen, drawn behind every egui window.
+pub fn draw_nameplates(
+    mut egui_ctx: EguiContexts,
+    camera_query: Query<(&Camera, &GlobalTransform), With<CameraController>>,
+    nameplate_query: Query<(&Nameplate, &GlobalTransform)>,
+) {
+    let Ok((camera, camera_transform)) = camera_query.get_single() else {
+        return;
+    };
+    let painter = egui_ctx.ctx_mut().layer_painter(egui::LayerId::background());
+
+    for (nameplate, transform) in nameplate_query.iter() {
+        let position = transform.translation() + Vec3::Y * NAMEPLATE_HEIGHT;
+        // None when behind the camera
+        let Some(screen) = camera.world_to_viewport(camera_transform, position) else {
+            continue;
+        };
+        painter.text(
+            egui::pos2(screen.x, screen.y),
+            egui::Align2::CENTER_BOTTOM,
+            &nameplate.0,
+            egui::FontId::proportional(14.0),
+            egui::Color32::WHITE,
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use bevy::{
+    diagnostic::{DiagnosticPath, DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    prelude::*,
+};
+use bevy_egui::{egui, EguiContexts};
+use bevy_renet::renet::RenetClient;
+
+use crate::game::{
+    net::{
+        client::FromServer,
+        interpolation::{RemotePlayer, SnapshotBuffer},
+        prediction::PredictionSmoothing,
+        protocol::ServerMessage,
+    },
+    player::player::LocalPlayer,
+};
+
+#[derive(Resource, Default)]
+pub struct NetGraph {
+    pub open: bool,
+    // from the server's last ServerStats
+    server_tick_time: Option<f32>,
+    loss_in: f32,
+}
+
+// F7, like net_graph 1
+pub fn toggle_net_graph(keys: Res<ButtonInput<KeyCode>>, mut net_graph: ResMut<NetGraph>) {
+    if keys.just_pressed(KeyCode::F7) {
+        net_graph.open = !net_graph.open;
+    }
+}
+
+pub fn receive_server_stats(mut from_server: EventReader<FromServer>, mut net_graph: ResMut<NetGraph>) {
+    for FromServer(message) in from_server.read() {
+        if let ServerMessage::ServerStats { tick_time, packet_loss } = message {
+            net_graph.server_tick_time = Some(*tick_time);
+            net_graph.loss_in = *packet_loss;
+        }
+    }
+}
+
+pub fn clear_server_stats(mut net_graph: ResMut<NetGraph>) {
+    net_graph.server_tick_time = None;
+    net_graph.loss_in = 0.;
+}
+
+pub fn net_graph_ui(
+    mut egui_ctx: EguiContexts,
+    net_graph: Res<NetGraph>,
+    diagnostics: Res<DiagnosticsStore>,
+    client: Option<Res<RenetClient>>,
+    buffers: Query<&SnapshotBuffer, With<RemotePlayer>>,
+    smoothing: Query<&PredictionSmoothing, With<LocalPlayer>>,
+) {
+    if !net_graph.open {
+        return;
+    }
+    let smoothed = |path: &DiagnosticPath| diagnostics.get(path).and_then(|diagnostic| diagnostic.smoothed());
+    let fps = smoothed(&FrameTimeDiagnosticsPlugin::FPS).unwrap_or(0.);
+    let frame_time = smoothed(&FrameTimeDiagnosticsPlugin::FRAME_TIME).unwrap_or(0.);
+
+    let mut lines = vec![format!("fps {:>4.0}  frame {:>5.2} ms", fps, frame_time)];
+    match client {
+        Some(client) => {
+            let info = client.network_info();
+            lines.push(format!(
+                "ping {:>4.0} ms  sv {}",
+                info.rtt * 1000.,
+                net_graph
+                    .server_tick_time
+                    .map_or("-".to_string(), |tick_time| format!("{:.2} ms", tick_time)),
+            ));
+            lines.push(format!(
+                "loss in {:>4.1}%  out {:>4.1}%",
+                net_graph.loss_in * 100.,
+                info.packet_loss * 100.
+            ));
+            lines.push(format!(
+                "in {:>6.2} kB/s  out {:>6.2} kB/s",
+                info.bytes_received_per_second / 1024.,
+                info.bytes_sent_per_second / 1024.
+            ));
+
+            // snapshots waiting to be interpolated, averaged over the remote players
+            let depths: Vec<usize> = buffers.iter().map(SnapshotBuffer::len).collect();
+            let depth = if depths.is_empty() {
+                "-".to_string()
+            } else {
+                format!("{:.1}", depths.iter().sum::<usize>() as f32 / depths.len() as f32)
+            };
+            let error = smoothing
+                .get_single()
+                .map_or("-".to_string(), |smoothing| format!("{:.1} cm", smoothing.last_error * 100.));
+            lines.push(format!("interp {}  pred err {}", depth, error));
+        }
+        None => lines.push("offline".to_string()),
+    }
+
+    egui::Area::new(egui::Id::new("net_graph"))
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-16.0, -16.0))
+        .interactable(false)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(120))
+                .inner_margin(egui::Margin::same(6.0))
+                .show(ui, |ui| {
+                    for line in lines {
+                        ui.label(egui::RichText::new(line).monospace().small().color(egui::Color32::LIGHT_GRAY));
+                    }
+                });
+        });
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::game::net::conditioner::ClientConditioner;
+
+#[derive(Resource, Default)]
+pub struct NetworkConditionsPanel {
+    pub open: bool,
+}
+
+// F8, the sliders need a free cursor so pause the game to change them
+pub fn toggle_network_conditions(keys: Res<ButtonInput<KeyCode>>, mut panel: ResMut<NetworkConditionsPanel>) {
+    if keys.just_pressed(KeyCode::F8) {
+        panel.open = !panel.open;
+    }
+}
+
+pub fn network_conditions_ui(
+    mut egui_ctx: EguiContexts,
+    mut panel: ResMut<NetworkConditionsPanel>,
+    mut conditioner: ResMut<ClientConditioner>,
+) {
+    if !panel.open {
+        return;
+    }
+    let mut settings = conditioner.settings().clone();
+    let mut latency = settings.latency.as_millis() as u64;
+    let mut jitter = settings.jitter.as_millis() as u64;
+    let mut loss = settings.loss * 100.;
+    let mut duplicate = settings.duplicate * 100.;
+    let mut reorder = settings.reorder * 100.;
+
+    egui::Window::new("Network conditions")
+        .open(&mut panel.open)
+        .resizable(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-10.0, 10.0))
+        .show(egui_ctx.ctx_mut(), |ui| {
+            ui.small("Applied to this client's traffic in both directions");
+            ui.add(egui::Slider::new(&mut latency, 0..=500).text("Latency").suffix(" ms"));
+            ui.add(egui::Slider::new(&mut jitter, 0..=100).text("Jitter").suffix(" ms"));
+            ui.add(egui::Slider::new(&mut loss, 0.0..=50.0).text("Loss").suffix(" %"));
+            ui.add(egui::Slider::new(&mut duplicate, 0.0..=20.0).text("Duplicate").suffix(" %"));
+            ui.add(egui::Slider::new(&mut reorder, 0.0..=20.0).text("Reorder").suffix(" %"));
+            ui.horizontal(|ui| {
+                ui.label("Seed");
+                ui.add(egui::DragValue::new(&mut settings.seed));
+            });
+            if ui.button("Reset").clicked() {
+                (latency, jitter, loss, duplicate, reorder) = (0, 0, 0., 0., 0.);
+            }
+        });
+
+    settings.latency = Duration::from_millis(latency);
+    settings.jitter = Duration::from_millis(jitter);
+    settings.loss = loss / 100.;
+    settings.duplicate = duplicate / 100.;
+    settings.reorder = reorder / 100.;
+    if settings != *conditioner.settings() {
+        conditioner.set_settings(settings);
+    }
+}
@@ -0,0 +1,87 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use super::ui::frame_card;
+use crate::game::{
+    net::{
+        client::{FromServer, LocalClientId},
+        protocol::{ScoreboardEntry, ServerMessage},
+    },
+    player::player::Team,
+};
+
+// the latest list from the server, it sends a full one every second
+#[derive(Resource, Default)]
+pub struct Scoreboard {
+    entries: Vec<ScoreboardEntry>,
+}
+
+pub fn receive_scoreboard(mut from_server: EventReader<FromServer>, mut scoreboard: ResMut<Scoreboard>) {
+    for FromServer(message) in from_server.read() {
+        if let ServerMessage::Scoreboard(entries) = message {
+            scoreboard.entries = entries.clone();
+        }
+    }
+}
+
+pub fn clear_scoreboard(mut scoreboard: ResMut<Scoreboard>) {
+    scoreboard.entries.clear();
+}
+
+// shown while Tab is held
+pub fn scoreboard_ui(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut egui_ctx: EguiContexts,
+    scoreboard: Res<Scoreboard>,
+    local_id: Option<Res<LocalClientId>>,
+) {
+    if !keys.pressed(KeyCode::Tab) {
+        return;
+    }
+    let local_id = local_id.map(|id| id.0);
+
+    egui::Area::new(egui::Id::new("scoreboard"))
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .interactable(false)
+        .show(egui_ctx.ctx_mut(), |ui| {
+            frame_card().show(ui, |ui| {
+                ui.set_width(520.0);
+                for (team, title) in [
+                    (Team::CounterTerrorists, "Counter-Terrorists"),
+                    (Team::Terrorists, "Terrorists"),
+                ] {
+                    let mut entries: Vec<&ScoreboardEntry> =
+                        scoreboard.entries.iter().filter(|entry| entry.team == team).collect();
+                    entries.sort_by(|a, b| b.kills.cmp(&a.kills).then(a.deaths.cmp(&b.deaths)));
+
+                    ui.heading(title);
+                    egui::Grid::new(title).num_columns(4).striped(true).min_col_width(60.0).show(ui, |ui| {
+                        for header in ["Name", "K", "D", "Ping"] {
+                            ui.strong(header);
+                        }
+                        ui.end_row();
+
+                        for entry in entries {
+                            let mut name = egui::RichText::new(&entry.name);
+                            if Some(entry.id) == local_id {
+                                name = name.strong();
+                            }
+                            if !entry.connected {
+                                name = name.weak();
+                            }
+                            ui.label(name);
+                            ui.label(entry.kills.to_string());
+                            ui.label(entry.deaths.to_string());
+                            if entry.connected {
+                                ui.label(entry.ping.to_string());
+                            } else {
+                                ui.weak("DISCONNECTED");
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    ui.add_space(8.0);
+                }
+            });
+        });
+}
@@ -0,0 +1,212 @@
+use std::{cmp::Ordering, net::SocketAddr};
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+
+use crate::game::net::{
+    client::{ClientAuth, ConnectSettings},
+    connection::connect,
+    discovery::{BrowserEntry, ServerBrowser},
+    protocol::PROTOCOL_VERSION,
+};
+
+/* ---------------- State för serverlistan ---------------- */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BrowserColumn {
+    Name,
+    Map,
+    Mode,
+    Players,
+    #[default]
+    Ping,
+}
+
+#[derive(Resource)]
+pub struct BrowserView {
+    sort: BrowserColumn,
+    ascending: bool,
+    // query address of the highlighted row
+    selected: Option<SocketAddr>,
+    manual_address: String,
+    error: Option<String>,
+}
+
+impl Default for BrowserView {
+    fn default() -> Self {
+        BrowserView {
+            sort: BrowserColumn::Ping,
+            ascending: true,
+            selected: None,
+            manual_address: String::new(),
+            error: None,
+        }
+    }
+}
+
+impl BrowserView {
+    pub fn selected<'a>(&self, browser: &'a ServerBrowser) -> Option<&'a BrowserEntry> {
+        browser.entries.iter().find(|entry| Some(entry.query_addr) == self.selected)
+    }
+}
+
+pub fn refresh_server_browser(mut browser: ResMut<ServerBrowser>) {
+    browser.refresh();
+}
+
+fn compare(a: &BrowserEntry, b: &BrowserEntry, column: BrowserColumn) -> Ordering {
+    match column {
+        // servers that haven't answered yet go last
+        BrowserColumn::Ping => match (a.ping, b.ping) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (a, b) => a.is_none().cmp(&b.is_none()),
+        },
+        _ => {
+            let (Some(a), Some(b)) = (&a.info, &b.info) else {
+                return a.info.is_none().cmp(&b.info.is_none());
+            };
+            match column {
+                BrowserColumn::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                BrowserColumn::Map => a.map.cmp(&b.map),
+                BrowserColumn::Mode => a.mode.cmp(&b.mode),
+                _ => a.players.cmp(&b.players),
+            }
+        }
+    }
+}
+
+fn joinable(entry: &BrowserEntry) -> bool {
+    entry
+        .info
+        .as_ref()
+        .is_some_and(|info| info.protocol_version == PROTOCOL_VERSION && info.players < info.max_players)
+}
+
+/* ---------------- Serverlista ---------------- */
+
+// Draws the browser into the PLAY tab, true once a join has started connecting.
+pub fn server_browser_ui(
+    ui: &mut egui::Ui,
+    commands: &mut Commands,
+    browser: &mut ServerBrowser,
+    view: &mut BrowserView,
+    name: &str,
+    auth: &ClientAuth,
+) -> bool {
+    let mut join = None;
+
+    ui.horizontal(|ui| {
+        ui.label(egui::RichText::new("SERVERS").weak());
+        if ui.button("Refresh").clicked() {
+            browser.refresh();
+        }
+        ui.separator();
+        ui.add(egui::TextEdit::singleline(&mut view.manual_address).hint_text("127.0.0.1:5000").desired_width(160.0));
+        if ui.button("Add").clicked() {
+            match view.manual_address.trim().parse::<SocketAddr>() {
+                Ok(addr) => {
+                    browser.add_manual(addr);
+                    view.manual_address.clear();
+                    view.error = None;
+                }
+                Err(_) => view.error = Some(format!("'{}' is not an ip:port address", view.manual_address.trim())),
+            }
+        }
+    });
+    if let Some(error) = &view.error {
+        ui.colored_label(egui::Color32::from_rgb(230, 80, 80), error);
+    }
+    ui.separator();
+
+    let mut entries: Vec<&BrowserEntry> = browser.entries.iter().collect();
+    entries.sort_by(|a, b| {
+        let ordering = compare(a, b, view.sort);
+        if view.ascending { ordering } else { ordering.reverse() }
+    });
+
+    egui::ScrollArea::vertical().max_height(360.0).show(ui, |ui| {
+        egui::Grid::new("server_browser").num_columns(5).striped(true).min_col_width(60.0).show(ui, |ui| {
+            for (column, label) in [
+                (BrowserColumn::Name, "Name"),
+                (BrowserColumn::Map, "Map"),
+                (BrowserColumn::Mode, "Mode"),
+                (BrowserColumn::Players, "Players"),
+                (BrowserColumn::Ping, "Ping"),
+            ] {
+                let arrow = match (view.sort == column, view.ascending) {
+                    (true, true) => " ▲",
+                    (true, false) => " ▼",
+                    _ => "",
+                };
+                if ui.selectable_label(view.sort == column, format!("{}{}", label, arrow)).clicked() {
+                    if view.sort == column {
+                        view.ascending = !view.ascending;
+                    } else {
+                        (view.sort, view.ascending) = (column, true);
+                    }
+                }
+            }
+            ui.end_row();
+
+            for entry in entries {
+                let selected = view.selected == Some(entry.query_addr);
+                let name = match &entry.info {
+                    Some(info) if info.protocol_version != PROTOCOL_VERSION => {
+                        format!("{} (v{})", info.name, info.protocol_version)
+                    }
+                    Some(info) => info.name.clone(),
+                    None => entry.query_addr.to_string(),
+                };
+                let response = ui.selectable_label(selected, name);
+                if response.clicked() {
+                    view.selected = Some(entry.query_addr);
+                }
+                if response.double_clicked() && joinable(entry) {
+                    join = entry.game_addr();
+                }
+                match &entry.info {
+                    Some(info) => {
+                        ui.label(&info.map);
+                        ui.label(&info.mode);
+                        ui.label(format!("{}/{}", info.players, info.max_players));
+                    }
+                    None => {
+                        ui.weak("-");
+                        ui.weak("-");
+                        ui.weak("-");
+                    }
+                }
+                match entry.ping {
+                    Some(ping) => ui.label(format!("{} ms", ping.as_millis())),
+                    None => ui.weak("?"),
+                };
+                ui.end_row();
+            }
+        });
+    });
+
+    ui.add_space(8.0);
+    let selected = view.selected(browser).filter(|entry| joinable(entry));
+    if ui.add_enabled(selected.is_some(), egui::Button::new("Join")).clicked() {
+        join = selected.and_then(BrowserEntry::game_addr);
+    }
+
+    let Some(server_addr) = join else {
+        return false;
+    };
+    let settings = ConnectSettings {
+        server_addr,
+        name: name.trim().to_string(),
+        auth: auth.clone(),
+    };
+    match connect(commands, settings) {
+        Ok(()) => {
+            view.error = None;
+            true
+        }
+        Err(err) => {
+            view.error = Some(format!("could not connect: {}", err));
+            false
+        }
+    }
+}
@@ -1,20 +1,100 @@
-use bevy::prelude::*;
+use bevy::{diagnostic::FrameTimeDiagnosticsPlugin, input::InputSystem, prelude::*};
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use crate::game::app_state::AppState;
+use bevy_renet::renet::RenetClient;
+use crate::game::{
+    app_state::AppState,
+    net::{
+        client::{ClientAuth, LocalClientId},
+        discovery::ServerBrowser,
+    },
+};
 
-use super::crosshair;
+use super::{
+    ammo, chat, connect, crosshair, hit_marker, nameplates, net_graph, network_conditions, scoreboard, server_browser,
+};
 
 pub struct UiPlugin;
 
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
+        // the net graph reads fps and frame time from it
+        if !app.is_plugin_added::<FrameTimeDiagnosticsPlugin>() {
+            app.add_plugins(FrameTimeDiagnosticsPlugin);
+        }
         app.add_plugins(EguiPlugin)
             .insert_resource(MenuState::default())
+            .init_resource::<network_conditions::NetworkConditionsPanel>()
+            .init_resource::<connect::ConnectDialog>()
+            .init_resource::<server_browser::BrowserView>()
+            .init_resource::<chat::ChatInput>()
+            .init_resource::<chat::ChatHistory>()
+            .init_resource::<scoreboard::Scoreboard>()
+            .init_resource::<net_graph::NetGraph>()
+            .init_resource::<hit_marker::HitMarker>()
+            .init_resource::<ammo::AmmoHud>()
             .add_systems(Startup, setup_egui_theme)
-            .add_systems(Update, main_menu_ui.run_if(in_state(AppState::MainMenu)))
+            .add_systems(OnEnter(AppState::MainMenu), server_browser::refresh_server_browser)
+            .add_systems(
+                Update,
+                (main_menu_ui, connect::connect_dialog_ui)
+                    .chain()
+                    .run_if(in_state(AppState::MainMenu)),
+            )
+            .add_systems(
+                Update,
+                connect::connecting_ui
+                    .run_if(in_state(AppState::Connecting).or_else(in_state(AppState::Loading))),
+            )
+            .add_systems(Update, connect::disconnected_ui.run_if(in_state(AppState::Disconnected)))
             .add_systems(Update, pause_menu_ui.run_if(in_state(AppState::Paused)))
             .add_systems(Update, crosshair::spawn_crosshair.run_if(in_state(AppState::InGame)))
-            .add_systems(Update, toggle_pause);
+            .add_systems(
+                Update,
+                (hit_marker::hit_marker_ui, ammo::ammo_ui).run_if(in_state(AppState::InGame)),
+            )
+            .add_systems(Update, nameplates::draw_nameplates.run_if(not(in_state(AppState::MainMenu))))
+            .add_systems(Update, toggle_pause)
+            .add_systems(
+                PreUpdate,
+                chat::block_game_input.after(InputSystem).run_if(in_state(AppState::InGame)),
+            )
+            .add_systems(
+                Update,
+                chat::open_chat.run_if(in_state(AppState::InGame).and_then(resource_exists::<LocalClientId>)),
+            )
+            .add_systems(
+                Update,
+                (chat::receive_chat, chat::chat_ui).chain().run_if(not(in_state(AppState::MainMenu))),
+            )
+            .add_systems(OnExit(AppState::InGame), chat::close_chat)
+            .add_systems(
+                OnEnter(AppState::Connecting),
+                (scoreboard::clear_scoreboard, net_graph::clear_server_stats),
+            )
+            .add_systems(
+                Update,
+                (
+                    scoreboard::receive_scoreboard,
+                    scoreboard::scoreboard_ui.run_if(in_state(AppState::InGame)),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                (
+                    net_graph::receive_server_stats,
+                    (net_graph::toggle_net_graph, net_graph::net_graph_ui).run_if(in_state(AppState::InGame)),
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                (
+                    network_conditions::toggle_network_conditions,
+                    network_conditions::network_conditions_ui,
+                )
+                    .chain(),
+            );
     }
 }
 
@@ -26,9 +106,8 @@ struct MenuState {
     selected_news: usize,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum TopTab { Play, Inventory, Watch, Awards, Options }
-impl Default for TopTab { fn default() -> Self { TopTab::Play } }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum TopTab { #[default] Play, Inventory, Watch, Awards, Options }
 
 /* ---------------- Tema / Skin ---------------- */
 
@@ -63,7 +142,7 @@ fn setup_egui_theme(mut egui_ctx: EguiContexts) {
     ctx.set_style(style);
 }
 
-fn frame_card() -> egui::Frame {
+pub fn frame_card() -> egui::Frame {
     egui::Frame::none()
         .fill(egui::Color32::from_black_alpha(100))
         .stroke(egui::Stroke::new(1.0, egui::Color32::from_white_alpha(28)))
@@ -81,8 +160,13 @@ fn frame_topbar() -> egui::Frame {
 /* ---------------- Huvudmeny ---------------- */
 
 fn main_menu_ui(
+    mut commands: Commands,
     mut egui_ctx: EguiContexts,
     mut menu: ResMut<MenuState>,
+    mut connect_dialog: ResMut<connect::ConnectDialog>,
+    mut browser: ResMut<ServerBrowser>,
+    mut browser_view: ResMut<server_browser::BrowserView>,
+    auth: Res<ClientAuth>,
     mut next_state: ResMut<NextState<AppState>>,
 ) {
     let ctx = egui_ctx.ctx_mut();
@@ -143,10 +227,13 @@ fn main_menu_ui(
             frame_card().show(ui, |ui| {
                 ui.label(egui::RichText::new("YOUR QUEUE").weak());
                 ui.separator();
-                ui.label("Competitive • Mirage");
+                match browser_view.selected(&browser).and_then(|entry| entry.info.as_ref()) {
+                    Some(info) => ui.label(format!("{} • {}", info.mode, info.map)),
+                    None => ui.weak("No server selected"),
+                };
                 ui.add_space(8.0);
                 if ui.button("Find Match").clicked() && matches!(menu.top_tab, TopTab::Play) {
-                    next_state.set(AppState::InGame);
+                    connect_dialog.open = true;
                 }
             });
             ui.add_space(8.0);
@@ -161,6 +248,16 @@ fn main_menu_ui(
     egui::CentralPanel::default()
         .frame(egui::Frame::none().inner_margin(egui::Margin::symmetric(12.0, 10.0)))
         .show(ctx, |ui| {
+            if menu.top_tab == TopTab::Play {
+                frame_card().show(ui, |ui| {
+                    let name = connect_dialog.name().to_string();
+                    if server_browser::server_browser_ui(ui, &mut commands, &mut browser, &mut browser_view, &name, &auth) {
+                        next_state.set(AppState::Connecting);
+                    }
+                });
+                return;
+            }
+
             // HERO / banner
             frame_card().show(ui, |ui| {
                 ui.set_height(160.0);
@@ -197,7 +294,11 @@ fn main_menu_ui(
 
 /* ---------------- Pausmeny (overlay) ---------------- */
 
-fn pause_menu_ui(mut egui_ctx: EguiContexts, mut next_state: ResMut<NextState<AppState>>) {
+fn pause_menu_ui(
+    mut egui_ctx: EguiContexts,
+    client: Option<Res<RenetClient>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
     let ctx = egui_ctx.ctx_mut();
 
     // Mörk tint
@@ -216,7 +317,12 @@ fn pause_menu_ui(mut egui_ctx: EguiContexts, mut next_state: ResMut<NextState<Ap
         .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
         .show(ctx, |ui| {
             if ui.button("Resume").clicked() { next_state.set(AppState::InGame); }
-            if ui.button("Main Menu").clicked() { next_state.set(AppState::MainMenu); }
+            // the main menu tears down the connection, see net::connection
+            if client.is_some() {
+                if ui.button("Disconnect").clicked() { next_state.set(AppState::MainMenu); }
+            } else if ui.button("Main Menu").clicked() {
+                next_state.set(AppState::MainMenu);
+            }
             if ui.button("Quit").clicked() { std::process::exit(0); }
         });
 }
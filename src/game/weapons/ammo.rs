@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{definition::WeaponDefinition, weapons::WeaponStats};
+use crate::game::player::player::{Inventory, Player, WeaponType};
+
+// Rounds carried for one gun.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ammo {
+    pub magazine: u32,
+    pub reserve: u32,
+}
+
+impl Ammo {
+    // None for melee
+    pub fn full(definition: &WeaponDefinition) -> Option<Ammo> {
+        Some(Ammo {
+            magazine: definition.magazine_size?,
+            reserve: definition.reserve_size,
+        })
+    }
+
+    pub fn can_reload(&self, definition: &WeaponDefinition) -> bool {
+        self.reserve > 0 && definition.magazine_size.is_some_and(|size| self.magazine < size)
+    }
+
+    // tops the magazine up from the reserve
+    pub fn reload(&mut self, definition: &WeaponDefinition) {
+        let Some(size) = definition.magazine_size else {
+            return;
+        };
+        let moved = size.saturating_sub(self.magazine).min(self.reserve);
+        self.magazine += moved;
+        self.reserve -= moved;
+    }
+}
+
+// A reload in progress. It is cancelled by switching away from the weapon.
+#[derive(Component, Debug)]
+pub struct Reloading {
+    pub weapon: WeaponType,
+    pub timer: Timer,
+}
+
+impl Reloading {
+    pub fn new(weapon: WeaponType, definition: &WeaponDefinition) -> Reloading {
+        Reloading {
+            weapon,
+            timer: Timer::new(Duration::from_secs_f32(definition.reload_time), TimerMode::Once),
+        }
+    }
+}
+
+// The trigger was pulled on an empty magazine, the ammo counter flashes red.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DryFire {
+    pub weapon: WeaponType,
+}
+
+// Runs the same way on the client (for the local player) and on the server.
+pub fn finish_reloads(
+    mut commands: Commands,
+    time: Res<Time>,
+    weapons: Res<WeaponStats>,
+    mut player_query: Query<(Entity, &Player, &mut Reloading, &mut Inventory)>,
+) {
+    for (entity, player, mut reloading, mut inventory) in player_query.iter_mut() {
+        if player.weapon != reloading.weapon {
+            commands.entity(entity).remove::<Reloading>();
+            continue;
+        }
+        if reloading.timer.tick(time.delta()).finished() {
+            let definition = weapons.get(reloading.weapon);
+            if let Some(ammo) = inventory.ammo.get_mut(&reloading.weapon) {
+                ammo.reload(definition);
+            }
+            commands.entity(entity).remove::<Reloading>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_moves_what_the_reserve_has() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        let mut ammo = Ammo::full(&rifle).unwrap();
+        assert_eq!(ammo, Ammo { magazine: 30, reserve: 90 });
+        assert!(!ammo.can_reload(&rifle));
+
+        ammo.magazine = 12;
+        assert!(ammo.can_reload(&rifle));
+        ammo.reload(&rifle);
+        assert_eq!(ammo, Ammo { magazine: 30, reserve: 72 });
+
+        let mut ammo = Ammo { magazine: 0, reserve: 10 };
+        ammo.reload(&rifle);
+        assert_eq!(ammo, Ammo { magazine: 10, reserve: 0 });
+        assert!(!ammo.can_reload(&rifle));
+    }
+
+    #[test]
+    fn melee_has_no_ammo() {
+        let knife = WeaponDefinition::builtin(WeaponType::Knife);
+        assert_eq!(Ammo::full(&knife), None);
+    }
+
+    #[test]
+    fn inventory_only_fires_loaded_weapons() {
+        let weapons = WeaponStats::default();
+        let mut inventory = Inventory::new(&weapons);
+        inventory.ammo.insert(WeaponType::Pistol, Ammo { magazine: 1, reserve: 0 });
+
+        assert!(inventory.take_round(WeaponType::Pistol, weapons.get(WeaponType::Pistol)));
+        assert!(!inventory.take_round(WeaponType::Pistol, weapons.get(WeaponType::Pistol)));
+        assert!(inventory.take_round(WeaponType::Knife, weapons.get(WeaponType::Knife)));
+        // not carried
+        assert!(!inventory.take_round(WeaponType::Sniper, weapons.get(WeaponType::Sniper)));
+    }
+}
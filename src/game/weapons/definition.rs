@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::game::{math::coordinates::blender_to_world, player::player::WeaponType};
+
+// Everything that makes one weapon play differently, loaded from assets/weapons/*.weapon.ron.
+// Angles are in degrees, distances in metres and times in seconds.
+#[derive(Asset, TypePath, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WeaponDefinition {
+    pub name: String,
+    // body shot on an unarmored player at point blank
+    pub damage: f32,
+    // share of the damage that goes through armor
+    pub armor_penetration: f32,
+    // rounds per minute
+    pub fire_rate: f32,
+    pub fire_mode: FireMode,
+    // weapons with a burst can be toggled into it
+    #[serde(default)]
+    pub burst: Option<Burst>,
+    // None for melee
+    pub magazine_size: Option<u32>,
+    pub reserve_size: u32,
+    pub reload_time: f32,
+    // seconds after switching to it before it fires
+    pub draw_time: f32,
+    pub move_speed: f32,
+    pub recoil: Recoil,
+    pub falloff: RangeFalloff,
+    pub model: String,
+    // where tracers start, relative to the camera
+    pub muzzle_offset: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FireMode {
+    Semi,
+    Auto,
+    Burst,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Burst {
+    pub rounds: u32,
+    // rounds per minute within the burst
+    pub fire_rate: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Recoil {
+    // (pitch, yaw) offset of each shot in a spray, the last one repeats
+    pub pattern: Vec<Vec2>,
+    // how much of the offset kicks the camera
+    pub view_punch: f32,
+    // degrees per second back towards the original aim
+    pub recovery: f32,
+    // seconds between two shots before the pattern starts over
+    pub reset: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RangeFalloff {
+    // nothing is hit further away than this
+    pub range: f32,
+    // share of the damage left after every 10 m
+    pub modifier: f32,
+}
+
+impl WeaponDefinition {
+    // minsta tid mellan två skott
+    pub fn cycle_time(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.fire_rate as f64)
+    }
+
+    pub fn damage_at(&self, distance: f32) -> f32 {
+        self.damage * self.falloff.modifier.powf(distance.max(0.0) / 10.0)
+    }
+
+    // Catches values that would break the game rather than just balance it.
+    pub fn validate(&self) -> Result<(), String> {
+        let non_negative = [
+            ("damage", self.damage),
+            ("reload_time", self.reload_time),
+            ("draw_time", self.draw_time),
+            ("move_speed", self.move_speed),
+            ("falloff.range", self.falloff.range),
+            ("recoil.view_punch", self.recoil.view_punch),
+            ("recoil.recovery", self.recoil.recovery),
+            ("recoil.reset", self.recoil.reset),
+        ];
+        for (field, value) in non_negative {
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("{} must be a positive number, got {}", field, value));
+            }
+        }
+        if !self.fire_rate.is_finite() || self.fire_rate <= 0.0 {
+            return Err(format!("fire_rate must be above zero, got {}", self.fire_rate));
+        }
+        for (field, value) in [
+            ("armor_penetration", self.armor_penetration),
+            ("falloff.modifier", self.falloff.modifier),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be between 0 and 1, got {}", field, value));
+            }
+        }
+        match (self.fire_mode, self.burst) {
+            (FireMode::Burst, None) => return Err("fire_mode Burst needs a burst".to_string()),
+            (_, Some(burst)) if burst.rounds == 0 || !burst.fire_rate.is_finite() || burst.fire_rate <= 0.0 => {
+                return Err("burst needs at least one round and a fire_rate above zero".to_string());
+            }
+            _ => {}
+        }
+        if !self.recoil.pattern.iter().all(|offset| offset.is_finite()) {
+            return Err("recoil.pattern must only hold numbers".to_string());
+        }
+        if self.magazine_size == Some(0) {
+            return Err("magazine_size can't be 0, use None for melee".to_string());
+        }
+        Ok(())
+    }
+
+    // What the game used before weapon files existed, and what it falls back to
+    // when a file is missing or broken.
+    pub fn builtin(weapon: WeaponType) -> WeaponDefinition {
+        let model = "models/ak.glb#Scene0".to_string();
+        let muzzle_offset = blender_to_world(Vec3::new(0.530462, 2.10557, -0.466568));
+        match weapon {
+            WeaponType::Knife => WeaponDefinition {
+                name: "Knife".to_string(),
+                damage: 40.0,
+                armor_penetration: 0.85,
+                fire_rate: 120.0,
+                fire_mode: FireMode::Semi,
+                burst: None,
+                magazine_size: None,
+                reserve_size: 0,
+                reload_time: 0.0,
+                draw_time: 0.6,
+                move_speed: 5.0, // ~250 u/s
+                recoil: Recoil {
+                    pattern: Vec::new(),
+                    view_punch: 0.0,
+                    recovery: 0.0,
+                    reset: 0.0,
+                },
+                falloff: RangeFalloff {
+                    range: 2.0,
+                    modifier: 1.0,
+                },
+                model,
+                muzzle_offset,
+            },
+            WeaponType::Pistol => WeaponDefinition {
+                name: "Pistol".to_string(),
+                damage: 35.0,
+                armor_penetration: 0.5,
+                fire_rate: 400.0,
+                fire_mode: FireMode::Semi,
+                burst: Some(Burst {
+                    rounds: 3,
+                    fire_rate: 1200.0,
+                }),
+                magazine_size: Some(20),
+                reserve_size: 120,
+                reload_time: 2.2,
+                draw_time: 1.0,
+                move_speed: 4.8, // ~240 u/s
+                recoil: Recoil {
+                    pattern: vec![Vec2::new(0.0, 0.0), Vec2::new(1.2, 0.1), Vec2::new(2.2, -0.2), Vec2::new(3.0, 0.3)],
+                    view_punch: 0.5,
+                    recovery: 12.0,
+                    reset: 0.4,
+                },
+                falloff: RangeFalloff {
+                    range: 100.0,
+                    modifier: 0.85,
+                },
+                model,
+                muzzle_offset,
+            },
+            WeaponType::Rifle => WeaponDefinition {
+                name: "Rifle".to_string(),
+                damage: 36.0,
+                armor_penetration: 0.775,
+                fire_rate: 600.0,
+                fire_mode: FireMode::Auto,
+                burst: None,
+                magazine_size: Some(30),
+                reserve_size: 90,
+                reload_time: 2.5,
+                draw_time: 1.0,
+                move_speed: 4.1, // ~215 u/s
+                recoil: Recoil {
+                    // climbs for the first ten shots, then wanders left and right
+                    pattern: vec![
+                        Vec2::new(0.0, 0.0),
+                        Vec2::new(0.5, 0.0),
+                        Vec2::new(1.2, 0.1),
+                        Vec2::new(2.1, -0.1),
+                        Vec2::new(3.1, 0.2),
+                        Vec2::new(4.1, 0.3),
+                        Vec2::new(5.0, 0.1),
+                        Vec2::new(5.8, -0.3),
+                        Vec2::new(6.4, -0.8),
+                        Vec2::new(6.8, -1.4),
+                        Vec2::new(7.0, -1.2),
+                        Vec2::new(7.1, -0.4),
+                        Vec2::new(7.1, 0.6),
+                        Vec2::new(7.2, 1.5),
+                        Vec2::new(7.3, 2.0),
+                        Vec2::new(7.3, 1.6),
+                        Vec2::new(7.4, 0.8),
+                        Vec2::new(7.4, -0.2),
+                        Vec2::new(7.5, -1.0),
+                        Vec2::new(7.5, -1.5),
+                    ],
+                    view_punch: 0.5,
+                    recovery: 10.0,
+                    reset: 0.3,
+                },
+                falloff: RangeFalloff {
+                    range: 200.0,
+                    modifier: 0.96,
+                },
+                model,
+                muzzle_offset,
+            },
+            WeaponType::Sniper => WeaponDefinition {
+                name: "Sniper".to_string(),
+                damage: 115.0,
+                armor_penetration: 0.975,
+                fire_rate: 41.1,
+                fire_mode: FireMode::Semi,
+                burst: None,
+                magazine_size: Some(5),
+                reserve_size: 30,
+                reload_time: 3.7,
+                draw_time: 1.25,
+                move_speed: 3.9, // ~200 u/s
+                recoil: Recoil {
+                    pattern: vec![Vec2::new(0.0, 0.0)],
+                    view_punch: 3.0,
+                    recovery: 6.0,
+                    reset: 0.0,
+                },
+                falloff: RangeFalloff {
+                    range: 500.0,
+                    modifier: 0.99,
+                },
+                model,
+                muzzle_offset,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> WeaponDefinition {
+        ron::de::from_str(source).expect("weapon file parses")
+    }
+
+    #[test]
+    fn shipped_files_match_the_builtin_stats() {
+        let files = [
+            (WeaponType::Knife, include_str!("../../../assets/weapons/knife.weapon.ron")),
+            (WeaponType::Pistol, include_str!("../../../assets/weapons/pistol.weapon.ron")),
+            (WeaponType::Rifle, include_str!("../../../assets/weapons/rifle.weapon.ron")),
+            (WeaponType::Sniper, include_str!("../../../assets/weapons/sniper.weapon.ron")),
+        ];
+        for (weapon, source) in files {
+            let definition = parse(source);
+            assert_eq!(definition.validate(), Ok(()));
+            assert_eq!(definition, WeaponDefinition::builtin(weapon), "{:?}", weapon);
+        }
+    }
+
+    #[test]
+    fn builtins_are_valid() {
+        for weapon in WeaponType::ALL {
+            assert_eq!(WeaponDefinition::builtin(weapon).validate(), Ok(()), "{:?}", weapon);
+        }
+    }
+
+    #[test]
+    fn broken_values_are_rejected() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        let broken = [
+            WeaponDefinition { fire_rate: 0.0, ..rifle.clone() },
+            WeaponDefinition { damage: f32::NAN, ..rifle.clone() },
+            WeaponDefinition { armor_penetration: 1.5, ..rifle.clone() },
+            WeaponDefinition { magazine_size: Some(0), ..rifle.clone() },
+            WeaponDefinition {
+                recoil: Recoil {
+                    pattern: vec![Vec2::new(f32::INFINITY, 0.0)],
+                    ..rifle.recoil.clone()
+                },
+                ..rifle.clone()
+            },
+            WeaponDefinition { fire_mode: FireMode::Burst, ..rifle.clone() },
+            WeaponDefinition {
+                burst: Some(Burst { rounds: 0, fire_rate: 900.0 }),
+                ..rifle.clone()
+            },
+        ];
+        for definition in broken {
+            assert!(definition.validate().is_err(), "{:?}", definition);
+        }
+    }
+
+    #[test]
+    fn damage_falls_off_with_distance() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        assert_eq!(rifle.damage_at(0.0), 36.0);
+        assert!((rifle.damage_at(20.0) - 36.0 * 0.96 * 0.96).abs() < 0.001);
+        assert_eq!(rifle.cycle_time(), Duration::from_millis(100));
+    }
+}
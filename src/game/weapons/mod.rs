@@ -0,0 +1,5 @@
+pub mod ammo;
+pub mod definition;
+pub mod recoil;
+pub mod trigger;
+pub mod weapons;
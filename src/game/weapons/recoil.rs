@@ -0,0 +1,168 @@
+use bevy::prelude::*;
+
+use super::{definition::Recoil, trigger::Trigger};
+use crate::game::player::{camera_controller::CameraController, player::LocalPlayer};
+
+impl Recoil {
+    // (pitch, yaw) offset of shot `index` in a spray, the last entry repeats
+    pub fn offset(&self, index: usize) -> Vec2 {
+        self.pattern.get(index).or(self.pattern.last()).copied().unwrap_or(Vec2::ZERO)
+    }
+}
+
+// Where a player is in their spray. The client and the server both feed it the tick of
+// every shot, so the n:th shot of a spray gets the same offset on both.
+#[derive(Component, Debug, Default)]
+pub struct Spray {
+    index: usize,
+    last_shot: Option<f64>,
+}
+
+impl Spray {
+    // Registers a shot fired at `time` seconds and returns its index in the pattern.
+    pub fn next(&mut self, time: f64, recoil: &Recoil) -> usize {
+        let continues = self.last_shot.is_some_and(|last| time - last <= recoil.reset as f64);
+        self.index = if continues { self.index + 1 } else { 0 };
+        self.last_shot = Some(time);
+        self.index
+    }
+
+    // Same as `next` for a shot fired on `tick`.
+    pub fn next_at_tick(&mut self, tick: u32, tickrate: f64, recoil: &Recoil) -> usize {
+        self.next(tick as f64 / tickrate, recoil)
+    }
+}
+
+// The tick the server counts a shot's spray on: the one the client fired on, not the rewind
+// tick, which is capped for high pings. Never ahead of the server.
+pub fn spray_tick(claimed: u32, server_tick: u32) -> u32 {
+    claimed.min(server_tick)
+}
+
+// The part of CameraController::rotation that came from recoil rather than the mouse.
+// Aiming is always done from the rotation without it.
+#[derive(Component, Debug, Default)]
+pub struct ViewPunch {
+    pub offset: Vec2,
+    // degrees per second, from the weapon that kicked last
+    recovery: f32,
+}
+
+impl ViewPunch {
+    // Kicks the view to the share of the shot offset the weapon shows, returns the change
+    // to add to the rotation.
+    pub fn kick(&mut self, recoil: &Recoil, index: usize) -> Vec2 {
+        let target = recoil.offset(index) * recoil.view_punch;
+        let change = target - self.offset;
+        self.offset = target;
+        self.recovery = recoil.recovery;
+        change
+    }
+
+    // Moves back towards the original aim, returns the change to add to the rotation.
+    pub fn recover(&mut self, delta: f32) -> Vec2 {
+        let step = self.recovery * delta;
+        let change = if self.offset.length() <= step {
+            -self.offset
+        } else {
+            -self.offset.normalize() * step
+        };
+        self.offset += change;
+        change
+    }
+}
+
+// the view only settles once the trigger is let go
+pub fn recover_view_punch(
+    time: Res<Time>,
+    trigger_query: Query<&Trigger, With<LocalPlayer>>,
+    mut camera_query: Query<(&mut CameraController, &mut ViewPunch)>,
+) {
+    if trigger_query.get_single().is_ok_and(Trigger::is_firing) {
+        return;
+    }
+    if let Ok((mut camera_controller, mut punch)) = camera_query.get_single_mut() {
+        if punch.offset != Vec2::ZERO {
+            camera_controller.rotation += punch.recover(time.delta_seconds());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{player::player::WeaponType, weapons::definition::WeaponDefinition};
+
+    #[test]
+    fn spray_follows_the_pattern_and_starts_over() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle).recoil;
+        let mut spray = Spray::default();
+        let indices: Vec<usize> = (0..25).map(|shot| spray.next(shot as f64 * 0.1, &rifle)).collect();
+        assert_eq!(indices, (0..25).collect::<Vec<_>>());
+        // past the end the last offset repeats
+        assert_eq!(rifle.offset(24), *rifle.pattern.last().unwrap());
+
+        // a pause longer than the reset time starts a new spray
+        assert_eq!(spray.next(2.4 + rifle.reset as f64 + 0.01, &rifle), 0);
+        assert_eq!(rifle.offset(0), Vec2::ZERO);
+    }
+
+    #[test]
+    fn client_and_server_count_the_same_spray() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle).recoil;
+        let tickrate = 64.0;
+        // ticks the client fired on, a pause after the fifth shot starts a new spray
+        let ticks = [100, 106, 113, 119, 126, 160, 166, 172];
+
+        let mut client = Spray::default();
+        let sent: Vec<usize> = ticks.iter().map(|tick| client.next_at_tick(*tick, tickrate, &rifle)).collect();
+        assert_eq!(sent, [0, 1, 2, 3, 4, 0, 1, 2]);
+
+        // a lag spike that clears during the pause, the rewind is capped to 16 ticks so the
+        // rewind ticks of the first spray move up to the second and continue it
+        let pings = [40, 40, 40, 40, 40, 10, 10, 10];
+        let mut rewound = Spray::default();
+        let rewind_indices: Vec<usize> = ticks
+            .iter()
+            .zip(pings)
+            .map(|(tick, ping)| {
+                let now = tick + ping;
+                rewound.next_at_tick((*tick).clamp(now - 16, now), tickrate, &rifle)
+            })
+            .collect();
+        assert_eq!(rewind_indices, [0, 1, 2, 3, 4, 5, 6, 7]);
+
+        // the server counts every Fire on the client's tick, also the third one it rejected
+        let mut server = Spray::default();
+        for (shot, ((tick, ping), claimed)) in ticks.iter().zip(pings).zip(&sent).enumerate() {
+            let expected = server.next_at_tick(spray_tick(*tick, tick + ping), tickrate, &rifle);
+            if shot == 2 {
+                continue;
+            }
+            assert_eq!(expected, *claimed);
+        }
+
+        // a claimed tick ahead of the server is counted as now
+        assert_eq!(spray_tick(500, 300), 300);
+    }
+
+    #[test]
+    fn view_punch_kicks_and_recovers() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle).recoil;
+        let mut punch = ViewPunch::default();
+        let mut rotation = Vec2::new(-5.0, 90.0);
+        for index in 0..10 {
+            rotation += punch.kick(&rifle, index);
+        }
+        assert_eq!(punch.offset, rifle.offset(9) * rifle.view_punch);
+        assert!((rotation - Vec2::new(-5.0, 90.0) - punch.offset).length() < 0.001);
+
+        // half way after half the time, then all the way back without overshooting
+        let seconds = punch.offset.length() / rifle.recovery;
+        rotation += punch.recover(seconds / 2.0);
+        assert!((punch.offset - rifle.offset(9) * rifle.view_punch / 2.0).length() < 0.001);
+        rotation += punch.recover(seconds);
+        assert_eq!(punch.offset, Vec2::ZERO);
+        assert!((rotation - Vec2::new(-5.0, 90.0)).length() < 0.001);
+    }
+}
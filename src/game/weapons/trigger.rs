@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+
+use super::definition::{Burst, FireMode, WeaponDefinition};
+
+// Fire timing for one player. Time is accumulated across frames so a weapon keeps its
+// cyclic rate even when a frame is longer than the time between two rounds.
+#[derive(Component, Debug, Default)]
+pub struct Trigger {
+    // seconds until the next round may fire, below zero when it was due during the frame
+    cooldown: f32,
+    // the trigger was active last frame, so a due round may be fired late
+    firing: bool,
+    // rounds left of the current burst
+    burst_left: u32,
+    // burst toggle, only used by weapons that have a burst
+    pub burst: bool,
+}
+
+impl Trigger {
+    pub fn mode(&self, definition: &WeaponDefinition) -> FireMode {
+        match (definition.burst, definition.fire_mode) {
+            (Some(_), _) if self.burst => FireMode::Burst,
+            // burst without a burst to fire, validate() turns these files away
+            (None, FireMode::Burst) => FireMode::Semi,
+            (_, mode) => mode,
+        }
+    }
+
+    // a round is due or on its way, recoil doesn't recover in between
+    pub fn is_firing(&self) -> bool {
+        self.firing || self.burst_left > 0
+    }
+
+    // drops a burst in progress, after a weapon switch, reload or empty magazine
+    pub fn interrupt(&mut self) {
+        self.firing = false;
+        self.burst_left = 0;
+    }
+
+    // a weapon that was just switched to fires once it is drawn
+    pub fn draw(&mut self, definition: &WeaponDefinition) {
+        self.interrupt();
+        self.cooldown = self.cooldown.max(definition.draw_time);
+    }
+
+    // Advances the trigger by a frame of `delta` seconds. `pressed` is true on the frame the
+    // button went down, `held` while it stays down. Returns how many seconds ago each round
+    // that became due should have fired, oldest first.
+    pub fn update(&mut self, delta: f32, pressed: bool, held: bool, definition: &WeaponDefinition) -> Vec<f32> {
+        let mode = self.mode(definition);
+        let cycle = definition.cycle_time().as_secs_f32();
+        let burst = definition.burst.unwrap_or(Burst {
+            rounds: 1,
+            fire_rate: definition.fire_rate,
+        });
+        if mode == FireMode::Burst && pressed && self.burst_left == 0 {
+            self.burst_left = burst.rounds;
+        }
+        let active = match mode {
+            FireMode::Semi => pressed,
+            FireMode::Auto => held,
+            FireMode::Burst => self.burst_left > 0,
+        };
+
+        self.cooldown -= delta;
+        if !active || !self.firing {
+            // nothing is saved up while idle, and a fresh pull fires now rather than in the past
+            self.cooldown = self.cooldown.max(0.0);
+        }
+        self.firing = active;
+
+        let mut shots = Vec::new();
+        while active && self.cooldown <= 0.0 {
+            shots.push(-self.cooldown);
+            match mode {
+                FireMode::Semi => {
+                    self.cooldown += cycle;
+                    break;
+                }
+                FireMode::Auto => self.cooldown += cycle,
+                FireMode::Burst => {
+                    self.burst_left -= 1;
+                    if self.burst_left == 0 {
+                        self.cooldown += cycle;
+                        self.firing = false;
+                        break;
+                    }
+                    self.cooldown += 60.0 / burst.fire_rate;
+                }
+            }
+        }
+        shots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::player::player::WeaponType;
+
+    // frames of `delta` seconds for `duration` seconds with the button held throughout
+    fn hold(trigger: &mut Trigger, definition: &WeaponDefinition, delta: f32, duration: f32) -> Vec<f32> {
+        let frames = (duration / delta).round() as usize;
+        (0..frames)
+            .flat_map(|frame| trigger.update(delta, frame == 0, true, definition))
+            .collect()
+    }
+
+    #[test]
+    fn automatic_fire_keeps_its_rate_at_any_frame_rate() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        // 600 rpm, the first round fires at the end of the frame the button went down
+        for delta in [1.0 / 300.0, 1.0 / 60.0, 1.0 / 20.0, 0.25] {
+            let shots = hold(&mut Trigger::default(), &rifle, delta, 1.0);
+            let expected = ((1.0 - delta) / 0.1).floor() as usize + 1;
+            assert!(shots.len().abs_diff(expected) <= 1, "{} shots at {} s frames", shots.len(), delta);
+        }
+
+        // two rounds were due during one long frame
+        let mut trigger = Trigger::default();
+        assert_eq!(trigger.update(0.01, true, true, &rifle), vec![0.0]);
+        let shots = trigger.update(0.25, false, true, &rifle);
+        assert_eq!(shots.len(), 2);
+        assert!((shots[0] - 0.15).abs() < 0.001);
+        assert!((shots[1] - 0.05).abs() < 0.001);
+    }
+
+    #[test]
+    fn idle_time_is_not_saved_up() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        let mut trigger = Trigger::default();
+        assert!(trigger.update(5.0, false, false, &rifle).is_empty());
+        assert_eq!(trigger.update(0.05, true, true, &rifle), vec![0.0]);
+    }
+
+    #[test]
+    fn semi_automatic_fires_once_per_pull() {
+        let pistol = WeaponDefinition::builtin(WeaponType::Pistol);
+        let shots = hold(&mut Trigger::default(), &pistol, 1.0 / 60.0, 1.0);
+        assert_eq!(shots.len(), 1);
+
+        // pulls faster than the cyclic rate are ignored
+        let mut trigger = Trigger::default();
+        assert_eq!(trigger.update(0.01, true, true, &pistol).len(), 1);
+        assert!(trigger.update(0.05, true, true, &pistol).is_empty());
+        assert_eq!(trigger.update(0.2, true, true, &pistol).len(), 1);
+    }
+
+    #[test]
+    fn burst_fires_its_rounds_and_stops() {
+        let pistol = WeaponDefinition::builtin(WeaponType::Pistol);
+        let rounds = pistol.burst.unwrap().rounds as usize;
+        let mut trigger = Trigger {
+            burst: true,
+            ..default()
+        };
+        assert_eq!(trigger.mode(&pistol), FireMode::Burst);
+        // a single tap is enough for the whole burst
+        let shots: Vec<f32> = (0..60).flat_map(|frame| trigger.update(1.0 / 60.0, frame == 0, frame == 0, &pistol)).collect();
+        assert_eq!(shots.len(), rounds);
+
+        // no burst on the rifle, the toggle does nothing
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        assert_eq!(trigger.mode(&rifle), FireMode::Auto);
+    }
+
+    #[test]
+    fn nothing_fires_until_the_weapon_is_drawn() {
+        let rifle = WeaponDefinition::builtin(WeaponType::Rifle);
+        let mut trigger = Trigger::default();
+        trigger.draw(&rifle);
+        let shots = hold(&mut trigger, &rifle, 0.01, rifle.draw_time - 0.02);
+        assert!(shots.is_empty());
+        assert_eq!(hold(&mut trigger, &rifle, 0.01, 0.05).len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::HashMap, fmt};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+};
+
+use super::{
+    ammo::{finish_reloads, DryFire},
+    definition::WeaponDefinition,
+};
+use crate::game::player::player::WeaponType;
+
+// Loads the weapon files and keeps WeaponStats in sync with them. With the file_watcher
+// feature a saved file is applied to the running game (or server) straight away.
+// Reloads run here too, the same way on both ends.
+pub struct WeaponsPlugin;
+
+impl Plugin for WeaponsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<WeaponDefinition>()
+            .init_asset_loader::<WeaponDefinitionLoader>()
+            .init_resource::<WeaponStats>()
+            .add_event::<DryFire>()
+            .add_systems(Startup, load_weapon_definitions)
+            .add_systems(PreUpdate, apply_weapon_definitions)
+            .add_systems(Update, finish_reloads);
+    }
+}
+
+// The stats in effect for every weapon, the builtin ones until the files have loaded.
+#[derive(Resource)]
+pub struct WeaponStats {
+    definitions: HashMap<WeaponType, WeaponDefinition>,
+}
+
+impl Default for WeaponStats {
+    fn default() -> Self {
+        WeaponStats {
+            definitions: WeaponType::ALL
+                .iter()
+                .map(|weapon| (*weapon, WeaponDefinition::builtin(*weapon)))
+                .collect(),
+        }
+    }
+}
+
+impl WeaponStats {
+    pub fn get(&self, weapon: WeaponType) -> &WeaponDefinition {
+        &self.definitions[&weapon]
+    }
+}
+
+pub fn weapon_asset_path(weapon: WeaponType) -> String {
+    let name = format!("{:?}", weapon).to_lowercase();
+    format!("weapons/{}.weapon.ron", name)
+}
+
+// keeps the handles alive so the assets aren't unloaded
+#[derive(Resource)]
+struct WeaponHandles(Vec<(WeaponType, Handle<WeaponDefinition>)>);
+
+fn load_weapon_definitions(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let handles = WeaponType::ALL
+        .iter()
+        .map(|weapon| (*weapon, asset_server.load(weapon_asset_path(*weapon))))
+        .collect();
+    commands.insert_resource(WeaponHandles(handles));
+}
+
+// A file that fails to load or validate leaves the previous stats in place.
+fn apply_weapon_definitions(
+    mut events: EventReader<AssetEvent<WeaponDefinition>>,
+    handles: Option<Res<WeaponHandles>>,
+    definitions: Res<Assets<WeaponDefinition>>,
+    mut stats: ResMut<WeaponStats>,
+) {
+    let Some(handles) = handles else {
+        return;
+    };
+    for event in events.read() {
+        let (AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id }) = event else {
+            continue;
+        };
+        let Some((weapon, _)) = handles.0.iter().find(|(_, handle)| handle.id() == *id) else {
+            continue;
+        };
+        let Some(definition) = definitions.get(*id) else {
+            continue;
+        };
+        match definition.validate() {
+            Ok(()) => {
+                info!("Weapon stats for {:?} loaded from {}", weapon, weapon_asset_path(*weapon));
+                stats.definitions.insert(*weapon, definition.clone());
+            }
+            Err(problem) => warn!("{}: {}, keeping the previous stats", weapon_asset_path(*weapon), problem),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WeaponDefinitionLoader;
+
+#[derive(Debug)]
+pub enum WeaponLoadError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+}
+
+impl fmt::Display for WeaponLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeaponLoadError::Io(err) => write!(f, "could not read weapon file: {}", err),
+            WeaponLoadError::Parse(err) => write!(f, "malformed weapon file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WeaponLoadError {}
+
+impl AssetLoader for WeaponDefinitionLoader {
+    type Asset = WeaponDefinition;
+    type Settings = ();
+    type Error = WeaponLoadError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<WeaponDefinition, WeaponLoadError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.map_err(WeaponLoadError::Io)?;
+        ron::de::from_bytes(&bytes).map_err(WeaponLoadError::Parse)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["weapon.ron"]
+    }
+}
@@ -0,0 +1,5 @@
+// bevy systems take their resources and queries as parameters, and each module keeps its
+// plugin in a file named after the module
+#![allow(clippy::too_many_arguments, clippy::type_complexity, clippy::module_inception)]
+
+pub mod game;
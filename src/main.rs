@@ -1,11 +1,46 @@
 use bevy::prelude::*;
-
-pub mod game;
+use rust_fps_net::game::{
+    self,
+    app_state::AppState,
+    config::args::Args,
+    net::{
+        client,
+        conditioner::{ClientConditioner, ConditionerSettings},
+        connection::DisconnectCause,
+        interpolation::InterpolationSettings,
+        rates::ClientRates,
+    },
+};
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .init_state::<game::app_state::AppState>() 
+    let args = Args::from_env();
+    let auth = client::ClientAuth::from_args(&args);
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins)
+        .init_state::<AppState>()
         .add_plugins(game::game::GamePlugin)
-        .run();
-}
\ No newline at end of file
+        .insert_resource(InterpolationSettings::from_args(&args))
+        .insert_resource(auth.clone())
+        .insert_resource(ClientRates::from_args(&args))
+        .insert_resource(ClientConditioner::new(ConditionerSettings::from_args(&args)));
+
+    // "--connect <addr>" joins a dedicated server straight away
+    if let Some(settings) = client::ConnectSettings::from_args(&args, &auth) {
+        match client::open_connection(&settings) {
+            Ok((client, transport)) => {
+                app.insert_resource(client)
+                    .insert_resource(transport)
+                    .insert_resource(settings)
+                    .insert_state(AppState::Connecting);
+            }
+            Err(err) => {
+                error!("Could not connect to {}: {}", settings.server_addr, err);
+                app.insert_resource(DisconnectCause::Failed(err.to_string()))
+                    .insert_resource(settings)
+                    .insert_state(AppState::Disconnected);
+            }
+        }
+    }
+
+    app.run();
+}